@@ -0,0 +1,31 @@
+#!/usr/bin/env bash
+# Usage: manage_following.sh <project name> follow <username> <actor>
+#        manage_following.sh <project name> unfollow <username> <actor>
+#
+# Sends a Follow of the remote <actor> from <username>, it is listed in the following collection once it is accepted.
+
+set -euo pipefail
+
+PROJECT_NAME="$1"
+COMMAND="$2"
+USERNAME="$3"
+ACTOR="$4"
+
+case "$COMMAND" in
+  follow)
+    EVENT_TYPE="FollowAccount"
+    ;;
+  unfollow)
+    EVENT_TYPE="UnfollowAccount"
+    ;;
+  *)
+    echo "unknown command: $COMMAND" >&2
+    exit 1
+    ;;
+esac
+
+EVENTS_PATH=$(mktemp)
+trap 'rm -f "$EVENTS_PATH"' EXIT
+jq -nc --arg type "$EVENT_TYPE" --arg user "$USERNAME" --arg actor "$ACTOR" \
+  '{event_type: $type, username: $user, actor: $actor}' > "$EVENTS_PATH"
+./send_to_queue.sh "${PROJECT_NAME}-job-queue" "$EVENTS_PATH"
@@ -0,0 +1,28 @@
+#!/usr/bin/env bash
+# Usage: manage_relay.sh <project name> subscribe <username> <relay actor> [Mastodon|LitePub]
+#        manage_relay.sh <project name> unsubscribe <relay actor>
+#
+# Sends a Follow of the relay from <username>, articles are delivered to the relay once it is accepted.
+# Mastodon style relays (pub-relay, Activity-Relay) are the default, pass LitePub for Pleroma relays.
+
+set -euo pipefail
+
+PROJECT_NAME="$1"
+COMMAND="$2"
+
+EVENTS_PATH=$(mktemp)
+trap 'rm -f "$EVENTS_PATH"' EXIT
+case "$COMMAND" in
+  subscribe)
+    jq -nc --arg user "$3" --arg actor "$4" --arg kind "${5:-Mastodon}" \
+      '{event_type: "SubscribeRelay", username: $user, actor: $actor, kind: $kind}' > "$EVENTS_PATH"
+    ;;
+  unsubscribe)
+    jq -nc --arg actor "$3" '{event_type: "UnsubscribeRelay", actor: $actor}' > "$EVENTS_PATH"
+    ;;
+  *)
+    echo "unknown command: $COMMAND" >&2
+    exit 1
+    ;;
+esac
+./send_to_queue.sh "${PROJECT_NAME}-job-queue" "$EVENTS_PATH"
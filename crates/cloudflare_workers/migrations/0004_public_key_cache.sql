@@ -0,0 +1,9 @@
+-- Migration number: 0004 	 2026-10-18T09:12:44.318Z
+
+CREATE TABLE public_keys
+(
+    key_id     TEXT PRIMARY KEY,
+    actor      TEXT,
+    pem        TEXT,
+    expires_at TEXT
+);
@@ -0,0 +1,12 @@
+-- Migration number: 0005 	 2026-10-18T10:02:17.542Z
+
+-- activities are recorded per local inbox since one addressed to several local users is processed once for each of them
+CREATE TABLE processed_activities
+(
+    username    TEXT NOT NULL,
+    activity_id TEXT NOT NULL,
+    expires_at  TEXT,
+    PRIMARY KEY (username, activity_id)
+);
+
+CREATE INDEX idx_processed_activities_expires_at ON processed_activities (expires_at);
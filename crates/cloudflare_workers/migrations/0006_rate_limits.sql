@@ -0,0 +1,8 @@
+-- Migration number: 0006 	 2026-10-18T10:41:05.208Z
+
+CREATE TABLE rate_limits
+(
+    key          TEXT PRIMARY KEY,
+    window_start INTEGER,
+    count        INTEGER
+);
@@ -0,0 +1,10 @@
+-- Migration number: 0007 	 2026-10-18T11:20:43.118Z
+
+CREATE TABLE delivery_failures
+(
+    origin            TEXT PRIMARY KEY,
+    first_failed_at   TEXT,
+    last_failed_day   INTEGER,
+    failure_days      INTEGER,
+    unavailable_until TEXT
+);
@@ -0,0 +1,9 @@
+-- Migration number: 0008 	 2026-10-18T12:05:31.604Z
+
+CREATE TABLE delivery_hosts
+(
+    origin        TEXT PRIMARY KEY,
+    in_flight     INTEGER,
+    lease_until   TEXT,
+    backoff_until TEXT
+);
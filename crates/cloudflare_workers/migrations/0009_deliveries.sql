@@ -0,0 +1,11 @@
+-- Migration number: 0009 	 2026-10-18T12:41:09.377Z
+
+CREATE TABLE deliveries
+(
+    activity_id TEXT,
+    inbox       TEXT,
+    expires_at  TEXT,
+    PRIMARY KEY (activity_id, inbox)
+);
+
+CREATE INDEX idx_deliveries_expires_at ON deliveries (expires_at);
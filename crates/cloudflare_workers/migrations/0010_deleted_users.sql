@@ -0,0 +1,7 @@
+-- Migration number: 0010 	 2026-10-18T15:20:44.803Z
+
+CREATE TABLE deleted_users
+(
+    username   TEXT PRIMARY KEY,
+    deleted_at TEXT
+);
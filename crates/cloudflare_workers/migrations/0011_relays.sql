@@ -0,0 +1,11 @@
+-- Migration number: 0011 	 2026-10-18T21:16:05.039Z
+
+CREATE TABLE relays
+(
+    actor       TEXT PRIMARY KEY,
+    inbox       TEXT NOT NULL,
+    kind        TEXT NOT NULL,
+    username    TEXT NOT NULL,
+    follow_id   TEXT NOT NULL,
+    accepted_at TEXT
+);
@@ -0,0 +1,11 @@
+-- Migration number: 0012 	 2026-10-18T21:19:42.964Z
+
+CREATE TABLE following
+(
+    username    TEXT NOT NULL,
+    actor       TEXT NOT NULL,
+    inbox       TEXT NOT NULL,
+    follow_id   TEXT NOT NULL,
+    accepted_at TEXT,
+    PRIMARY KEY (username, actor)
+);
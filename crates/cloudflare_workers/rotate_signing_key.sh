@@ -0,0 +1,43 @@
+#!/usr/bin/env bash
+# Usage: rotate_signing_key.sh <project name> <username> <signing keys json> [shared private key pem]
+#
+# Generates a new signing key for <username> and puts it first in <signing keys json>, keeping the previous key
+# published on the actor until the next rotation. The file is uploaded as the SIGNING_KEYS secret and an Update of
+# the actor is sent to the followers. Users without keys of their own sign with the shared key of PRIVATE_KEY_PEM,
+# pass its PEM so that it stays published as their previous key.
+
+set -euo pipefail
+
+PROJECT_NAME="$1"
+USERNAME="$2"
+KEYS_PATH="$3"
+SHARED_KEY_PATH="${4:-}"
+
+if [ ! -f "$KEYS_PATH" ]; then
+  echo '{}' > "$KEYS_PATH"
+fi
+SHARED_KEY_PEM=""
+if [ -n "$SHARED_KEY_PATH" ]; then
+  SHARED_KEY_PEM=$(cat "$SHARED_KEY_PATH")
+fi
+
+KEY_ID="key-$(date -u +%Y%m%d%H%M%S)"
+NEW_KEY_PEM=$(openssl genpkey -algorithm RSA -pkeyopt rsa_keygen_bits:2048)
+
+jq --arg user "$USERNAME" --arg id "$KEY_ID" --arg pem "$NEW_KEY_PEM" --arg shared "$SHARED_KEY_PEM" '
+  (.[$user] // []) as $keys
+  | .[$user] = [{id: $id, pem: $pem}] + (
+      if ($keys | length) > 0 then $keys[:1]
+      elif $shared != "" then [{id: "main-key", pem: $shared}]
+      else [] end
+    )
+' "$KEYS_PATH" > "$KEYS_PATH.new"
+mv "$KEYS_PATH.new" "$KEYS_PATH"
+echo "[fblog_system] Rotated the signing key of '${USERNAME}' to '${KEY_ID}'"
+
+pnpm exec wrangler secret put SIGNING_KEYS --config "$(pwd)/wrangler.toml" < "$KEYS_PATH"
+
+EVENTS_PATH=$(mktemp)
+trap 'rm -f "$EVENTS_PATH"' EXIT
+jq -nc --arg user "$USERNAME" '{event_type: "DeliveryUpdateUserToAll", username: $user}' > "$EVENTS_PATH"
+./send_to_queue.sh "${PROJECT_NAME}-job-queue" "$EVENTS_PATH"
@@ -14,3 +14,14 @@ fi
 mkdir -p dist/raw__/articles/ap
 ./snapshot.sh dist/raw__/articles/ap > article_snapshot_new
 ./snapshot_diff.sh ./article_snapshot_old ./article_snapshot_new > events.jsonl
+
+if pnpm exec wrangler r2 object get --remote "$PROJECT_NAME-blog-bucket/user_snapshot_zst" -f ./user_snapshot_old.zst; then
+  zstd -d ./user_snapshot_old.zst -o ./user_snapshot_old
+  cat ./user_snapshot_old
+else
+  cat /dev/null > user_snapshot_old
+fi
+
+mkdir -p dist/raw__/users/ap
+./snapshot.sh dist/raw__/users/ap > user_snapshot_new
+./snapshot_diff.sh ./user_snapshot_old ./user_snapshot_new users >> events.jsonl
@@ -4,12 +4,16 @@ use axum::http::Request;
 use axum::response::IntoResponse;
 use bytes::Bytes;
 use chrono::Utc;
+use fblog_system_core::integrity::derive_integrity_key;
 use fblog_system_core::process_queue::{ProcessQueueResult, process_queue};
-use fblog_system_core::route::router;
+use fblog_system_core::route::{ClientAddr, router};
 use fblog_system_core::traits::*;
+#[cfg(feature = "activitypub")]
+use futures::StreamExt;
 use http::StatusCode;
 use http_body_util::{BodyDataStream, BodyExt};
 use rsa::pkcs8::DecodePrivateKey;
+use std::collections::HashMap;
 use std::fmt::Display;
 use tower_service::Service;
 use tracing_subscriber::fmt::format::Pretty;
@@ -25,10 +29,19 @@ mod tests;
 struct WorkerState {
     env: Env,
     signing_key: RSASHA2SigningKey,
+    user_signing_keys: std::sync::Arc<HashMap<String, Vec<ConfiguredSigningKey>>>,
+    integrity_secret: Option<String>,
     queue: worker::Queue,
     db: std::sync::Arc<worker::d1::D1Database>,
 }
 
+/// Signing key of a user from the `SIGNING_KEYS` secret.
+#[derive(Clone)]
+struct ConfiguredSigningKey {
+    id: String,
+    key: RSASHA2SigningKey,
+}
+
 impl WorkerState {
     fn assets(&self) -> worker::Fetcher {
         self.env.assets("ASSETS").unwrap()
@@ -54,12 +67,33 @@ impl fblog_system_core::traits::Env for WorkerState {
     fn timestamp_now(&self) -> chrono::DateTime<Utc> {
         Utc::now()
     }
-    fn signing_key(&self) -> &RSASHA2SigningKey {
-        &self.signing_key
+    fn signing_key(&self, username: &str) -> UserSigningKey<'_> {
+        match self.user_signing_keys.get(username).and_then(|keys| keys.first()) {
+            Some(key) => UserSigningKey { id: &key.id, key: &key.key },
+            // users without keys of their own share the key of PRIVATE_KEY_PEM
+            None => UserSigningKey {
+                id: "main-key",
+                key: &self.signing_key,
+            },
+        }
+    }
+    fn retiring_keys(&self, username: &str) -> Vec<UserSigningKey<'_>> {
+        self.user_signing_keys
+            .get(username)
+            .into_iter()
+            .flat_map(|keys| keys.iter().skip(1))
+            .map(|key| UserSigningKey { id: &key.id, key: &key.key })
+            .collect()
+    }
+    fn integrity_key(&self, username: &str) -> Option<Ed25519SigningKey> {
+        let secret = self.integrity_secret.as_ref()?;
+        Some(derive_integrity_key(secret.as_bytes(), username))
     }
 }
 
 impl ArticleProvider for WorkerState {
+    type Error = worker::Error;
+
     #[worker::send]
     async fn exists_article(&self, slug: &str) -> bool {
         tracing::trace!("exists_article: {}", slug);
@@ -104,127 +138,76 @@ impl ArticleProvider for WorkerState {
     }
 
     #[worker::send]
-    async fn add_comment(&self, slug: &str, comment: ArticleNewComment) {
+    async fn add_comment(&self, slug: &str, comment: ArticleNewComment) -> Result<(), Self::Error> {
         // Serialize the comment and store it in R2 bucket
-        let json = match serde_json::to_string(&comment) {
-            Ok(json) => json,
-            Err(e) => {
-                tracing::error!(error = ?e, "Failed to serialize comment");
-                return;
-            }
-        };
-
+        let json = serde_json::to_string(&comment)?;
         let path = format!("comments/{}/{}", slug, comment.id);
-        if let Err(e) = self.r2().put(&path, json.clone()).execute().await {
-            tracing::error!(error = ?e, "Failed to store comment in R2");
-            return;
-        }
+        self.r2().put(&path, json).execute().await?;
 
         // Increment the comment count in D1
-        match worker::query!(
+        worker::query!(
             self.db.as_ref(),
             "INSERT INTO comments (slug, count) VALUES (?1, 1)\
              ON CONFLICT (slug) DO UPDATE SET count = count + 1",
             &slug
-        ) {
-            Ok(stmt) => {
-                if let Err(e) = stmt.run().await {
-                    tracing::error!(error = ?e, "Failed to increment comment count in D1");
-                }
-            }
-            Err(e) => {
-                tracing::error!(error = ?e, "Failed to prepare increment comment count query");
-            }
-        }
+        )?
+        .run()
+        .await?;
+        Ok(())
     }
 
     #[worker::send]
-    async fn remove_reaction_by(&self, slug: &str, actor: &str) {
+    async fn remove_reaction_by(&self, slug: &str, actor: &str) -> Result<(), Self::Error> {
         // D1からリアクションのカウントを減らす
-        match worker::query!(
+        worker::query!(
             self.db.as_ref(),
             "UPDATE reactions SET count = CASE WHEN count > 0 THEN count - 1 ELSE 0 END WHERE slug = ?1",
             &slug
-        ) {
-            Ok(stmt) => {
-                if let Err(e) = stmt.run().await {
-                    tracing::error!(error = ?e, "Failed to decrement reaction count in D1");
-                }
-            }
-            Err(e) => {
-                tracing::error!(error = ?e, "Failed to prepare decrement reaction count query");
-            }
-        }
+        )?
+        .run()
+        .await?;
 
         // D1からアクターのリアクション情報を削除
-        match worker::query!(
+        worker::query!(
             self.db.as_ref(),
             "DELETE FROM reaction_actors WHERE slug = ?1 AND actor_id = ?2",
             &slug,
             &actor
-        ) {
-            Ok(stmt) => {
-                if let Err(e) = stmt.run().await {
-                    tracing::error!(error = ?e, "Failed to remove actor's reaction from D1");
-                }
-            }
-            Err(e) => {
-                tracing::error!(error = ?e, "Failed to prepare remove actor's reaction query");
-            }
-        }
+        )?
+        .run()
+        .await?;
+        Ok(())
     }
 
     #[worker::send]
-    async fn add_reaction(&self, slug: &str, reaction: ArticleNewReaction) {
+    async fn add_reaction(&self, slug: &str, reaction: ArticleNewReaction) -> Result<(), Self::Error> {
         // Serialize the reaction and store it in R2 bucket
-        let json = match serde_json::to_string(&reaction) {
-            Ok(json) => json,
-            Err(e) => {
-                tracing::error!(error = ?e, "Failed to serialize reaction");
-                return;
-            }
-        };
-
+        let json = serde_json::to_string(&reaction)?;
         let path = format!("reactions/{}/{}", slug, reaction.id);
-        if let Err(e) = self.r2().put(&path, json.clone()).execute().await {
-            tracing::error!(error = ?e, "Failed to store reaction in R2");
-            return;
-        }
-
-        // Increment the reaction count in D1
-        match worker::query!(
-            self.db.as_ref(),
-            "INSERT INTO reactions (slug, count) VALUES (?1, 1)\
-             ON CONFLICT (slug) DO UPDATE SET count = count + 1",
-            &slug
-        ) {
-            Ok(stmt) => {
-                if let Err(e) = stmt.run().await {
-                    tracing::error!(error = ?e, "Failed to increment reaction count in D1");
-                }
-            }
-            Err(e) => {
-                tracing::error!(error = ?e, "Failed to prepare increment reaction count query");
-            }
-        }
+        self.r2().put(&path, json).execute().await?;
 
         // リアクションを行ったアクターの情報をD1に保存
-        match worker::query!(
+        // the count is incremented last so that a retry after a failure does not count the reaction twice
+        worker::query!(
             self.db.as_ref(),
             "INSERT OR REPLACE INTO reaction_actors (slug, actor_id, reaction_id) VALUES (?1, ?2, ?3)",
             &slug,
             &reaction.author_id,
             &reaction.id
-        ) {
-            Ok(stmt) => {
-                if let Err(e) = stmt.run().await {
-                    tracing::error!(error = ?e, "Failed to store actor's reaction in D1");
-                }
-            }
-            Err(e) => {
-                tracing::error!(error = ?e, "Failed to prepare store actor's reaction query");
-            }
-        }
+        )?
+        .run()
+        .await?;
+
+        // Increment the reaction count in D1
+        worker::query!(
+            self.db.as_ref(),
+            "INSERT INTO reactions (slug, count) VALUES (?1, 1)\
+             ON CONFLICT (slug) DO UPDATE SET count = count + 1",
+            &slug
+        )?
+        .run()
+        .await?;
+        Ok(())
     }
 
     #[worker::send]
@@ -269,6 +252,8 @@ impl ArticleProvider for WorkerState {
 }
 
 impl UserProvider for WorkerState {
+    type Error = worker::Error;
+
     #[worker::send]
     async fn exists_user(&self, username: &str) -> bool {
         self.fetch_asset(format_args!("/raw__/users/ap/{username}.json"))
@@ -298,103 +283,706 @@ impl UserProvider for WorkerState {
     }
 
     #[worker::send]
-    async fn add_follower(&self, username: &str, follower_id: &str, inbox: &str, event_id: &str) {
-        match worker::query!(
+    async fn add_follower(&self, username: &str, follower_id: &str, inbox: &str, event_id: &str) -> Result<(), Self::Error> {
+        worker::query!(
             self.db.as_ref(),
             "INSERT INTO followers (username, follower_id, inbox, event_id) VALUES (?1, ?2, ?3, ?4)",
             &username,
             &follower_id,
             &inbox,
             &event_id,
+        )?
+        .run()
+        .await?;
+        Ok(())
+    }
+
+    #[worker::send]
+    async fn get_follower_by_event_id(&self, username: &str, event_id: &str) -> Option<String> {
+        let stmt = match worker::query!(
+            self.db.as_ref(),
+            "SELECT follower_id FROM followers WHERE username = ?1 AND event_id = ?2",
+            &username,
+            &event_id
+        ) {
+            Ok(s) => s,
+            Err(e) => {
+                tracing::error!(error = ?e, "failed to prepare get_follower_by_event_id");
+                return None;
+            }
+        };
+        match stmt.first::<String>(Some("follower_id")).await {
+            Ok(follower) => follower,
+            Err(e) => {
+                tracing::error!(error = ?e, "failed to execute get_follower_by_event_id");
+                None
+            }
+        }
+    }
+
+    #[worker::send]
+    async fn remove_follower(&self, username: &str, event_id: &str) -> Result<(), Self::Error> {
+        worker::query!(
+            self.db.as_ref(),
+            "DELETE FROM followers WHERE username = ?1 AND event_id = ?2",
+            &username,
+            &event_id,
+        )?
+        .run()
+        .await?;
+        Ok(())
+    }
+
+    #[worker::send]
+    async fn remove_follower_by_actor(&self, username: &str, actor: &str) -> Result<(), Self::Error> {
+        worker::query!(
+            self.db.as_ref(),
+            "DELETE FROM followers WHERE username = ?1 AND follower_id = ?2",
+            &username,
+            &actor,
+        )?
+        .run()
+        .await?;
+        Ok(())
+    }
+
+    #[worker::send]
+    async fn remove_followers_by_inbox(&self, inbox: &str) -> Result<(), Self::Error> {
+        worker::query!(self.db.as_ref(), "DELETE FROM followers WHERE inbox = ?1", &inbox)?
+            .run()
+            .await?;
+        Ok(())
+    }
+
+    #[worker::send]
+    async fn remove_followers_by_origin(&self, origin: &str) -> Result<(), Self::Error> {
+        worker::query!(
+            self.db.as_ref(),
+            "DELETE FROM followers WHERE substr(inbox, 1, length(?1) + 1) = ?1 || '/'",
+            &origin
+        )?
+        .run()
+        .await?;
+        Ok(())
+    }
+
+    #[worker::send]
+    async fn get_followers_inbox_batch(&self, username: &str, last_inbox: &str) -> Result<(ArrayVec<String, 10>, String), Self::Error> {
+        let rows: Vec<Vec<String>> = worker::query!(
+            self.db.as_ref(),
+            "SELECT DISTINCT inbox FROM followers WHERE username = ?1 AND inbox > ?2 \
+             AND NOT EXISTS (SELECT 1 FROM delivery_failures WHERE delivery_failures.unavailable_until > ?3 \
+             AND substr(followers.inbox, 1, length(delivery_failures.origin) + 1) = delivery_failures.origin || '/') \
+             ORDER BY inbox LIMIT 10",
+            &username,
+            &last_inbox,
+            &Utc::now().to_rfc3339()
+        )?
+        .raw()
+        .await?;
+        let mut vec = ArrayVec::<String, 10>::new();
+        for mut row in rows {
+            if let Some(inbox) = row.pop()
+                && vec.try_push(inbox).is_err()
+            {
+                break;
+            }
+        }
+        let next_last = vec.last().cloned().unwrap_or_default();
+        Ok((vec, next_last))
+    }
+    #[worker::send]
+    async fn remove_followers_of(&self, username: &str) -> Result<(), Self::Error> {
+        worker::query!(self.db.as_ref(), "DELETE FROM followers WHERE username = ?1", &username)?
+            .run()
+            .await?;
+        Ok(())
+    }
+
+    #[worker::send]
+    async fn user_deleted_at(&self, username: &str) -> Option<chrono::DateTime<Utc>> {
+        let stmt = match worker::query!(self.db.as_ref(), "SELECT deleted_at FROM deleted_users WHERE username = ?1", &username) {
+            Ok(s) => s,
+            Err(e) => {
+                tracing::error!(error = ?e, "failed to prepare user_deleted_at");
+                return None;
+            }
+        };
+        match stmt.first::<chrono::DateTime<Utc>>(Some("deleted_at")).await {
+            Ok(deleted_at) => deleted_at,
+            Err(e) => {
+                tracing::error!(error = ?e, "failed to execute user_deleted_at");
+                None
+            }
+        }
+    }
+
+    #[worker::send]
+    async fn mark_user_deleted(&self, username: &str, deleted_at: chrono::DateTime<Utc>) -> Result<(), Self::Error> {
+        worker::query!(
+            self.db.as_ref(),
+            "INSERT INTO deleted_users (username, deleted_at) VALUES (?1, ?2) ON CONFLICT (username) DO NOTHING",
+            &username,
+            &deleted_at.to_rfc3339(),
+        )?
+        .run()
+        .await?;
+        Ok(())
+    }
+}
+impl PublicKeyCache for WorkerState {
+    type Error = worker::Error;
+
+    #[worker::send]
+    async fn get_public_key(&self, key_id: &str) -> Option<CachedPublicKey> {
+        let stmt = match worker::query!(
+            self.db.as_ref(),
+            "SELECT actor, pem, expires_at FROM public_keys WHERE key_id = ?1",
+            &key_id
+        ) {
+            Ok(s) => s,
+            Err(e) => {
+                tracing::error!(error = ?e, "failed to prepare get_public_key");
+                return None;
+            }
+        };
+        match stmt.first::<CachedPublicKey>(None).await {
+            Ok(key) => key,
+            Err(e) => {
+                tracing::error!(error = ?e, "failed to execute get_public_key");
+                None
+            }
+        }
+    }
+
+    #[worker::send]
+    async fn put_public_key(&self, key_id: &str, key: CachedPublicKey) -> Result<(), Self::Error> {
+        worker::query!(
+            self.db.as_ref(),
+            "INSERT OR REPLACE INTO public_keys (key_id, actor, pem, expires_at) VALUES (?1, ?2, ?3, ?4)",
+            &key_id,
+            &key.actor,
+            &key.pem,
+            &key.expires_at.to_rfc3339(),
+        )?
+        .run()
+        .await?;
+        Ok(())
+    }
+}
+
+impl ProcessedActivityStore for WorkerState {
+    type Error = worker::Error;
+
+    #[worker::send]
+    async fn is_activity_processed(&self, username: &str, activity_id: &str) -> Result<bool, Self::Error> {
+        let processed = worker::query!(
+            self.db.as_ref(),
+            "SELECT activity_id FROM processed_activities WHERE username = ?1 AND activity_id = ?2 AND expires_at > ?3",
+            &username,
+            &activity_id,
+            &Utc::now().to_rfc3339(),
+        )?
+        .first::<String>(Some("activity_id"))
+        .await?;
+        Ok(processed.is_some())
+    }
+
+    #[worker::send]
+    async fn mark_activity_processed(&self, username: &str, activity_id: &str, expires_at: chrono::DateTime<Utc>) -> Result<(), Self::Error> {
+        match worker::query!(
+            self.db.as_ref(),
+            "DELETE FROM processed_activities WHERE expires_at <= ?1",
+            &Utc::now().to_rfc3339()
         ) {
             Ok(stmt) => {
                 if let Err(e) = stmt.run().await {
-                    tracing::error!(error = ?e, "failed to insert follower");
+                    tracing::error!(error = ?e, "failed to remove expired processed activities");
                 }
             }
             Err(e) => {
-                tracing::error!(error = ?e, "failed to prepare insert follower");
+                tracing::error!(error = ?e, "failed to prepare remove expired processed activities");
+            }
+        }
+        worker::query!(
+            self.db.as_ref(),
+            "INSERT INTO processed_activities (username, activity_id, expires_at) VALUES (?1, ?2, ?3) \
+             ON CONFLICT (username, activity_id) DO UPDATE SET expires_at = excluded.expires_at",
+            &username,
+            &activity_id,
+            &expires_at.to_rfc3339(),
+        )?
+        .run()
+        .await?;
+        Ok(())
+    }
+}
+
+impl InstanceAvailability for WorkerState {
+    type Error = worker::Error;
+
+    #[worker::send]
+    async fn record_delivery_failure(&self, origin: &str, now: chrono::DateTime<Utc>) -> DeliveryFailures {
+        let fallback = DeliveryFailures {
+            first_failed_at: now,
+            failure_days: 1,
+        };
+        let stmt = match worker::query!(
+            self.db.as_ref(),
+            "INSERT INTO delivery_failures (origin, first_failed_at, last_failed_day, failure_days) VALUES (?1, ?2, ?3, 1) \
+             ON CONFLICT (origin) DO UPDATE SET \
+             failure_days = delivery_failures.failure_days + (delivery_failures.last_failed_day < excluded.last_failed_day), \
+             last_failed_day = excluded.last_failed_day \
+             RETURNING first_failed_at, failure_days",
+            &origin,
+            &now.to_rfc3339(),
+            &now.timestamp().div_euclid(24 * 60 * 60),
+        ) {
+            Ok(s) => s,
+            Err(e) => {
+                tracing::error!(error = ?e, "failed to prepare record_delivery_failure");
+                return fallback;
+            }
+        };
+        match stmt.first::<DeliveryFailures>(None).await {
+            Ok(failures) => failures.unwrap_or(fallback),
+            Err(e) => {
+                tracing::error!(error = ?e, "failed to execute record_delivery_failure");
+                fallback
+            }
+        }
+    }
+
+    #[worker::send]
+    async fn clear_delivery_failures(&self, origin: &str) -> Result<(), Self::Error> {
+        worker::query!(self.db.as_ref(), "DELETE FROM delivery_failures WHERE origin = ?1", &origin)?
+            .run()
+            .await?;
+        Ok(())
+    }
+
+    #[worker::send]
+    async fn mark_unavailable(&self, origin: &str, until: chrono::DateTime<Utc>) -> Result<(), Self::Error> {
+        worker::query!(
+            self.db.as_ref(),
+            "UPDATE delivery_failures SET unavailable_until = ?2 WHERE origin = ?1",
+            &origin,
+            &until.to_rfc3339(),
+        )?
+        .run()
+        .await?;
+        Ok(())
+    }
+}
+
+impl DeliveryLog for WorkerState {
+    type Error = worker::Error;
+
+    #[worker::send]
+    async fn is_delivered(&self, activity_id: &str, inbox: &str) -> bool {
+        let stmt = match worker::query!(
+            self.db.as_ref(),
+            "SELECT 1 AS delivered FROM deliveries WHERE activity_id = ?1 AND inbox = ?2 AND expires_at > ?3",
+            &activity_id,
+            &inbox,
+            &Utc::now().to_rfc3339(),
+        ) {
+            Ok(s) => s,
+            Err(e) => {
+                tracing::error!(error = ?e, "failed to prepare is_delivered");
+                return false;
+            }
+        };
+        match stmt.first::<u32>(Some("delivered")).await {
+            Ok(delivered) => delivered.is_some(),
+            Err(e) => {
+                tracing::error!(error = ?e, "failed to execute is_delivered");
+                false
             }
         }
     }
 
     #[worker::send]
-    async fn remove_follower(&self, username: &str, event_id: &str) {
+    async fn record_delivery(&self, activity_id: &str, inbox: &str, expires_at: chrono::DateTime<Utc>) -> Result<(), Self::Error> {
         match worker::query!(
             self.db.as_ref(),
-            "DELETE FROM followers WHERE username = ?1 AND event_id = ?2",
-            &username,
-            &event_id,
+            "DELETE FROM deliveries WHERE expires_at <= ?1",
+            &Utc::now().to_rfc3339()
         ) {
             Ok(stmt) => {
                 if let Err(e) = stmt.run().await {
-                    tracing::error!(error = ?e, "failed to delete follower");
+                    tracing::error!(error = ?e, "failed to remove expired deliveries");
+                }
+            }
+            Err(e) => {
+                tracing::error!(error = ?e, "failed to prepare remove expired deliveries");
+            }
+        }
+        worker::query!(
+            self.db.as_ref(),
+            "INSERT OR REPLACE INTO deliveries (activity_id, inbox, expires_at) VALUES (?1, ?2, ?3)",
+            &activity_id,
+            &inbox,
+            &expires_at.to_rfc3339(),
+        )?
+        .run()
+        .await?;
+        Ok(())
+    }
+
+    #[worker::send]
+    async fn forget_deliveries(&self, activity_id: &str) {
+        match worker::query!(self.db.as_ref(), "DELETE FROM deliveries WHERE activity_id = ?1", &activity_id) {
+            Ok(stmt) => {
+                if let Err(e) = stmt.run().await {
+                    tracing::error!(error = ?e, "failed to forget deliveries");
                 }
             }
             Err(e) => {
-                tracing::error!(error = ?e, "failed to prepare delete follower");
+                tracing::error!(error = ?e, "failed to prepare forget deliveries");
+            }
+        }
+    }
+}
+
+impl DeliveryScheduler for WorkerState {
+    type Error = worker::Error;
+
+    #[worker::send]
+    async fn acquire_delivery_slot(
+        &self,
+        origin: &str,
+        max_in_flight: u32,
+        now: chrono::DateTime<Utc>,
+        lease_until: chrono::DateTime<Utc>,
+    ) -> DeliverySlot {
+        let now = now.to_rfc3339();
+        let stmt = match worker::query!(
+            self.db.as_ref(),
+            "INSERT INTO delivery_hosts (origin, in_flight, lease_until) VALUES (?1, 1, ?3) \
+             ON CONFLICT (origin) DO UPDATE SET \
+             in_flight = CASE WHEN delivery_hosts.lease_until <= ?2 THEN 1 ELSE delivery_hosts.in_flight + 1 END, \
+             lease_until = excluded.lease_until \
+             WHERE (delivery_hosts.backoff_until IS NULL OR delivery_hosts.backoff_until <= ?2) \
+             AND (delivery_hosts.in_flight < ?4 OR delivery_hosts.lease_until <= ?2) \
+             RETURNING in_flight",
+            &origin,
+            &now,
+            &lease_until.to_rfc3339(),
+            &max_in_flight,
+        ) {
+            Ok(s) => s,
+            Err(e) => {
+                tracing::error!(error = ?e, "failed to prepare acquire_delivery_slot");
+                return DeliverySlot::Acquired;
+            }
+        };
+        match stmt.first::<u32>(Some("in_flight")).await {
+            Ok(Some(_)) => return DeliverySlot::Acquired,
+            Ok(None) => {}
+            Err(e) => {
+                tracing::error!(error = ?e, "failed to execute acquire_delivery_slot");
+                return DeliverySlot::Acquired;
+            }
+        }
+        let stmt = match worker::query!(
+            self.db.as_ref(),
+            "SELECT backoff_until FROM delivery_hosts WHERE origin = ?1 AND backoff_until > ?2",
+            &origin,
+            &now,
+        ) {
+            Ok(s) => s,
+            Err(e) => {
+                tracing::error!(error = ?e, "failed to prepare get backoff_until");
+                return DeliverySlot::Busy;
+            }
+        };
+        match stmt.first::<chrono::DateTime<Utc>>(Some("backoff_until")).await {
+            Ok(Some(until)) => DeliverySlot::BackingOff(until),
+            Ok(None) => DeliverySlot::Busy,
+            Err(e) => {
+                tracing::error!(error = ?e, "failed to execute get backoff_until");
+                DeliverySlot::Busy
             }
         }
     }
 
     #[worker::send]
-    async fn remove_follower_by_actor(&self, username: &str, actor: &str) {
+    async fn release_delivery_slot(&self, origin: &str) {
         match worker::query!(
             self.db.as_ref(),
-            "DELETE FROM followers WHERE username = ?1 AND follower_id = ?2",
-            &username,
-            &actor,
+            "UPDATE delivery_hosts SET in_flight = max(in_flight - 1, 0) WHERE origin = ?1",
+            &origin
         ) {
             Ok(stmt) => {
                 if let Err(e) = stmt.run().await {
-                    tracing::error!(error = ?e, "failed to delete follower by actor");
+                    tracing::error!(error = ?e, "failed to release delivery slot");
                 }
             }
             Err(e) => {
-                tracing::error!(error = ?e, "failed to prepare delete follower by actor");
+                tracing::error!(error = ?e, "failed to prepare release delivery slot");
             }
         }
     }
 
     #[worker::send]
-    async fn get_followers_inbox_batch(&self, username: &str, last_inbox: &str) -> (ArrayVec<String, 10>, String) {
+    async fn back_off_host(&self, origin: &str, until: chrono::DateTime<Utc>) -> Result<(), Self::Error> {
+        worker::query!(
+            self.db.as_ref(),
+            "INSERT INTO delivery_hosts (origin, in_flight, lease_until, backoff_until) VALUES (?1, 0, ?2, ?2) \
+             ON CONFLICT (origin) DO UPDATE SET \
+             backoff_until = max(coalesce(delivery_hosts.backoff_until, ''), excluded.backoff_until)",
+            &origin,
+            &until.to_rfc3339(),
+        )?
+        .run()
+        .await?;
+        Ok(())
+    }
+}
+
+impl RelayStore for WorkerState {
+    type Error = worker::Error;
+
+    #[worker::send]
+    async fn add_relay(&self, relay: &Relay) -> Result<(), Self::Error> {
+        let kind = match relay.kind {
+            RelayKind::Mastodon => "Mastodon",
+            RelayKind::LitePub => "LitePub",
+        };
+        worker::query!(
+            self.db.as_ref(),
+            "INSERT OR REPLACE INTO relays (actor, inbox, kind, username, follow_id, accepted_at) VALUES (?1, ?2, ?3, ?4, ?5, NULL)",
+            &relay.actor,
+            &relay.inbox,
+            &kind,
+            &relay.username,
+            &relay.follow_id,
+        )?
+        .run()
+        .await?;
+        Ok(())
+    }
+
+    #[worker::send]
+    async fn get_relay(&self, actor: &str) -> Option<Relay> {
         let stmt = match worker::query!(
             self.db.as_ref(),
-            "SELECT DISTINCT inbox FROM followers WHERE username = ?1 AND inbox > ?2 ORDER BY inbox LIMIT 10",
+            "SELECT actor, inbox, kind, username, follow_id, accepted_at FROM relays WHERE actor = ?1",
+            &actor
+        ) {
+            Ok(s) => s,
+            Err(e) => {
+                tracing::error!(error = ?e, "failed to prepare get_relay");
+                return None;
+            }
+        };
+        match stmt.first::<Relay>(None).await {
+            Ok(relay) => relay,
+            Err(e) => {
+                tracing::error!(error = ?e, "failed to execute get_relay");
+                None
+            }
+        }
+    }
+
+    #[worker::send]
+    async fn accept_relay(&self, actor: &str, follow_id: &str, accepted_at: chrono::DateTime<Utc>) -> Result<bool, Self::Error> {
+        let accepted = worker::query!(
+            self.db.as_ref(),
+            "UPDATE relays SET accepted_at = ?3 WHERE actor = ?1 AND follow_id = ?2 RETURNING actor",
+            &actor,
+            &follow_id,
+            &accepted_at.to_rfc3339(),
+        )?
+        .first::<String>(Some("actor"))
+        .await?;
+        Ok(accepted.is_some())
+    }
+
+    #[worker::send]
+    async fn remove_relay(&self, actor: &str) -> Result<(), Self::Error> {
+        worker::query!(self.db.as_ref(), "DELETE FROM relays WHERE actor = ?1", &actor)?
+            .run()
+            .await?;
+        Ok(())
+    }
+
+    #[worker::send]
+    async fn remove_relays_by_inbox(&self, inbox: &str) -> Result<(), Self::Error> {
+        worker::query!(self.db.as_ref(), "DELETE FROM relays WHERE inbox = ?1", &inbox)?
+            .run()
+            .await?;
+        Ok(())
+    }
+
+    #[worker::send]
+    async fn get_relay_inboxes(&self) -> Result<Vec<String>, Self::Error> {
+        let stmt = worker::query!(self.db.as_ref(), "SELECT DISTINCT inbox FROM relays WHERE accepted_at IS NOT NULL");
+        let rows: Vec<Vec<String>> = stmt.raw().await?;
+        Ok(rows.into_iter().filter_map(|mut row| row.pop()).collect())
+    }
+}
+
+impl FollowingStore for WorkerState {
+    type Error = worker::Error;
+
+    #[worker::send]
+    async fn add_following(&self, following: &Following) -> Result<(), Self::Error> {
+        worker::query!(
+            self.db.as_ref(),
+            "INSERT OR REPLACE INTO following (username, actor, inbox, follow_id, accepted_at) VALUES (?1, ?2, ?3, ?4, NULL)",
+            &following.username,
+            &following.actor,
+            &following.inbox,
+            &following.follow_id,
+        )?
+        .run()
+        .await?;
+        Ok(())
+    }
+
+    #[worker::send]
+    async fn get_following(&self, username: &str, actor: &str) -> Option<Following> {
+        let stmt = match worker::query!(
+            self.db.as_ref(),
+            "SELECT username, actor, inbox, follow_id, accepted_at FROM following WHERE username = ?1 AND actor = ?2",
             &username,
-            &last_inbox
+            &actor
         ) {
             Ok(s) => s,
             Err(e) => {
-                tracing::error!(error = ?e, "failed to prepare get_followers_inbox_batch");
-                return (ArrayVec::new(), last_inbox.to_string());
+                tracing::error!(error = ?e, "failed to prepare get_following");
+                return None;
+            }
+        };
+        match stmt.first::<Following>(None).await {
+            Ok(following) => following,
+            Err(e) => {
+                tracing::error!(error = ?e, "failed to execute get_following");
+                None
+            }
+        }
+    }
+
+    #[worker::send]
+    async fn accept_following(&self, actor: &str, follow_id: &str, accepted_at: chrono::DateTime<Utc>) -> Result<bool, Self::Error> {
+        let accepted = worker::query!(
+            self.db.as_ref(),
+            "UPDATE following SET accepted_at = ?3 WHERE actor = ?1 AND follow_id = ?2 RETURNING actor",
+            &actor,
+            &follow_id,
+            &accepted_at.to_rfc3339(),
+        )?
+        .first::<String>(Some("actor"))
+        .await?;
+        Ok(accepted.is_some())
+    }
+
+    #[worker::send]
+    async fn reject_following(&self, actor: &str, follow_id: &str) -> Result<bool, Self::Error> {
+        let rejected = worker::query!(
+            self.db.as_ref(),
+            "DELETE FROM following WHERE actor = ?1 AND follow_id = ?2 RETURNING actor",
+            &actor,
+            &follow_id,
+        )?
+        .first::<String>(Some("actor"))
+        .await?;
+        Ok(rejected.is_some())
+    }
+
+    #[worker::send]
+    async fn remove_following(&self, username: &str, actor: &str) -> Result<(), Self::Error> {
+        worker::query!(
+            self.db.as_ref(),
+            "DELETE FROM following WHERE username = ?1 AND actor = ?2",
+            &username,
+            &actor
+        )?
+        .run()
+        .await?;
+        Ok(())
+    }
+
+    #[worker::send]
+    async fn get_following_actors(&self, username: &str) -> Vec<String> {
+        let stmt = match worker::query!(
+            self.db.as_ref(),
+            "SELECT actor FROM following WHERE username = ?1 AND accepted_at IS NOT NULL ORDER BY accepted_at DESC",
+            &username
+        ) {
+            Ok(s) => s,
+            Err(e) => {
+                tracing::error!(error = ?e, "failed to prepare get_following_actors");
+                return Vec::new();
             }
         };
         let rows: Vec<Vec<String>> = match stmt.raw().await {
             Ok(r) => r,
             Err(e) => {
-                tracing::error!(error = ?e, "failed to execute get_followers_inbox_batch");
-                return (ArrayVec::new(), last_inbox.to_string());
+                tracing::error!(error = ?e, "failed to execute get_following_actors");
+                return Vec::new();
             }
         };
-        let mut vec = ArrayVec::<String, 10>::new();
-        for mut row in rows {
-            if let Some(inbox) = row.pop()
-                && vec.try_push(inbox).is_err()
-            {
-                break;
+        rows.into_iter().filter_map(|mut row| row.pop()).collect()
+    }
+}
+
+impl RateLimiter for WorkerState {
+    #[worker::send]
+    async fn hit_rate_limit(&self, key: &str, limit: RateLimit) -> Option<chrono::TimeDelta> {
+        let now = Utc::now().timestamp();
+        let window = limit.window.num_seconds().max(1);
+        let window_start = now - now.rem_euclid(window);
+        let stmt = match worker::query!(
+            self.db.as_ref(),
+            "INSERT INTO rate_limits (key, window_start, count) VALUES (?1, ?2, 1)\
+             ON CONFLICT (key) DO UPDATE SET \
+             count = CASE WHEN rate_limits.window_start = excluded.window_start THEN rate_limits.count + 1 ELSE 1 END,\
+             window_start = excluded.window_start \
+             RETURNING count",
+            &key,
+            &window_start,
+        ) {
+            Ok(s) => s,
+            Err(e) => {
+                tracing::error!(error = ?e, "failed to prepare hit_rate_limit");
+                return None;
+            }
+        };
+        match stmt.first::<u32>(Some("count")).await {
+            Ok(Some(count)) if count > limit.max_requests => Some(chrono::TimeDelta::seconds(window_start + window - now)),
+            Ok(_) => None,
+            Err(e) => {
+                tracing::error!(error = ?e, "failed to execute hit_rate_limit");
+                None
             }
         }
-        let next_last = vec.last().cloned().unwrap_or_default();
-        (vec, next_last)
     }
 }
+
 impl Queue for WorkerState {
-    async fn enqueue(&self, data: QueueData) {
+    type Error = worker::Error;
+
+    async fn enqueue(&self, data: QueueData) -> Result<(), Self::Error> {
+        worker::send::SendFuture::new(async move { self.queue.send(data).await }).await
+    }
+
+    async fn enqueue_batch(&self, data: Vec<QueueData>) -> Result<(), Self::Error> {
+        if data.is_empty() {
+            return Ok(());
+        }
+        worker::send::SendFuture::new(async move { self.queue.send_batch(data).await }).await
+    }
+
+    async fn enqueue_with_delay(&self, data: QueueData, delay: chrono::TimeDelta) -> Result<(), Self::Error> {
+        // Cloudflare Queues accept delays of up to 12 hours
+        let delay_seconds = delay.num_seconds().clamp(0, 12 * 60 * 60) as u32;
         worker::send::SendFuture::new(async move {
-            if let Err(e) = self.queue.send(data).await {
-                worker::console_error!("failed to enqueue: {:?}", e);
-            }
+            self.queue
+                .send(worker::MessageBuilder::new(data).delay_seconds(delay_seconds).build())
+                .await
         })
         .await
     }
@@ -422,16 +1010,49 @@ fn start() {
     tracing_subscriber::registry().with(fmt_layer).with(perf_layer).init();
 }
 
+/// Number of messages of a queue batch processed at the same time unless `QUEUE_PARALLELISM` is set.
+#[cfg(feature = "activitypub")]
+const DEFAULT_QUEUE_PARALLELISM: usize = 8;
+
+/// Parses `{"<username>": [{"id": "<key id fragment>", "pem": "<PKCS#8 PEM>"}, ...]}`, the key signing requests first.
+fn parse_signing_keys(json: &str) -> worker::Result<HashMap<String, Vec<ConfiguredSigningKey>>> {
+    #[derive(serde::Deserialize)]
+    struct SigningKeyConfig {
+        id: String,
+        pem: String,
+    }
+    let config = serde_json::from_str::<HashMap<String, Vec<SigningKeyConfig>>>(json)?;
+    let mut keys = HashMap::with_capacity(config.len());
+    for (username, config) in config {
+        let user_keys = config
+            .into_iter()
+            .map(|SigningKeyConfig { id, pem }| match RSASHA2SigningKey::from_pkcs8_pem(&pem) {
+                Ok(key) => Ok(ConfiguredSigningKey { id, key }),
+                Err(e) => Err(worker::Error::RustError(format!("invalid signing key {id} of {username}: {e}"))),
+            })
+            .collect::<worker::Result<Vec<_>>>()?;
+        keys.insert(username, user_keys);
+    }
+    Ok(keys)
+}
+
 // Setup function to create WorkerState from environment
 fn setup_worker_state(env: &Env) -> worker::Result<WorkerState> {
     console_error_panic_hook::set_once();
     let pem = env.var("PRIVATE_KEY_PEM").unwrap().to_string();
     let signing_key = RSASHA2SigningKey::from_pkcs8_pem(&pem).unwrap();
+    let user_signing_keys = match env.var("SIGNING_KEYS") {
+        Ok(keys) => parse_signing_keys(&keys.to_string())?,
+        Err(_) => HashMap::new(),
+    };
+    let integrity_secret = env.var("INTEGRITY_KEY_SECRET").ok().map(|secret| secret.to_string());
     let queue = env.queue("JOB_QUEUE")?;
     let db = std::sync::Arc::new(env.d1("BLOG_DB")?);
     Ok(WorkerState {
         env: env.clone(),
         signing_key,
+        user_signing_keys: std::sync::Arc::new(user_signing_keys),
+        integrity_secret,
         queue,
         db,
     })
@@ -448,8 +1069,12 @@ compile_error!("Cannot enable both preview and activitypub features at the same
 
 #[cfg(feature = "activitypub")]
 #[event(fetch)]
-async fn fetch(req: HttpRequest, env: Env, _ctx: Context) -> worker::Result<http::Response<Body>> {
+async fn fetch(mut req: HttpRequest, env: Env, _ctx: Context) -> worker::Result<http::Response<Body>> {
     let state = setup_worker_state(&env)?;
+    // Cloudflare sets this header to the connecting address, replacing any value sent by the client
+    if let Some(addr) = req.headers().get("cf-connecting-ip").and_then(|addr| addr.to_str().ok()?.parse().ok()) {
+        req.extensions_mut().insert(ClientAddr(addr));
+    }
     Ok(router(state.clone()).with_state::<()>(state).call(req).await?)
 }
 
@@ -494,13 +1119,24 @@ async fn fetch(_req: HttpRequest, _env: Env, _ctx: Context) -> worker::Result<ht
 #[event(queue)]
 async fn queue_event(batch: worker::MessageBatch<QueueData>, env: Env, _ctx: Context) -> worker::Result<()> {
     let state = setup_worker_state(&env)?;
-    for message in batch.messages()? {
-        let data = message.body().clone();
-        match process_queue(&state, data).await {
-            ProcessQueueResult::Finished => message.ack(),
-            ProcessQueueResult::Retry => message.retry(),
-        }
-    }
+    let parallelism = env
+        .var("QUEUE_PARALLELISM")
+        .ok()
+        .and_then(|parallelism| parallelism.to_string().parse::<usize>().ok())
+        .unwrap_or(DEFAULT_QUEUE_PARALLELISM)
+        .max(1);
+    futures::stream::iter(batch.messages()?)
+        .for_each_concurrent(parallelism, |message| {
+            let state = &state;
+            async move {
+                let data = message.body().clone();
+                match process_queue(state, data).await {
+                    ProcessQueueResult::Finished => message.ack(),
+                    ProcessQueueResult::Retry => message.retry(),
+                }
+            }
+        })
+        .await;
     Ok(())
 }
 
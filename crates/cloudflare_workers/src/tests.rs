@@ -1,14 +1,27 @@
 use crate::WorkerState;
-use fblog_system_core::traits::{ArticleNewReaction, ArticleProvider, Env, UserProvider};
+use fblog_system_core::traits::{
+    ArticleNewReaction, ArticleProvider, CachedPublicKey, DeliveryLog, DeliveryScheduler, DeliverySlot, Env, Following, FollowingStore,
+    InstanceAvailability, ProcessedActivityStore, PublicKeyCache, RateLimit, RateLimiter, Relay, RelayKind, RelayStore, UserProvider,
+};
 use serde_json::json;
 use std::collections::HashSet;
 
 pub async fn run_all_tests(state: WorkerState) {
     test_basic_methods(&state).await;
     test_env_trait_methods(&state).await;
+    test_signing_keys();
     test_article_provider_methods(&state).await;
     test_user_provider_methods(&state).await;
     test_reaction_methods(&state).await;
+    test_public_key_cache_methods(&state).await;
+    test_processed_activity_methods(&state).await;
+    test_rate_limiter_methods(&state).await;
+    test_instance_availability_methods(&state).await;
+    test_delivery_scheduler_methods(&state).await;
+    test_delivery_log_methods(&state).await;
+    test_deleted_user_methods(&state).await;
+    test_relay_methods(&state).await;
+    test_following_methods(&state).await;
 }
 
 async fn test_basic_methods(state: &WorkerState) {
@@ -18,7 +31,22 @@ async fn test_basic_methods(state: &WorkerState) {
 
 async fn test_env_trait_methods(state: &WorkerState) {
     assert_eq!(state.url().to_string(), "https://local.test");
-    state.signing_key();
+    assert_eq!(state.signing_key("user1").id, "main-key");
+    assert!(state.retiring_keys("user1").is_empty());
+}
+
+fn test_signing_keys() {
+    let pem = include_str!("../../../test_config/private-key-for-test.pem");
+    let config = json!({
+        "user1": [{ "id": "key-2", "pem": pem }, { "id": "main-key", "pem": pem }],
+    });
+    let keys = crate::parse_signing_keys(&config.to_string()).unwrap();
+    let ids = keys["user1"].iter().map(|key| key.id.as_str()).collect::<Vec<_>>();
+    assert_eq!(ids, ["key-2", "main-key"]);
+    assert!(!keys.contains_key("user2"));
+
+    let config = json!({ "user1": [{ "id": "key-2", "pem": "not a key" }] });
+    assert!(crate::parse_signing_keys(&config.to_string()).is_err());
 }
 
 async fn test_article_provider_methods(state: &WorkerState) {
@@ -74,11 +102,11 @@ async fn test_user_provider_methods(state: &WorkerState) {
         let follower_id1 = format!("https://{c}.test/user1");
         let inbox_url = format!("https://{c}.test/inbox");
         let event_id = format!("https://{c}.test/follow/event-1");
-        state.add_follower("user1", &follower_id1, &inbox_url, &event_id).await;
+        state.add_follower("user1", &follower_id1, &inbox_url, &event_id).await.unwrap();
 
         let follower_id2 = format!("https://{c}.test/user2");
         let event_id = format!("https://{c}.test/follow/event-2");
-        state.add_follower("user1", &follower_id2, &inbox_url, &event_id).await;
+        state.add_follower("user1", &follower_id2, &inbox_url, &event_id).await.unwrap();
 
         expect_all_followers_inbox.insert(inbox_url);
     }
@@ -86,7 +114,7 @@ async fn test_user_provider_methods(state: &WorkerState) {
     let mut actual_all_followers_inbox = HashSet::new();
     let mut last_inbox = String::new();
     loop {
-        let (inboxes, next_last_inbox) = state.get_followers_inbox_batch(username, &last_inbox).await;
+        let (inboxes, next_last_inbox) = state.get_followers_inbox_batch(username, &last_inbox).await.unwrap();
         if inboxes.is_empty() {
             break;
         } else {
@@ -96,6 +124,13 @@ async fn test_user_provider_methods(state: &WorkerState) {
     }
 
     assert_eq!(actual_all_followers_inbox, expect_all_followers_inbox);
+
+    assert_eq!(
+        state.get_follower_by_event_id(username, "https://a.test/follow/event-2").await.as_deref(),
+        Some("https://a.test/user2")
+    );
+    assert!(state.get_follower_by_event_id("user2", "https://a.test/follow/event-2").await.is_none());
+    assert!(state.get_follower_by_event_id(username, "https://a.test/follow/event-3").await.is_none());
 }
 
 async fn test_reaction_methods(state: &WorkerState) {
@@ -121,7 +156,7 @@ async fn test_reaction_methods(state: &WorkerState) {
         proceed_at: state.timestamp_now(),
         raw: raw1,
     };
-    state.add_reaction("article1", reaction1).await;
+    state.add_reaction("article1", reaction1).await.unwrap();
     assert_eq!(state.reaction_count("article1").await, 1);
 
     // add second reaction by another actor
@@ -143,14 +178,268 @@ async fn test_reaction_methods(state: &WorkerState) {
         proceed_at: state.timestamp_now(),
         raw: raw2,
     };
-    state.add_reaction("article1", reaction2).await;
+    state.add_reaction("article1", reaction2).await.unwrap();
     assert_eq!(state.reaction_count("article1").await, 2);
 
     // remove reactions of actor1
-    state.remove_reaction_by("article1", "https://actor1.test/users/actor1").await;
+    state.remove_reaction_by("article1", "https://actor1.test/users/actor1").await.unwrap();
     assert_eq!(state.reaction_count("article1").await, 1);
 
     // removing again should not make count negative
-    state.remove_reaction_by("article1", "https://actor1.test/users/actor1").await;
+    state.remove_reaction_by("article1", "https://actor1.test/users/actor1").await.unwrap();
     assert_eq!(state.reaction_count("article1").await, 0);
 }
+
+async fn test_public_key_cache_methods(state: &WorkerState) {
+    let key_id = "https://actor1.test/users/actor1#main-key";
+    assert!(state.get_public_key(key_id).await.is_none());
+
+    let expires_at = state.timestamp_now() + chrono::TimeDelta::hours(1);
+    let key = CachedPublicKey {
+        actor: "https://actor1.test/users/actor1".to_owned(),
+        pem: "pem-1".to_owned(),
+        expires_at,
+    };
+    state.put_public_key(key_id, key).await.unwrap();
+    let cached = state.get_public_key(key_id).await.unwrap();
+    assert_eq!(cached.actor, "https://actor1.test/users/actor1");
+    assert_eq!(cached.pem, "pem-1");
+    assert_eq!(cached.expires_at.timestamp(), expires_at.timestamp());
+
+    // a refetched key replaces the cached one
+    let key = CachedPublicKey {
+        actor: "https://actor1.test/users/actor1".to_owned(),
+        pem: "pem-2".to_owned(),
+        expires_at,
+    };
+    state.put_public_key(key_id, key).await.unwrap();
+    assert_eq!(state.get_public_key(key_id).await.unwrap().pem, "pem-2");
+}
+
+async fn test_processed_activity_methods(state: &WorkerState) {
+    let activity_id = "https://actor1.test/activities/like-1";
+    let expires_at = state.timestamp_now() + chrono::TimeDelta::hours(1);
+    assert!(!state.is_activity_processed("user1", activity_id).await.unwrap());
+    state.mark_activity_processed("user1", activity_id, expires_at).await.unwrap();
+    assert!(state.is_activity_processed("user1", activity_id).await.unwrap());
+    // the same activity delivered to another local inbox is processed there too
+    assert!(!state.is_activity_processed("user2", activity_id).await.unwrap());
+
+    // marking it again is harmless
+    state.mark_activity_processed("user1", activity_id, expires_at).await.unwrap();
+    assert!(state.is_activity_processed("user1", activity_id).await.unwrap());
+
+    // an expired record no longer suppresses the activity
+    let activity_id = "https://actor1.test/activities/like-2";
+    state
+        .mark_activity_processed("user1", activity_id, state.timestamp_now() - chrono::TimeDelta::hours(1))
+        .await;
+    assert!(!state.is_activity_processed("user1", activity_id).await.unwrap());
+}
+
+async fn test_rate_limiter_methods(state: &WorkerState) {
+    let limit = RateLimit {
+        max_requests: 2,
+        window: chrono::TimeDelta::hours(1),
+    };
+    assert!(state.hit_rate_limit("inbox:host:actor1.test", limit).await.is_none());
+    assert!(state.hit_rate_limit("inbox:host:actor1.test", limit).await.is_none());
+    let retry_after = state.hit_rate_limit("inbox:host:actor1.test", limit).await.unwrap();
+    assert!(retry_after > chrono::TimeDelta::zero() && retry_after <= limit.window);
+
+    // other keys have their own budget
+    assert!(state.hit_rate_limit("inbox:host:actor2.test", limit).await.is_none());
+}
+
+async fn test_instance_availability_methods(state: &WorkerState) {
+    let username = "user2";
+    for host in ["alive", "dead", "gone"] {
+        let follower_id = format!("https://{host}.test/users/{host}");
+        let inbox = format!("https://{host}.test/users/{host}/inbox");
+        let event_id = format!("https://{host}.test/follow/event-1");
+        state.add_follower(username, &follower_id, &inbox, &event_id).await.unwrap();
+    }
+
+    // failures are counted once per day
+    let now = state.timestamp_now();
+    let failures = state.record_delivery_failure("https://dead.test", now).await;
+    assert_eq!(failures.failure_days, 1);
+    let failures = state.record_delivery_failure("https://dead.test", now).await;
+    assert_eq!(failures.failure_days, 1);
+    let failures = state.record_delivery_failure("https://dead.test", now + chrono::TimeDelta::days(1)).await;
+    assert_eq!(failures.failure_days, 2);
+    assert_eq!(failures.first_failed_at.timestamp(), now.timestamp());
+
+    // unavailable hosts are left out of follower batches
+    state
+        .mark_unavailable("https://dead.test", now + chrono::TimeDelta::hours(1))
+        .await
+        .unwrap();
+    let (inboxes, _) = state.get_followers_inbox_batch(username, "").await.unwrap();
+    assert_eq!(
+        inboxes.as_slice(),
+        ["https://alive.test/users/alive/inbox", "https://gone.test/users/gone/inbox"]
+    );
+
+    state.clear_delivery_failures("https://dead.test").await.unwrap();
+    let (inboxes, _) = state.get_followers_inbox_batch(username, "").await.unwrap();
+    assert_eq!(inboxes.len(), 3);
+
+    state.remove_followers_by_inbox("https://gone.test/users/gone/inbox").await.unwrap();
+    state.remove_followers_by_origin("https://dead.test").await.unwrap();
+    let (inboxes, _) = state.get_followers_inbox_batch(username, "").await.unwrap();
+    assert_eq!(inboxes.as_slice(), ["https://alive.test/users/alive/inbox"]);
+}
+
+async fn test_delivery_scheduler_methods(state: &WorkerState) {
+    let origin = "https://busy.test";
+    let now = state.timestamp_now();
+    let lease_until = now + chrono::TimeDelta::minutes(2);
+    assert_eq!(state.acquire_delivery_slot(origin, 2, now, lease_until).await, DeliverySlot::Acquired);
+    assert_eq!(state.acquire_delivery_slot(origin, 2, now, lease_until).await, DeliverySlot::Acquired);
+    assert_eq!(state.acquire_delivery_slot(origin, 2, now, lease_until).await, DeliverySlot::Busy);
+
+    state.release_delivery_slot(origin).await;
+    assert_eq!(state.acquire_delivery_slot(origin, 2, now, lease_until).await, DeliverySlot::Acquired);
+
+    // slots that were never released are given back once their lease ends
+    assert_eq!(
+        state
+            .acquire_delivery_slot(origin, 2, lease_until, lease_until + chrono::TimeDelta::minutes(2))
+            .await,
+        DeliverySlot::Acquired
+    );
+
+    let until = now + chrono::TimeDelta::minutes(5);
+    state.back_off_host(origin, until).await.unwrap();
+    let DeliverySlot::BackingOff(backoff_until) = state.acquire_delivery_slot(origin, 2, now, lease_until).await else {
+        panic!("host should be backing off");
+    };
+    assert_eq!(backoff_until.timestamp(), until.timestamp());
+
+    // other hosts are not affected
+    assert_eq!(
+        state.acquire_delivery_slot("https://idle.test", 2, now, lease_until).await,
+        DeliverySlot::Acquired
+    );
+}
+
+async fn test_delivery_log_methods(state: &WorkerState) {
+    let activity_id = "https://local.test/events/articles/update/article1";
+    let inbox = "https://actor1.test/inbox";
+    assert!(!state.is_delivered(activity_id, inbox).await);
+
+    let expires_at = state.timestamp_now() + chrono::TimeDelta::hours(1);
+    state.record_delivery(activity_id, inbox, expires_at).await.unwrap();
+    assert!(state.is_delivered(activity_id, inbox).await);
+    assert!(!state.is_delivered(activity_id, "https://actor2.test/inbox").await);
+    assert!(!state.is_delivered("https://local.test/events/articles/create/article1", inbox).await);
+
+    state.forget_deliveries(activity_id).await;
+    assert!(!state.is_delivered(activity_id, inbox).await);
+
+    // expired records are ignored
+    state
+        .record_delivery(activity_id, inbox, state.timestamp_now() - chrono::TimeDelta::hours(1))
+        .await;
+    assert!(!state.is_delivered(activity_id, inbox).await);
+}
+
+async fn test_deleted_user_methods(state: &WorkerState) {
+    let username = "removed_user";
+    let follower_id = "https://actor1.test/users/actor1";
+    let inbox = "https://actor1.test/users/actor1/inbox";
+    state
+        .add_follower(username, follower_id, inbox, "https://actor1.test/follow/event-1")
+        .await
+        .unwrap();
+    assert!(state.user_deleted_at(username).await.is_none());
+
+    let deleted_at = state.timestamp_now();
+    state.mark_user_deleted(username, deleted_at).await.unwrap();
+    assert_eq!(state.user_deleted_at(username).await.unwrap().timestamp(), deleted_at.timestamp());
+    // deleting again keeps the first date
+    state.mark_user_deleted(username, deleted_at + chrono::TimeDelta::days(1)).await.unwrap();
+    assert_eq!(state.user_deleted_at(username).await.unwrap().timestamp(), deleted_at.timestamp());
+
+    state.remove_followers_of(username).await.unwrap();
+    let (inboxes, _) = state.get_followers_inbox_batch(username, "").await.unwrap();
+    assert!(inboxes.is_empty());
+    assert!(state.user_deleted_at("user1").await.is_none());
+}
+
+async fn test_relay_methods(state: &WorkerState) {
+    let actor = "https://relay.test/actor";
+    let relay = Relay {
+        actor: actor.to_string(),
+        inbox: "https://relay.test/inbox".to_string(),
+        kind: RelayKind::LitePub,
+        username: "user1".to_string(),
+        follow_id: "https://local.test/users/user1#follows/1".to_string(),
+        accepted_at: None,
+    };
+    state.add_relay(&relay).await.unwrap();
+    let stored = state.get_relay(actor).await.unwrap();
+    assert_eq!(stored.kind, RelayKind::LitePub);
+    assert_eq!(stored.follow_id, relay.follow_id);
+    assert!(stored.accepted_at.is_none());
+    // pending relays receive nothing
+    assert!(state.get_relay_inboxes().await.unwrap().is_empty());
+
+    assert!(
+        !state
+            .accept_relay(actor, "https://local.test/users/user1#follows/0", state.timestamp_now())
+            .await
+            .unwrap()
+    );
+    assert!(state.get_relay_inboxes().await.unwrap().is_empty());
+    assert!(state.accept_relay(actor, &relay.follow_id, state.timestamp_now()).await.unwrap());
+    assert_eq!(state.get_relay_inboxes().await.unwrap(), ["https://relay.test/inbox"]);
+
+    // subscribing again waits for a new accept
+    state.add_relay(&relay).await.unwrap();
+    assert!(state.get_relay_inboxes().await.unwrap().is_empty());
+
+    state.remove_relay(actor).await.unwrap();
+    assert!(state.get_relay(actor).await.is_none());
+
+    state.add_relay(&relay).await.unwrap();
+    state.remove_relays_by_inbox(&relay.inbox).await.unwrap();
+    assert!(state.get_relay(actor).await.is_none());
+}
+
+async fn test_following_methods(state: &WorkerState) {
+    let actor = "https://actor1.test/users/actor1";
+    let following = Following {
+        username: "user1".to_string(),
+        actor: actor.to_string(),
+        inbox: "https://actor1.test/users/actor1/inbox".to_string(),
+        follow_id: "https://local.test/users/user1#follows/1".to_string(),
+        accepted_at: None,
+    };
+    state.add_following(&following).await.unwrap();
+    let stored = state.get_following("user1", actor).await.unwrap();
+    assert_eq!(stored.inbox, following.inbox);
+    assert!(stored.accepted_at.is_none());
+    assert!(state.get_following("user2", actor).await.is_none());
+    // pending follows are not listed
+    assert!(state.get_following_actors("user1").await.is_empty());
+
+    assert!(
+        !state
+            .accept_following(actor, "https://local.test/users/user1#follows/0", state.timestamp_now())
+            .await
+            .unwrap()
+    );
+    assert!(state.accept_following(actor, &following.follow_id, state.timestamp_now()).await.unwrap());
+    assert_eq!(state.get_following_actors("user1").await, [actor]);
+    assert!(state.get_following_actors("user2").await.is_empty());
+
+    assert!(!state.reject_following(actor, "https://local.test/users/user1#follows/0").await.unwrap());
+    assert!(state.reject_following(actor, &following.follow_id).await.unwrap());
+    assert!(state.get_following("user1", actor).await.is_none());
+
+    state.add_following(&following).await.unwrap();
+    state.remove_following("user1", actor).await.unwrap();
+    assert!(state.get_following("user1", actor).await.is_none());
+}
@@ -1,4 +1,10 @@
+pub mod actor;
+pub mod follow;
 pub mod headers;
+pub mod integrity;
+pub mod jsonld;
+pub mod ld_signature;
 pub mod macros;
+pub mod publication;
 pub mod sign;
 pub mod verify;
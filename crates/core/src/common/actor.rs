@@ -0,0 +1,143 @@
+//! The actor document as it is served, with the keys configured on the backend.
+
+use crate::common::integrity;
+use crate::traits::Env;
+use axum::body::Body;
+use http_body_util::{BodyExt, Limited};
+use rsa::RsaPublicKey;
+use rsa::pkcs8::{EncodePublicKey, LineEnding};
+use serde_json::{Map, Value};
+
+const SECURITY_CONTEXT: &str = "https://w3id.org/security/v1";
+
+/// Publishes the signing keys and the integrity key of `username` on their deployed actor in `body`.
+pub async fn actor_document<E>(state: &E, username: &str, body: Body) -> Option<Body>
+where
+    E: Env,
+{
+    let body = BodyExt::collect(Limited::new(body, 1024 * 64)).await.ok()?.to_bytes();
+    let mut actor = serde_json::from_slice::<Map<String, Value>>(&body).ok()?;
+    let actor_id = format!("{}/users/{username}", state.url());
+    let mut context = match actor.remove("@context") {
+        Some(Value::Array(context)) => context,
+        Some(context) => vec![context],
+        None => Vec::new(),
+    };
+    if !context.iter().any(|context| context == SECURITY_CONTEXT) {
+        context.push(SECURITY_CONTEXT.into());
+    }
+    // the key signing requests comes first, implementations reading a single key pick it up
+    let mut public_keys = Vec::new();
+    for key in std::iter::once(state.signing_key(username)).chain(state.retiring_keys(username)) {
+        let pem = RsaPublicKey::from(key.key.as_ref()).to_public_key_pem(LineEnding::LF).ok()?;
+        public_keys.push(serde_json::json!({
+            "id": key.key_id(&actor_id),
+            "type": "Key",
+            "owner": actor_id,
+            "publicKeyPem": pem,
+        }));
+    }
+    let public_key = match <[Value; 1]>::try_from(public_keys) {
+        Ok([public_key]) => public_key,
+        Err(public_keys) => Value::Array(public_keys),
+    };
+    actor.insert("publicKey".to_owned(), public_key);
+    if let Some(key) = state.integrity_key(username) {
+        if !context.iter().any(|context| context == integrity::MULTIKEY_CONTEXT) {
+            context.push(integrity::MULTIKEY_CONTEXT.into());
+        }
+        actor.insert(
+            "assertionMethod".to_owned(),
+            Value::Array(vec![integrity::assertion_method(&actor_id, &key)]),
+        );
+    }
+    actor.insert("@context".to_owned(), Value::Array(context));
+    Some(Body::from(serde_json::to_vec(&actor).ok()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::actor_document;
+    use crate::common::integrity::MULTIKEY_CONTEXT;
+    use crate::traits::{Ed25519SigningKey, Env, RSASHA2SigningKey, UserSigningKey};
+    use axum::body::Body;
+    use chrono::{DateTime, Utc};
+    use rsa::pkcs8::DecodePrivateKey;
+    use std::fmt::Display;
+
+    const PRIVATE_KEY: &str = include_str!("../../../../test_config/private-key-for-test.pem");
+
+    struct TestState {
+        key: RSASHA2SigningKey,
+        rotating: bool,
+    }
+
+    impl Env for TestState {
+        fn url(&self) -> impl Display + Send + '_ {
+            "https://blog.test"
+        }
+        fn timestamp_now(&self) -> DateTime<Utc> {
+            DateTime::parse_from_rfc3339("2025-06-01T00:00:00Z").unwrap().to_utc()
+        }
+        fn signing_key(&self, _username: &str) -> UserSigningKey<'_> {
+            UserSigningKey { id: "key-2", key: &self.key }
+        }
+        fn retiring_keys(&self, _username: &str) -> Vec<UserSigningKey<'_>> {
+            match self.rotating {
+                true => vec![UserSigningKey {
+                    id: "main-key",
+                    key: &self.key,
+                }],
+                false => Vec::new(),
+            }
+        }
+        fn integrity_key(&self, _username: &str) -> Option<Ed25519SigningKey> {
+            None
+        }
+    }
+
+    fn served_actor(rotating: bool) -> serde_json::Value {
+        let state = TestState {
+            key: RSASHA2SigningKey::from_pkcs8_pem(PRIVATE_KEY).unwrap(),
+            rotating,
+        };
+        let deployed = serde_json::json!({
+            "@context": ["https://www.w3.org/ns/activitystreams"],
+            "id": "https://blog.test/users/alice",
+            "type": "Person",
+            "publicKey": { "id": "https://blog.test/users/alice#main-key", "publicKeyPem": "stale" },
+        });
+        futures::executor::block_on(async {
+            let body = actor_document(&state, "alice", Body::from(deployed.to_string())).await.unwrap();
+            serde_json::from_slice(&axum::body::to_bytes(body, usize::MAX).await.unwrap()).unwrap()
+        })
+    }
+
+    #[test]
+    fn publishes_the_configured_signing_key() {
+        let actor = served_actor(false);
+        assert_eq!(actor["publicKey"]["id"], "https://blog.test/users/alice#key-2");
+        assert_eq!(actor["publicKey"]["owner"], "https://blog.test/users/alice");
+        assert!(
+            actor["publicKey"]["publicKeyPem"]
+                .as_str()
+                .unwrap()
+                .starts_with("-----BEGIN PUBLIC KEY-----")
+        );
+        assert!(actor["@context"].as_array().unwrap().contains(&"https://w3id.org/security/v1".into()));
+        assert!(!actor["@context"].as_array().unwrap().contains(&MULTIKEY_CONTEXT.into()));
+        assert!(actor.get("assertionMethod").is_none());
+    }
+
+    #[test]
+    fn keeps_retiring_keys_published_after_the_signing_key() {
+        let actor = served_actor(true);
+        let ids = actor["publicKey"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|key| key["id"].as_str().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(ids, ["https://blog.test/users/alice#key-2", "https://blog.test/users/alice#main-key"]);
+    }
+}
@@ -0,0 +1,100 @@
+//! The `Accept` of a follow by a local user, as it is delivered and served from its id.
+
+use crate::common::integrity;
+use crate::common::macros::json_format;
+use crate::traits::Env;
+use url::Url;
+
+/// Returns the id of the `Accept` of `follow_id` by `username`, dereferencing it serves the activity.
+pub fn accept_id<E>(state: &E, username: &str, follow_id: &str) -> String
+where
+    E: Env,
+{
+    let url = state.url();
+    Url::parse_with_params(&format!("{url}/users/{username}/accept_follow"), [("object", follow_id)])
+        .unwrap()
+        .to_string()
+}
+
+/// Builds the signed `Accept` of the follow `follow_id` sent by `follower` to `username`, returns its id and body.
+pub fn accept_activity<E>(state: &E, username: &str, follower: &str, follow_id: &str) -> (String, String)
+where
+    E: Env,
+{
+    let activity_id = accept_id(state, username, follow_id);
+    let id = serde_json::to_string(&activity_id).unwrap();
+    let accept_actor = serde_json::to_string(&format!("{}/users/{username}", state.url())).unwrap();
+    let follow_actor = serde_json::to_string(follower).unwrap();
+    let follow_id = serde_json::to_string(follow_id).unwrap();
+    let body = json_format! {
+        "@context": "https://www.w3.org/ns/activitystreams",
+        "id": id,
+        "type": "Accept",
+        "actor": accept_actor,
+        "object": {
+            "id": follow_id,
+            "type": "Follow",
+            "actor": follow_actor,
+            "object": accept_actor,
+        },
+    };
+    (activity_id, integrity::sign_activity(state, username, body))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::accept_activity;
+    use crate::traits::{Ed25519SigningKey, Env, RSASHA2SigningKey, UserSigningKey};
+    use chrono::{DateTime, Utc};
+    use rsa::pkcs8::DecodePrivateKey;
+    use std::fmt::Display;
+
+    const PRIVATE_KEY: &str = include_str!("../../../../test_config/private-key-for-test.pem");
+
+    struct TestState {
+        key: RSASHA2SigningKey,
+    }
+
+    impl Env for TestState {
+        fn url(&self) -> impl Display + Send + '_ {
+            "https://blog.test"
+        }
+        fn timestamp_now(&self) -> DateTime<Utc> {
+            DateTime::parse_from_rfc3339("2025-06-01T00:00:00Z").unwrap().to_utc()
+        }
+        fn signing_key(&self, _username: &str) -> UserSigningKey<'_> {
+            UserSigningKey {
+                id: "main-key",
+                key: &self.key,
+            }
+        }
+        fn integrity_key(&self, _username: &str) -> Option<Ed25519SigningKey> {
+            None
+        }
+    }
+
+    #[test]
+    fn accept_embeds_the_follow() {
+        let state = TestState {
+            key: RSASHA2SigningKey::from_pkcs8_pem(PRIVATE_KEY).unwrap(),
+        };
+        let (id, body) = accept_activity(&state, "alice", "https://social.test/users/bob", "https://social.test/follows/1");
+        let accept = serde_json::from_str::<serde_json::Value>(&body).unwrap();
+
+        assert_eq!(
+            id,
+            "https://blog.test/users/alice/accept_follow?object=https%3A%2F%2Fsocial.test%2Ffollows%2F1"
+        );
+        assert_eq!(accept["id"], id);
+        assert_eq!(accept["actor"], "https://blog.test/users/alice");
+        assert_eq!(
+            accept["object"],
+            serde_json::json!({
+                "id": "https://social.test/follows/1",
+                "type": "Follow",
+                "actor": "https://social.test/users/bob",
+                "object": "https://blog.test/users/alice",
+            })
+        );
+    }
+}
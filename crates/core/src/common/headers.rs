@@ -1,5 +1,6 @@
 use axum::http::HeaderMap;
-use axum::http::header::ACCEPT;
+use axum::http::header::{ACCEPT, RETRY_AFTER};
+use chrono::{DateTime, TimeDelta, Utc};
 use mime::{Mime, Name};
 
 pub const AP_ACCEPT: &str = r#"application/activity+json, application/ld+json; profile="https://www.w3.org/ns/activitystreams""#;
@@ -109,6 +110,16 @@ pub fn is_content_type_ap(ty: &Mime) -> bool {
     }
 }
 
+/// Reads `Retry-After` as either delay seconds or an HTTP date relative to `now`.
+pub fn retry_after(headers: &HeaderMap, now: DateTime<Utc>) -> Option<TimeDelta> {
+    let value = headers.get(RETRY_AFTER)?.to_str().ok()?.trim();
+    if let Ok(seconds) = value.parse::<u32>() {
+        return Some(TimeDelta::seconds(seconds.into()));
+    }
+    let date = DateTime::parse_from_rfc2822(value).ok()?;
+    Some((date.to_utc() - now).max(TimeDelta::zero()))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -169,4 +180,19 @@ mod tests {
         assert!(!is_content_type_ap(&"application/json".parse().unwrap()));
         assert!(!is_content_type_ap(&"text/html".parse().unwrap()));
     }
+
+    #[test]
+    fn test_retry_after() {
+        let now = DateTime::parse_from_rfc3339("2015-10-21T07:28:00Z").unwrap().to_utc();
+        let mut headers = HeaderMap::new();
+        assert_eq!(retry_after(&headers, now), None);
+        headers.insert(RETRY_AFTER, "120".parse().unwrap());
+        assert_eq!(retry_after(&headers, now), Some(TimeDelta::minutes(2)));
+        headers.insert(RETRY_AFTER, "Wed, 21 Oct 2015 07:30:00 GMT".parse().unwrap());
+        assert_eq!(retry_after(&headers, now), Some(TimeDelta::minutes(2)));
+        headers.insert(RETRY_AFTER, "Wed, 21 Oct 2015 07:00:00 GMT".parse().unwrap());
+        assert_eq!(retry_after(&headers, now), Some(TimeDelta::zero()));
+        headers.insert(RETRY_AFTER, "soon".parse().unwrap());
+        assert_eq!(retry_after(&headers, now), None);
+    }
 }
@@ -0,0 +1,421 @@
+//! Object Integrity Proofs (FEP-8b32) using the `eddsa-jcs-2022` cryptosuite.
+
+use crate::common::verify::{OneOrMany, fetch_document};
+use crate::traits::{Ed25519SigningKey, Env, HTTPClient};
+use chrono::{DateTime, SecondsFormat, Utc};
+use ring_compat::signature::ed25519::{Signature, VerifyingKey};
+use ring_compat::signature::{Signer, Verifier};
+use rsa::sha2::{Digest, Sha256};
+use serde::Deserialize;
+use serde_json::{Map, Value};
+
+pub const MULTIKEY_CONTEXT: &str = "https://w3id.org/security/multikey/v1";
+const ED25519_PUB_MULTICODEC: [u8; 2] = [0xed, 0x01];
+
+/// Derives a stable per-user Ed25519 key from a backend secret.
+pub fn derive_integrity_key(secret: &[u8], username: &str) -> Ed25519SigningKey {
+    let seed = Sha256::new()
+        .chain_update(b"fblog_system integrity key\0")
+        .chain_update(secret)
+        .chain_update(b"\0")
+        .chain_update(username.as_bytes())
+        .finalize();
+    Ed25519SigningKey::from_slice(&seed).unwrap()
+}
+
+pub fn encode_multikey(key: &VerifyingKey) -> String {
+    let mut bytes = ED25519_PUB_MULTICODEC.to_vec();
+    bytes.extend_from_slice(key.as_ref());
+    format!("z{}", bs58::encode(bytes).into_string())
+}
+
+fn decode_multikey(multibase: &str) -> Option<VerifyingKey> {
+    let bytes = bs58::decode(multibase.strip_prefix('z')?).into_vec().ok()?;
+    let key = bytes.strip_prefix(&ED25519_PUB_MULTICODEC)?;
+    VerifyingKey::from_slice(key).ok()
+}
+
+/// Builds the `assertionMethod` entry that advertises `key` on the actor document.
+pub fn assertion_method(actor_id: &str, key: &Ed25519SigningKey) -> Value {
+    serde_json::json!({
+        "id": format!("{actor_id}#ed25519-key"),
+        "type": "Multikey",
+        "controller": actor_id,
+        "publicKeyMultibase": encode_multikey(&key.verifying_key()),
+    })
+}
+
+/// Serializes `value` in the JSON Canonicalization Scheme (RFC 8785).
+fn canonicalize(value: &Value, out: &mut String) -> Result<(), serde_json::Error> {
+    match value {
+        Value::Array(items) => {
+            out.push('[');
+            for (i, item) in items.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                canonicalize(item, out)?;
+            }
+            out.push(']');
+        }
+        Value::Object(members) => {
+            // members are sorted by the UTF-16 code units of their names, which differs from UTF-8 order beyond the BMP
+            let mut members = members.iter().collect::<Vec<_>>();
+            members.sort_by(|(a, _), (b, _)| a.encode_utf16().cmp(b.encode_utf16()));
+            out.push('{');
+            for (i, (name, value)) in members.into_iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                out.push_str(&serde_json::to_string(name)?);
+                out.push(':');
+                canonicalize(value, out)?;
+            }
+            out.push('}');
+        }
+        // numbers are IEEE 754 doubles written as ECMAScript does
+        Value::Number(number) => match number.as_f64().filter(|number| number.is_finite()) {
+            Some(number) => out.push_str(ryu_js::Buffer::new().format_finite(number)),
+            None => return Err(serde::ser::Error::custom(format!("{number} is not an IEEE 754 double"))),
+        },
+        // serde_json escapes strings the way JCS does
+        Value::Null | Value::Bool(_) | Value::String(_) => out.push_str(&value.to_string()),
+    }
+    Ok(())
+}
+
+fn hash_data(proof_config: &Value, document: &Value) -> Result<Vec<u8>, serde_json::Error> {
+    let mut proof_config_jcs = String::new();
+    canonicalize(proof_config, &mut proof_config_jcs)?;
+    let mut document_jcs = String::new();
+    canonicalize(document, &mut document_jcs)?;
+    let mut hash = Sha256::digest(proof_config_jcs.as_bytes()).to_vec();
+    hash.extend_from_slice(&Sha256::digest(document_jcs.as_bytes()));
+    Ok(hash)
+}
+
+/// Adds a `DataIntegrityProof` signed by `key` to the JSON `document`.
+pub fn attach_proof(document: &str, key: &Ed25519SigningKey, verification_method: &str, created: DateTime<Utc>) -> Result<String, serde_json::Error> {
+    let mut document = serde_json::from_str::<Map<String, Value>>(document)?;
+    document.remove("proof");
+    let mut proof = Map::new();
+    proof.insert("type".to_owned(), "DataIntegrityProof".into());
+    proof.insert("cryptosuite".to_owned(), "eddsa-jcs-2022".into());
+    proof.insert("verificationMethod".to_owned(), verification_method.into());
+    proof.insert("proofPurpose".to_owned(), "assertionMethod".into());
+    proof.insert("created".to_owned(), created.to_rfc3339_opts(SecondsFormat::Secs, true).into());
+    if let Some(context) = document.get("@context") {
+        proof.insert("@context".to_owned(), context.clone());
+    }
+    let mut proof = Value::Object(proof);
+    let document = Value::Object(document);
+    let signature: Signature = key.sign(&hash_data(&proof, &document)?);
+    proof["proofValue"] = format!("z{}", bs58::encode(signature.to_bytes()).into_string()).into();
+    let Value::Object(mut document) = document else { unreachable!() };
+    document.insert("proof".to_owned(), proof);
+    serde_json::to_string(&document)
+}
+
+/// Signs an outgoing activity of `author` when the backend has an integrity key for them.
+pub fn sign_activity<E>(state: &E, author: &str, activity: String) -> String
+where
+    E: Env,
+{
+    let Some(key) = state.integrity_key(author) else {
+        return activity;
+    };
+    let verification_method = format!("{}/users/{author}#ed25519-key", state.url());
+    match attach_proof(&activity, &key, &verification_method, state.timestamp_now()) {
+        Ok(signed) => signed,
+        Err(e) => {
+            tracing::warn!(error = %e, "failed to attach integrity proof");
+            activity
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct Multikey {
+    id: String,
+    controller: String,
+    #[serde(rename = "publicKeyMultibase")]
+    public_key_multibase: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Controller {
+    id: String,
+    #[serde(rename = "assertionMethod")]
+    assertion_method: Option<OneOrMany<Value>>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum MethodDocument {
+    Key(Multikey),
+    Controller(Controller),
+}
+
+/// Resolves `verification_method` and returns the controller together with its key.
+async fn resolve_verification_method<E>(state: &E, verification_method: &str) -> Option<(String, VerifyingKey)>
+where
+    E: HTTPClient,
+{
+    let document_url = verification_method.split('#').next().unwrap_or(verification_method);
+    let controller = match fetch_document::<E, MethodDocument>(state, document_url).await? {
+        MethodDocument::Key(key) => {
+            if key.id != verification_method {
+                tracing::warn!(id = key.id, "verification method id mismatch");
+                return None;
+            }
+            let controller = fetch_document::<E, Controller>(state, &key.controller).await?;
+            if controller.id != key.controller {
+                tracing::warn!(controller = key.controller, actor = controller.id, "controller id mismatch");
+                return None;
+            }
+            controller
+        }
+        MethodDocument::Controller(controller) => {
+            // the controller has to be the document it was fetched from, or any host could publish keys for it
+            if controller.id.split('#').next() != Some(document_url) {
+                tracing::warn!(actor = controller.id, document_url, "controller id mismatch");
+                return None;
+            }
+            controller
+        }
+    };
+    let methods = match controller.assertion_method? {
+        OneOrMany::One(method) => vec![method],
+        OneOrMany::Many(methods) => methods,
+    };
+    let key = methods
+        .into_iter()
+        .filter_map(|method| serde_json::from_value::<Multikey>(method).ok())
+        .find(|method| method.id == verification_method)?;
+    if key.controller != controller.id {
+        tracing::warn!(
+            controller = key.controller,
+            actor = controller.id,
+            "verification method controller mismatch"
+        );
+        return None;
+    }
+    Some((controller.id, decode_multikey(&key.public_key_multibase)?))
+}
+
+/// Verifies an `eddsa-jcs-2022` proof on `document` and returns the actor that controls the signing key.
+#[tracing::instrument(skip(state, document))]
+pub async fn verify_proof<E>(state: &E, document: &str) -> Option<String>
+where
+    E: HTTPClient,
+{
+    let mut document = serde_json::from_str::<Map<String, Value>>(document).ok()?;
+    let Some(Value::Object(mut proof)) = document.remove("proof") else {
+        tracing::info!("document has no single proof object");
+        return None;
+    };
+    if proof.get("type").and_then(Value::as_str) != Some("DataIntegrityProof")
+        || proof.get("cryptosuite").and_then(Value::as_str) != Some("eddsa-jcs-2022")
+        || proof.get("proofPurpose").and_then(Value::as_str) != Some("assertionMethod")
+    {
+        tracing::info!(?proof, "unsupported proof");
+        return None;
+    }
+    let proof_value = proof.remove("proofValue")?;
+    let signature = bs58::decode(proof_value.as_str()?.strip_prefix('z')?).into_vec().ok()?;
+    let Ok(signature) = Signature::from_slice(&signature) else {
+        tracing::warn!("invalid proof value");
+        return None;
+    };
+    let verification_method = proof.get("verificationMethod")?.as_str()?.to_owned();
+    if let Some(proof_context) = proof.get("@context") {
+        let as_list = |context: &Value| match context {
+            Value::Array(list) => list.clone(),
+            context => vec![context.clone()],
+        };
+        if !document
+            .get("@context")
+            .map(as_list)
+            .unwrap_or_default()
+            .starts_with(&as_list(proof_context))
+        {
+            tracing::warn!("proof context does not match the document");
+            return None;
+        }
+        document.insert("@context".to_owned(), proof_context.clone());
+    }
+
+    let (controller, key) = resolve_verification_method(state, &verification_method).await?;
+    let hash = match hash_data(&Value::Object(proof), &Value::Object(document)) {
+        Ok(hash) => hash,
+        Err(e) => {
+            tracing::warn!(error = ?e, "document cannot be canonicalized");
+            return None;
+        }
+    };
+    if key.verify(&hash, &signature).is_err() {
+        tracing::warn!(verification_method, "integrity proof verification failed");
+        return None;
+    }
+    tracing::info!(controller, "integrity proof verified");
+    Some(controller)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{assertion_method, attach_proof, canonicalize, decode_multikey, derive_integrity_key, hash_data, verify_proof};
+    use crate::traits::HTTPClient;
+    use axum::body::Body;
+    use axum::http::{Request, Response};
+    use bytes::Bytes;
+    use chrono::DateTime;
+    use ring_compat::signature::Verifier;
+    use ring_compat::signature::ed25519::Signature;
+    use std::collections::HashMap;
+    use std::convert::Infallible;
+
+    const ACTOR: &str = "https://remote.test/users/alice";
+
+    struct TestState {
+        documents: HashMap<String, serde_json::Value>,
+    }
+
+    impl HTTPClient for TestState {
+        type Error = Infallible;
+        async fn request(&self, request: Request<Bytes>) -> Result<Response<Body>, Self::Error> {
+            match self.documents.get(&request.uri().to_string()) {
+                Some(document) => Ok(Response::new(Body::from(document.to_string()))),
+                None => Ok(Response::builder().status(404).body(Body::empty()).unwrap()),
+            }
+        }
+    }
+
+    fn like_activity() -> String {
+        serde_json::json!({
+            "@context": "https://www.w3.org/ns/activitystreams",
+            "id": "https://remote.test/activities/1",
+            "type": "Like",
+            "actor": ACTOR,
+            "object": "https://blog.test/articles/first-post",
+        })
+        .to_string()
+    }
+
+    fn jcs(json: &str) -> String {
+        let mut out = String::new();
+        canonicalize(&serde_json::from_str(json).unwrap(), &mut out).unwrap();
+        out
+    }
+
+    #[test]
+    fn canonical_form_matches_rfc_8785() {
+        // the examples of RFC 8785 section 3.2.2 and 3.2.3
+        let input = r#"{
+            "numbers": [333333333.33333329, 1E30, 4.50, 2e-3, 0.000000000000000000000000001],
+            "string": "\u20ac$\u000F\u000aA'\u0042\u0022\u005c\\\"\/",
+            "literals": [null, true, false]
+        }"#;
+        assert_eq!(
+            jcs(input),
+            r#"{"literals":[null,true,false],"numbers":[333333333.3333333,1e+30,4.5,0.002,1e-27],"string":"€$\u000f\nA'B\"\\\\\"/"}"#
+        );
+        let input = r#"{
+            "\u20ac": "Euro Sign",
+            "\r": "Carriage Return",
+            "\ufb33": "Hebrew Letter Dalet With Dagesh",
+            "1": "One",
+            "\ud83d\ude00": "Emoji: Grinning Face",
+            "\u0080": "Control",
+            "\u00f6": "Latin Small Letter O With Diaeresis"
+        }"#;
+        let names = jcs(input)
+            .split(',')
+            .map(|member| member.trim_start_matches('{').split_once(':').unwrap().1.trim_end_matches('}').to_owned())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            names,
+            [
+                r#""Carriage Return""#,
+                r#""One""#,
+                r#""Control""#,
+                r#""Latin Small Letter O With Diaeresis""#,
+                r#""Euro Sign""#,
+                r#""Emoji: Grinning Face""#,
+                r#""Hebrew Letter Dalet With Dagesh""#,
+            ]
+        );
+    }
+
+    #[test]
+    fn proof_matches_vc_di_eddsa_test_vector() {
+        // the eddsa-jcs-2022 test vector of W3C Data Integrity EdDSA Cryptosuites v1.0, the cryptosuite FEP-8b32 profiles
+        let context = serde_json::json!(["https://www.w3.org/ns/credentials/v2", "https://www.w3.org/ns/credentials/examples/v2"]);
+        let document = serde_json::json!({
+            "@context": context,
+            "id": "urn:uuid:58172aac-d8ba-11ed-83dd-0b3aef56cc33",
+            "type": ["VerifiableCredential", "AlumniCredential"],
+            "name": "Alumni Credential",
+            "description": "A minimum viable example of an Alumni Credential.",
+            "issuer": "https://vc.example/issuers/5678",
+            "validFrom": "2023-01-01T00:00:00Z",
+            "credentialSubject": {
+                "id": "did:example:abcdefgh",
+                "alumniOf": "The School of Examples",
+            },
+        });
+        let proof = serde_json::json!({
+            "@context": context,
+            "type": "DataIntegrityProof",
+            "cryptosuite": "eddsa-jcs-2022",
+            "created": "2023-02-24T23:36:38Z",
+            "verificationMethod": "did:key:z6MkrJVnaZkeFzdQyMZu1cgjg7k1pZZ6pvBQ7XJPt4swbTQ2#z6MkrJVnaZkeFzdQyMZu1cgjg7k1pZZ6pvBQ7XJPt4swbTQ2",
+            "proofPurpose": "assertionMethod",
+        });
+        let proof_value = "z2HnFSSPPBzR36zdDgK8PbEHeXbR56YF24jwMpt3R1eHXQzJDMWS93FCzpvJpwTWd3GAVFuUfjoJdcnTMuVor51aX";
+        let key = decode_multikey("z6MkrJVnaZkeFzdQyMZu1cgjg7k1pZZ6pvBQ7XJPt4swbTQ2").unwrap();
+        let signature = Signature::from_slice(&bs58::decode(&proof_value[1..]).into_vec().unwrap()).unwrap();
+
+        assert!(key.verify(&hash_data(&proof, &document).unwrap(), &signature).is_ok());
+    }
+
+    #[test]
+    fn attached_proof_is_verified_and_tampering_is_detected() {
+        let key = derive_integrity_key(b"secret", "alice");
+        let state = TestState {
+            documents: HashMap::from([(
+                ACTOR.to_owned(),
+                serde_json::json!({ "id": ACTOR, "type": "Person", "assertionMethod": [assertion_method(ACTOR, &key)] }),
+            )]),
+        };
+        let activity = like_activity();
+        let created = DateTime::parse_from_rfc3339("2025-06-01T00:00:00Z").unwrap().to_utc();
+        let signed = attach_proof(&activity, &key, &format!("{ACTOR}#ed25519-key"), created).unwrap();
+
+        assert_eq!(futures::executor::block_on(verify_proof(&state, &signed)).as_deref(), Some(ACTOR));
+
+        let tampered = signed.replace("first-post", "second-post");
+        assert_eq!(futures::executor::block_on(verify_proof(&state, &tampered)), None);
+
+        let other_key = derive_integrity_key(b"secret", "mallory");
+        let forged = attach_proof(&activity, &other_key, &format!("{ACTOR}#ed25519-key"), created).unwrap();
+        assert_eq!(futures::executor::block_on(verify_proof(&state, &forged)), None);
+    }
+
+    #[test]
+    fn controller_hosted_elsewhere_cannot_prove() {
+        const DOCUMENT: &str = "https://evil.test/x";
+        let key = derive_integrity_key(b"secret", "mallory");
+        let mut method = assertion_method(ACTOR, &key);
+        method["id"] = format!("{DOCUMENT}#ed25519-key").into();
+        let state = TestState {
+            documents: HashMap::from([(
+                DOCUMENT.to_owned(),
+                serde_json::json!({ "id": ACTOR, "type": "Person", "assertionMethod": [method] }),
+            )]),
+        };
+        let created = DateTime::parse_from_rfc3339("2025-06-01T00:00:00Z").unwrap().to_utc();
+        let forged = attach_proof(&like_activity(), &key, &format!("{DOCUMENT}#ed25519-key"), created).unwrap();
+
+        assert_eq!(futures::executor::block_on(verify_proof(&state, &forged)), None);
+    }
+}
@@ -0,0 +1,1011 @@
+//! The part of JSON-LD needed to check Linked Data Signatures on ActivityPub documents:
+//! expansion against preloaded contexts, conversion to RDF and URDNA2015 canonicalization.
+
+use rsa::sha2::{Digest, Sha256};
+use serde_json::{Map, Value};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap};
+
+const RDF_TYPE: &str = "http://www.w3.org/1999/02/22-rdf-syntax-ns#type";
+const RDF_FIRST: &str = "http://www.w3.org/1999/02/22-rdf-syntax-ns#first";
+const RDF_REST: &str = "http://www.w3.org/1999/02/22-rdf-syntax-ns#rest";
+const RDF_NIL: &str = "http://www.w3.org/1999/02/22-rdf-syntax-ns#nil";
+const RDF_LANG_STRING: &str = "http://www.w3.org/1999/02/22-rdf-syntax-ns#langString";
+const XSD_STRING: &str = "http://www.w3.org/2001/XMLSchema#string";
+const XSD_BOOLEAN: &str = "http://www.w3.org/2001/XMLSchema#boolean";
+const XSD_INTEGER: &str = "http://www.w3.org/2001/XMLSchema#integer";
+const XSD_DOUBLE: &str = "http://www.w3.org/2001/XMLSchema#double";
+
+/// Upper bound of blank nodes sharing a first degree hash, their permutations are tried exhaustively.
+const MAX_EQUIVALENT_BLANK_NODES: usize = 6;
+/// Upper bound of N-degree hash computations for one document.
+const MAX_N_DEGREE_HASHES: usize = 4096;
+
+#[derive(Debug, thiserror::Error)]
+pub enum JsonLdError {
+    #[error("context {0} is not preloaded")]
+    UnknownContext(String),
+    #[error("invalid JSON-LD: {0}")]
+    Invalid(&'static str),
+    #[error("unsupported JSON-LD feature: {0}")]
+    Unsupported(&'static str),
+    #[error("too many indistinguishable blank nodes")]
+    TooComplex,
+}
+
+/// Remote contexts are never fetched, only the ones ActivityPub implementations sign with are known.
+fn preloaded_context(url: &str) -> Option<&'static str> {
+    match url {
+        "https://www.w3.org/ns/activitystreams" | "http://www.w3.org/ns/activitystreams" => Some(include_str!("jsonld/activitystreams.jsonld")),
+        "https://w3id.org/security/v1" => Some(include_str!("jsonld/security-v1.jsonld")),
+        "https://w3id.org/identity/v1" => Some(include_str!("jsonld/identity-v1.jsonld")),
+        _ => None,
+    }
+}
+
+/// Returns the canonical N-Quads of `document`.
+pub fn canonicalize(document: &Value) -> Result<String, JsonLdError> {
+    let expanded = expand(&Context::default(), None, document)?;
+    let mut builder = RdfBuilder::default();
+    for node in as_array(expanded) {
+        if let Value::Object(node) = node {
+            builder.node(&node)?;
+        }
+    }
+    canonical_nquads(builder.quads)
+}
+
+/// Returns the properties of `document` that expansion drops, such as terms none of its contexts define.
+/// Signatures over the canonical form do not cover them.
+pub fn unsigned_properties(document: &Value) -> Result<Vec<String>, JsonLdError> {
+    let mut unsigned = Vec::new();
+    collect_unsigned_properties(&Context::default(), "", document, &mut unsigned)?;
+    Ok(unsigned)
+}
+
+fn collect_unsigned_properties(context: &Context, path: &str, element: &Value, unsigned: &mut Vec<String>) -> Result<(), JsonLdError> {
+    let element = match element {
+        Value::Array(items) => {
+            for item in items {
+                collect_unsigned_properties(context, path, item, unsigned)?;
+            }
+            return Ok(());
+        }
+        Value::Object(element) => element,
+        _ => return Ok(()),
+    };
+    let context = match element.get("@context") {
+        Some(local) => Cow::Owned(process_context(context, local, &mut Vec::new())?),
+        None => Cow::Borrowed(context),
+    };
+    let context = context.as_ref();
+    for (key, value) in element {
+        if key == "@context" {
+            continue;
+        }
+        let property = if path.is_empty() { key.clone() } else { format!("{path}.{key}") };
+        match expand_vocab_iri(context, key) {
+            Some(expanded) if is_keyword(&expanded) => {}
+            Some(expanded) if is_absolute_iri(&expanded) => {
+                if context.term(key).and_then(|definition| definition.container.as_deref()) != Some("@language") {
+                    collect_unsigned_properties(context, &property, value, unsigned)?;
+                }
+            }
+            _ => unsigned.push(property),
+        }
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone, Default)]
+struct Context {
+    vocab: Option<String>,
+    language: Option<String>,
+    terms: HashMap<String, Option<TermDefinition>>,
+}
+
+#[derive(Debug, Clone)]
+struct TermDefinition {
+    id: String,
+    ty: Option<String>,
+    container: Option<String>,
+    language: Option<Option<String>>,
+}
+
+impl Context {
+    fn term(&self, term: &str) -> Option<&TermDefinition> {
+        self.terms.get(term).and_then(Option::as_ref)
+    }
+}
+
+fn is_keyword(value: &str) -> bool {
+    matches!(
+        value,
+        "@context" | "@id" | "@type" | "@value" | "@language" | "@index" | "@list" | "@set" | "@graph" | "@reverse" | "@vocab" | "@base"
+    )
+}
+
+fn is_absolute_iri(value: &str) -> bool {
+    value.split_once(':').is_some_and(|(scheme, _)| {
+        scheme.starts_with(|c: char| c.is_ascii_alphabetic()) && scheme.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '+' | '-' | '.'))
+    })
+}
+
+fn as_array(value: Value) -> Vec<Value> {
+    match value {
+        Value::Array(values) => values,
+        Value::Null => Vec::new(),
+        value => vec![value],
+    }
+}
+
+fn process_context(active: &Context, local: &Value, loading: &mut Vec<String>) -> Result<Context, JsonLdError> {
+    let mut result = active.clone();
+    let contexts = match local {
+        Value::Array(contexts) => contexts.as_slice(),
+        context => std::slice::from_ref(context),
+    };
+    for context in contexts {
+        match context {
+            Value::Null => result = Context::default(),
+            Value::String(url) => {
+                if loading.contains(url) {
+                    return Err(JsonLdError::Invalid("recursive context inclusion"));
+                }
+                let document = preloaded_context(url).ok_or_else(|| JsonLdError::UnknownContext(url.clone()))?;
+                let document = serde_json::from_str::<Value>(document).expect("preloaded contexts are valid JSON");
+                loading.push(url.clone());
+                result = process_context(&result, &document["@context"], loading)?;
+                loading.pop();
+            }
+            Value::Object(definitions) => {
+                if definitions.contains_key("@base") {
+                    return Err(JsonLdError::Unsupported("@base"));
+                }
+                match definitions.get("@vocab") {
+                    None => {}
+                    Some(Value::Null) => result.vocab = None,
+                    Some(Value::String(vocab)) if is_absolute_iri(vocab) || vocab.starts_with("_:") => result.vocab = Some(vocab.clone()),
+                    Some(_) => return Err(JsonLdError::Invalid("invalid vocab mapping")),
+                }
+                match definitions.get("@language") {
+                    None => {}
+                    Some(Value::Null) => result.language = None,
+                    Some(Value::String(language)) => result.language = Some(language.to_lowercase()),
+                    Some(_) => return Err(JsonLdError::Invalid("invalid default language")),
+                }
+                let mut defined = HashMap::new();
+                for term in definitions.keys() {
+                    create_term_definition(&mut result, definitions, term, &mut defined)?;
+                }
+            }
+            _ => return Err(JsonLdError::Invalid("invalid local context")),
+        }
+    }
+    Ok(result)
+}
+
+fn create_term_definition(
+    active: &mut Context,
+    local: &Map<String, Value>,
+    term: &str,
+    defined: &mut HashMap<String, bool>,
+) -> Result<(), JsonLdError> {
+    match defined.get(term) {
+        Some(true) => return Ok(()),
+        Some(false) => return Err(JsonLdError::Invalid("cyclic IRI mapping")),
+        None => {}
+    }
+    if term.starts_with('@') {
+        return Ok(());
+    }
+    defined.insert(term.to_owned(), false);
+    let definition = match &local[term] {
+        Value::Null => None,
+        Value::String(id) => {
+            let id = expand_iri_for_definition(active, local, id, defined)?;
+            id.map(|id| TermDefinition {
+                id,
+                ty: None,
+                container: None,
+                language: None,
+            })
+        }
+        Value::Object(definition) => {
+            if definition.contains_key("@reverse") {
+                return Err(JsonLdError::Unsupported("@reverse"));
+            }
+            let id = match definition.get("@id") {
+                Some(Value::String(id)) => expand_iri_for_definition(active, local, id, defined)?,
+                Some(Value::Null) => None,
+                Some(_) => return Err(JsonLdError::Invalid("invalid IRI mapping")),
+                None => match term.split_once(':') {
+                    Some((prefix, suffix)) => {
+                        if local.contains_key(prefix) {
+                            create_term_definition(active, local, prefix, defined)?;
+                        }
+                        match active.term(prefix) {
+                            Some(prefix) => Some(format!("{}{suffix}", prefix.id)),
+                            None => Some(term.to_owned()),
+                        }
+                    }
+                    None => match &active.vocab {
+                        Some(vocab) => Some(format!("{vocab}{term}")),
+                        None => return Err(JsonLdError::Invalid("term without IRI mapping")),
+                    },
+                },
+            };
+            let ty = match definition.get("@type") {
+                None => None,
+                Some(Value::String(ty)) => match expand_iri_for_definition(active, local, ty, defined)? {
+                    Some(ty) if ty == "@id" || ty == "@vocab" || is_absolute_iri(&ty) => Some(ty),
+                    _ => return Err(JsonLdError::Invalid("invalid type mapping")),
+                },
+                Some(_) => return Err(JsonLdError::Invalid("invalid type mapping")),
+            };
+            let container = match definition.get("@container") {
+                None => None,
+                Some(Value::String(container)) if matches!(container.as_str(), "@list" | "@set" | "@index" | "@language") => Some(container.clone()),
+                Some(_) => return Err(JsonLdError::Invalid("invalid container mapping")),
+            };
+            let language = match definition.get("@language") {
+                None => None,
+                Some(Value::Null) => Some(None),
+                Some(Value::String(language)) => Some(Some(language.to_lowercase())),
+                Some(_) => return Err(JsonLdError::Invalid("invalid language mapping")),
+            };
+            id.map(|id| TermDefinition { id, ty, container, language })
+        }
+        _ => return Err(JsonLdError::Invalid("invalid term definition")),
+    };
+    if definition
+        .as_ref()
+        .is_some_and(|definition| !is_keyword(&definition.id) && !definition.id.contains(':'))
+    {
+        return Err(JsonLdError::Invalid("invalid IRI mapping"));
+    }
+    active.terms.insert(term.to_owned(), definition);
+    defined.insert(term.to_owned(), true);
+    Ok(())
+}
+
+fn expand_iri_for_definition(
+    active: &mut Context,
+    local: &Map<String, Value>,
+    value: &str,
+    defined: &mut HashMap<String, bool>,
+) -> Result<Option<String>, JsonLdError> {
+    if local.contains_key(value) {
+        create_term_definition(active, local, value, defined)?;
+    }
+    if let Some((prefix, _)) = value.split_once(':')
+        && local.contains_key(prefix)
+    {
+        create_term_definition(active, local, prefix, defined)?;
+    }
+    Ok(expand_vocab_iri(active, value))
+}
+
+/// Expands a document relative IRI, relative IRIs are kept as they are since documents have no base.
+fn expand_iri(context: &Context, value: &str) -> String {
+    if let Some((prefix, suffix)) = value.split_once(':')
+        && prefix != "_"
+        && !suffix.starts_with("//")
+        && let Some(prefix) = context.term(prefix)
+    {
+        return format!("{}{suffix}", prefix.id);
+    }
+    value.to_owned()
+}
+
+/// Expands a vocabulary relative IRI, `None` when the term is explicitly mapped to null.
+fn expand_vocab_iri(context: &Context, value: &str) -> Option<String> {
+    if is_keyword(value) {
+        return Some(value.to_owned());
+    }
+    if let Some(definition) = context.terms.get(value) {
+        return definition.as_ref().map(|definition| definition.id.clone());
+    }
+    if value.contains(':') {
+        return Some(expand_iri(context, value));
+    }
+    match &context.vocab {
+        Some(vocab) => Some(format!("{vocab}{value}")),
+        None => Some(value.to_owned()),
+    }
+}
+
+fn is_list(value: &Value) -> bool {
+    value.as_object().is_some_and(|value| value.contains_key("@list"))
+}
+
+fn expand(context: &Context, property: Option<&str>, element: &Value) -> Result<Value, JsonLdError> {
+    match element {
+        Value::Null => Ok(Value::Null),
+        Value::Array(items) => {
+            let list_container = property
+                .and_then(|property| context.term(property))
+                .and_then(|term| term.container.as_deref())
+                == Some("@list");
+            let mut result = Vec::new();
+            for item in items {
+                let expanded = expand(context, property, item)?;
+                if list_container && (expanded.is_array() || is_list(&expanded)) {
+                    return Err(JsonLdError::Invalid("list of lists"));
+                }
+                result.extend(as_array(expanded));
+            }
+            Ok(Value::Array(result))
+        }
+        Value::Object(element) => expand_object(context, property, element),
+        scalar => match property {
+            None | Some("@graph") => Ok(Value::Null),
+            Some(property) => Ok(expand_value(context, property, scalar)),
+        },
+    }
+}
+
+fn expand_value(context: &Context, property: &str, value: &Value) -> Value {
+    let definition = context.term(property);
+    let mut result = Map::new();
+    match (definition.and_then(|definition| definition.ty.as_deref()), value) {
+        (Some("@id"), Value::String(id)) => {
+            result.insert("@id".to_owned(), expand_iri(context, id).into());
+        }
+        (Some("@vocab"), Value::String(id)) => {
+            result.insert("@id".to_owned(), expand_vocab_iri(context, id).into());
+        }
+        (Some(ty), _) if ty != "@id" && ty != "@vocab" => {
+            result.insert("@value".to_owned(), value.clone());
+            result.insert("@type".to_owned(), ty.into());
+        }
+        (_, Value::String(_)) => {
+            result.insert("@value".to_owned(), value.clone());
+            let language = match definition.and_then(|definition| definition.language.as_ref()) {
+                Some(language) => language.as_ref(),
+                None => context.language.as_ref(),
+            };
+            if let Some(language) = language {
+                result.insert("@language".to_owned(), language.as_str().into());
+            }
+        }
+        _ => {
+            result.insert("@value".to_owned(), value.clone());
+        }
+    }
+    Value::Object(result)
+}
+
+fn expand_object(context: &Context, property: Option<&str>, element: &Map<String, Value>) -> Result<Value, JsonLdError> {
+    let context = match element.get("@context") {
+        Some(local) => Cow::Owned(process_context(context, local, &mut Vec::new())?),
+        None => Cow::Borrowed(context),
+    };
+    let context = context.as_ref();
+    let top_level = matches!(property, None | Some("@graph"));
+    let mut result = Map::new();
+    for (key, value) in element {
+        if key == "@context" {
+            continue;
+        }
+        let Some(expanded_property) = expand_vocab_iri(context, key) else {
+            continue;
+        };
+        if is_keyword(&expanded_property) {
+            if result.contains_key(&expanded_property) {
+                return Err(JsonLdError::Invalid("colliding keywords"));
+            }
+            let expanded = match expanded_property.as_str() {
+                "@id" => match value {
+                    Value::String(id) => expand_iri(context, id).into(),
+                    _ => return Err(JsonLdError::Invalid("invalid @id value")),
+                },
+                "@type" => match value {
+                    Value::String(ty) => expand_vocab_iri(context, ty).into(),
+                    Value::Array(types) => types
+                        .iter()
+                        .map(|ty| ty.as_str().map(|ty| expand_vocab_iri(context, ty).into()))
+                        .collect::<Option<Vec<Value>>>()
+                        .ok_or(JsonLdError::Invalid("invalid type value"))?
+                        .into(),
+                    _ => return Err(JsonLdError::Invalid("invalid type value")),
+                },
+                "@value" => match value {
+                    Value::Array(_) | Value::Object(_) => return Err(JsonLdError::Invalid("invalid value object value")),
+                    value => value.clone(),
+                },
+                "@language" => match value {
+                    Value::String(language) => language.to_lowercase().into(),
+                    _ => return Err(JsonLdError::Invalid("invalid language-tagged string")),
+                },
+                "@index" => match value {
+                    Value::String(_) => value.clone(),
+                    _ => return Err(JsonLdError::Invalid("invalid @index value")),
+                },
+                "@list" => {
+                    if top_level {
+                        continue;
+                    }
+                    let expanded = expand(context, property, value)?;
+                    if as_array(expanded.clone()).iter().any(is_list) {
+                        return Err(JsonLdError::Invalid("list of lists"));
+                    }
+                    Value::Array(as_array(expanded))
+                }
+                "@set" => expand(context, property, value)?,
+                "@graph" => return Err(JsonLdError::Unsupported("@graph")),
+                "@reverse" => return Err(JsonLdError::Unsupported("@reverse")),
+                _ => continue,
+            };
+            result.insert(expanded_property, expanded);
+            continue;
+        }
+        if !expanded_property.contains(':') {
+            continue;
+        }
+        let container = context.term(key).and_then(|definition| definition.container.as_deref());
+        let mut expanded = match (container, value) {
+            (Some("@language"), Value::Object(languages)) => {
+                let mut values = Vec::new();
+                for (language, items) in languages {
+                    for item in as_array(items.clone()) {
+                        let Value::String(item) = item else {
+                            return Err(JsonLdError::Invalid("invalid language map value"));
+                        };
+                        let mut value = Map::new();
+                        value.insert("@value".to_owned(), item.into());
+                        value.insert("@language".to_owned(), language.to_lowercase().into());
+                        values.push(Value::Object(value));
+                    }
+                }
+                Value::Array(values)
+            }
+            (Some("@index"), Value::Object(_)) => return Err(JsonLdError::Unsupported("index map")),
+            _ => expand(context, Some(key), value)?,
+        };
+        if expanded.is_null() {
+            continue;
+        }
+        if container == Some("@list") && !is_list(&expanded) {
+            let mut list = Map::new();
+            list.insert("@list".to_owned(), Value::Array(as_array(expanded)));
+            expanded = Value::Object(list);
+        }
+        if let Value::Array(values) = result.entry(expanded_property).or_insert_with(|| Value::Array(Vec::new())) {
+            values.extend(as_array(expanded));
+        }
+    }
+
+    if let Some(value) = result.get("@value") {
+        if result
+            .keys()
+            .any(|key| !matches!(key.as_str(), "@value" | "@language" | "@type" | "@index"))
+            || (result.contains_key("@language") && result.contains_key("@type"))
+        {
+            return Err(JsonLdError::Invalid("invalid value object"));
+        }
+        if value.is_null() {
+            return Ok(Value::Null);
+        }
+        if result.contains_key("@language") && !value.is_string() {
+            return Err(JsonLdError::Invalid("invalid language-tagged value"));
+        }
+        if result.get("@type").is_some_and(|ty| !ty.is_string()) {
+            return Err(JsonLdError::Invalid("invalid typed value"));
+        }
+    } else if let Some(ty) = result.get_mut("@type") {
+        if !ty.is_array() {
+            *ty = Value::Array(vec![ty.take()]);
+        }
+    } else if result.contains_key("@set") || result.contains_key("@list") {
+        if result.len() > 1 + usize::from(result.contains_key("@index")) {
+            return Err(JsonLdError::Invalid("invalid set or list object"));
+        }
+        if let Some(set) = result.remove("@set") {
+            return Ok(set);
+        }
+    }
+    if result.len() == 1 && result.contains_key("@language") {
+        return Ok(Value::Null);
+    }
+    if top_level
+        && (result.is_empty() || result.contains_key("@value") || result.contains_key("@list") || (result.len() == 1 && result.contains_key("@id")))
+    {
+        return Ok(Value::Null);
+    }
+    Ok(Value::Object(result))
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+enum Term {
+    Iri(String),
+    Blank(String),
+    Literal {
+        value: String,
+        datatype: String,
+        language: Option<String>,
+    },
+}
+
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct Quad {
+    subject: Term,
+    predicate: String,
+    object: Term,
+}
+
+#[derive(Default)]
+struct RdfBuilder {
+    quads: Vec<Quad>,
+    blank_nodes: HashMap<String, String>,
+    blank_node_count: usize,
+}
+
+impl RdfBuilder {
+    fn fresh_blank_node(&mut self) -> Term {
+        Term::Blank(self.fresh_blank_label())
+    }
+
+    fn fresh_blank_label(&mut self) -> String {
+        self.blank_node_count += 1;
+        format!("_:b{}", self.blank_node_count)
+    }
+
+    fn resource(&mut self, id: &str) -> Option<Term> {
+        if id.starts_with("_:") {
+            let label = match self.blank_nodes.get(id) {
+                Some(label) => label.clone(),
+                None => {
+                    let label = self.fresh_blank_label();
+                    self.blank_nodes.insert(id.to_owned(), label.clone());
+                    label
+                }
+            };
+            return Some(Term::Blank(label));
+        }
+        is_absolute_iri(id).then(|| Term::Iri(id.to_owned()))
+    }
+
+    fn push(&mut self, subject: Option<&Term>, predicate: &str, object: Option<Term>) {
+        if let (Some(subject), Some(object)) = (subject, object)
+            && is_absolute_iri(predicate)
+        {
+            self.quads.push(Quad {
+                subject: subject.clone(),
+                predicate: predicate.to_owned(),
+                object,
+            });
+        }
+    }
+
+    fn node(&mut self, node: &Map<String, Value>) -> Result<Option<Term>, JsonLdError> {
+        let subject = match node.get("@id").and_then(Value::as_str) {
+            Some(id) => self.resource(id),
+            None => Some(self.fresh_blank_node()),
+        };
+        for ty in node.get("@type").and_then(Value::as_array).into_iter().flatten() {
+            let object = ty.as_str().and_then(|ty| self.resource(ty));
+            self.push(subject.as_ref(), RDF_TYPE, object);
+        }
+        for (property, values) in node {
+            if is_keyword(property) {
+                continue;
+            }
+            for value in values.as_array().into_iter().flatten() {
+                let object = self.object(value)?;
+                self.push(subject.as_ref(), property, object);
+            }
+        }
+        Ok(subject)
+    }
+
+    fn object(&mut self, value: &Value) -> Result<Option<Term>, JsonLdError> {
+        let Value::Object(value) = value else {
+            return Err(JsonLdError::Invalid("expanded value is not an object"));
+        };
+        if value.contains_key("@value") {
+            return Ok(Some(literal(value)));
+        }
+        if let Some(items) = value.get("@list") {
+            return self.list(items.as_array().map(Vec::as_slice).unwrap_or_default()).map(Some);
+        }
+        self.node(value)
+    }
+
+    fn list(&mut self, items: &[Value]) -> Result<Term, JsonLdError> {
+        let Some((first, rest)) = items.split_first() else {
+            return Ok(Term::Iri(RDF_NIL.to_owned()));
+        };
+        let head = self.fresh_blank_node();
+        let object = self.object(first)?;
+        self.push(Some(&head), RDF_FIRST, object);
+        let rest = self.list(rest)?;
+        self.push(Some(&head), RDF_REST, Some(rest));
+        Ok(head)
+    }
+}
+
+fn literal(value: &Map<String, Value>) -> Term {
+    let datatype = value.get("@type").and_then(Value::as_str);
+    let language = value.get("@language").and_then(Value::as_str).map(str::to_owned);
+    let (value, default_datatype) = match &value["@value"] {
+        Value::Bool(value) => (value.to_string(), XSD_BOOLEAN),
+        Value::Number(number) => {
+            let double = number.as_f64().unwrap_or_default();
+            if number.is_f64() && (double.fract() != 0.0 || double.abs() >= 1e21) || datatype == Some(XSD_DOUBLE) {
+                (canonical_double(double), XSD_DOUBLE)
+            } else if number.is_f64() {
+                (format!("{double:.0}"), XSD_INTEGER)
+            } else {
+                (number.to_string(), XSD_INTEGER)
+            }
+        }
+        Value::String(value) => (value.clone(), XSD_STRING),
+        _ => (String::new(), XSD_STRING),
+    };
+    let datatype = match &language {
+        Some(_) => RDF_LANG_STRING,
+        None => datatype.unwrap_or(default_datatype),
+    };
+    Term::Literal {
+        value,
+        datatype: datatype.to_owned(),
+        language,
+    }
+}
+
+fn canonical_double(value: f64) -> String {
+    let formatted = format!("{value:.15E}");
+    let (mantissa, exponent) = formatted.split_once('E').unwrap_or((&formatted, "0"));
+    let mantissa = mantissa.trim_end_matches('0');
+    let zero = if mantissa.ends_with('.') { "0" } else { "" };
+    format!("{mantissa}{zero}E{exponent}")
+}
+
+fn write_term(line: &mut String, term: &Term, label: &impl Fn(&str) -> String) {
+    match term {
+        Term::Iri(iri) => {
+            line.push('<');
+            line.push_str(iri);
+            line.push('>');
+        }
+        Term::Blank(id) => line.push_str(&label(id)),
+        Term::Literal { value, datatype, language } => {
+            line.push('"');
+            for c in value.chars() {
+                match c {
+                    '\u{8}' => line.push_str("\\b"),
+                    '\t' => line.push_str("\\t"),
+                    '\n' => line.push_str("\\n"),
+                    '\u{c}' => line.push_str("\\f"),
+                    '\r' => line.push_str("\\r"),
+                    '"' => line.push_str("\\\""),
+                    '\\' => line.push_str("\\\\"),
+                    '\0'..='\u{1f}' | '\u{7f}' => line.push_str(&format!("\\u{:04X}", c as u32)),
+                    c => line.push(c),
+                }
+            }
+            line.push('"');
+            match language {
+                Some(language) => {
+                    line.push('@');
+                    line.push_str(language);
+                }
+                None if datatype != XSD_STRING => {
+                    line.push_str("^^<");
+                    line.push_str(datatype);
+                    line.push('>');
+                }
+                None => {}
+            }
+        }
+    }
+}
+
+fn nquad(quad: &Quad, label: impl Fn(&str) -> String) -> String {
+    let mut line = String::new();
+    write_term(&mut line, &quad.subject, &label);
+    line.push_str(" <");
+    line.push_str(&quad.predicate);
+    line.push_str("> ");
+    write_term(&mut line, &quad.object, &label);
+    line.push_str(" .\n");
+    line
+}
+
+fn sha256_hex(data: &str) -> String {
+    format!("{:x}", Sha256::digest(data.as_bytes()))
+}
+
+#[derive(Debug, Clone)]
+struct IdentifierIssuer {
+    prefix: &'static str,
+    issued: Vec<String>,
+    identifiers: HashMap<String, String>,
+}
+
+impl IdentifierIssuer {
+    fn new(prefix: &'static str) -> Self {
+        IdentifierIssuer {
+            prefix,
+            issued: Vec::new(),
+            identifiers: HashMap::new(),
+        }
+    }
+
+    fn get(&self, id: &str) -> Option<&String> {
+        self.identifiers.get(id)
+    }
+
+    fn issue(&mut self, id: &str) -> String {
+        if let Some(issued) = self.identifiers.get(id) {
+            return issued.clone();
+        }
+        let issued = format!("{}{}", self.prefix, self.issued.len());
+        self.issued.push(id.to_owned());
+        self.identifiers.insert(id.to_owned(), issued.clone());
+        issued
+    }
+}
+
+fn permutations<'a>(items: &[&'a str]) -> Vec<Vec<&'a str>> {
+    if items.len() <= 1 {
+        return vec![items.to_vec()];
+    }
+    let mut result = Vec::new();
+    for (i, item) in items.iter().enumerate() {
+        let mut rest = items.to_vec();
+        rest.remove(i);
+        for mut permutation in permutations(&rest) {
+            permutation.insert(0, item);
+            result.push(permutation);
+        }
+    }
+    result
+}
+
+/// URDNA2015, <https://www.w3.org/TR/rdf-canon/>
+struct Canonicalizer<'a> {
+    blank_node_quads: HashMap<&'a str, Vec<&'a Quad>>,
+    canonical: IdentifierIssuer,
+    remaining_hashes: usize,
+}
+
+impl<'a> Canonicalizer<'a> {
+    fn hash_first_degree(&self, id: &str) -> String {
+        let mut lines = self.blank_node_quads[id]
+            .iter()
+            .map(|quad| nquad(quad, |blank| if blank == id { "_:a" } else { "_:z" }.to_owned()))
+            .collect::<Vec<_>>();
+        lines.sort();
+        sha256_hex(&lines.concat())
+    }
+
+    fn hash_related(&self, related: &str, quad: &Quad, issuer: &IdentifierIssuer, position: char) -> String {
+        let identifier = match self.canonical.get(related).or_else(|| issuer.get(related)) {
+            Some(identifier) => identifier.clone(),
+            None => self.hash_first_degree(related),
+        };
+        sha256_hex(&format!("{position}<{}>{identifier}", quad.predicate))
+    }
+
+    fn hash_n_degree(&mut self, id: &'a str, mut issuer: IdentifierIssuer) -> Result<(String, IdentifierIssuer), JsonLdError> {
+        self.remaining_hashes = self.remaining_hashes.checked_sub(1).ok_or(JsonLdError::TooComplex)?;
+        let mut related_blank_nodes = BTreeMap::<String, Vec<&'a str>>::new();
+        for quad in &self.blank_node_quads[id] {
+            for (term, position) in [(&quad.subject, 's'), (&quad.object, 'o')] {
+                if let Term::Blank(related) = term
+                    && related != id
+                {
+                    let hash = self.hash_related(related, quad, &issuer, position);
+                    related_blank_nodes.entry(hash).or_default().push(related);
+                }
+            }
+        }
+        let mut data_to_hash = String::new();
+        for (related_hash, blank_nodes) in related_blank_nodes {
+            if blank_nodes.len() > MAX_EQUIVALENT_BLANK_NODES {
+                return Err(JsonLdError::TooComplex);
+            }
+            data_to_hash.push_str(&related_hash);
+            let mut chosen: Option<(String, IdentifierIssuer)> = None;
+            let is_worse = |path: &str, chosen: &Option<(String, IdentifierIssuer)>| {
+                chosen
+                    .as_ref()
+                    .is_some_and(|(chosen_path, _)| path.len() >= chosen_path.len() && path > chosen_path.as_str())
+            };
+            'permutation: for permutation in permutations(&blank_nodes) {
+                let mut issuer_copy = issuer.clone();
+                let mut path = String::new();
+                let mut recursion_list = Vec::new();
+                for related in permutation {
+                    match self.canonical.get(related) {
+                        Some(canonical) => path.push_str(canonical),
+                        None => {
+                            if issuer_copy.get(related).is_none() {
+                                recursion_list.push(related);
+                            }
+                            path.push_str(&issuer_copy.issue(related));
+                        }
+                    }
+                    if is_worse(&path, &chosen) {
+                        continue 'permutation;
+                    }
+                }
+                for related in recursion_list {
+                    let (hash, result_issuer) = self.hash_n_degree(related, issuer_copy.clone())?;
+                    path.push_str(&issuer_copy.issue(related));
+                    path.push('<');
+                    path.push_str(&hash);
+                    path.push('>');
+                    issuer_copy = result_issuer;
+                    if is_worse(&path, &chosen) {
+                        continue 'permutation;
+                    }
+                }
+                if chosen.as_ref().is_none_or(|(chosen_path, _)| path < *chosen_path) {
+                    chosen = Some((path, issuer_copy));
+                }
+            }
+            if let Some((path, chosen_issuer)) = chosen {
+                data_to_hash.push_str(&path);
+                issuer = chosen_issuer;
+            }
+        }
+        Ok((sha256_hex(&data_to_hash), issuer))
+    }
+}
+
+fn canonical_nquads(mut quads: Vec<Quad>) -> Result<String, JsonLdError> {
+    quads.sort();
+    quads.dedup();
+    let mut blank_node_quads = HashMap::<&str, Vec<&Quad>>::new();
+    for quad in &quads {
+        for term in [&quad.subject, &quad.object] {
+            if let Term::Blank(id) = term {
+                let entry = blank_node_quads.entry(id).or_default();
+                if entry.last().is_none_or(|last| !std::ptr::eq(*last, quad)) {
+                    entry.push(quad);
+                }
+            }
+        }
+    }
+    let mut canonicalizer = Canonicalizer {
+        blank_node_quads,
+        canonical: IdentifierIssuer::new("_:c14n"),
+        remaining_hashes: MAX_N_DEGREE_HASHES,
+    };
+
+    let mut hash_to_blank_nodes = BTreeMap::<String, Vec<&str>>::new();
+    for id in canonicalizer.blank_node_quads.keys() {
+        hash_to_blank_nodes.entry(canonicalizer.hash_first_degree(id)).or_default().push(id);
+    }
+    let mut shared_hashes = Vec::new();
+    for blank_nodes in hash_to_blank_nodes.into_values() {
+        match blank_nodes.as_slice() {
+            [id] => {
+                canonicalizer.canonical.issue(id);
+            }
+            _ => shared_hashes.push(blank_nodes),
+        }
+    }
+    for blank_nodes in shared_hashes {
+        let mut hash_paths = Vec::new();
+        for id in blank_nodes {
+            if canonicalizer.canonical.get(id).is_some() {
+                continue;
+            }
+            let mut issuer = IdentifierIssuer::new("_:b");
+            issuer.issue(id);
+            hash_paths.push(canonicalizer.hash_n_degree(id, issuer)?);
+        }
+        hash_paths.sort_by(|(a, _), (b, _)| a.cmp(b));
+        for (_, issuer) in hash_paths {
+            for id in &issuer.issued {
+                canonicalizer.canonical.issue(id);
+            }
+        }
+    }
+
+    let mut lines = quads
+        .iter()
+        .map(|quad| nquad(quad, |id| canonicalizer.canonical.get(id).cloned().unwrap_or_default()))
+        .collect::<Vec<_>>();
+    lines.sort();
+    Ok(lines.concat())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Quad, Term, XSD_STRING, canonical_nquads, canonicalize, unsigned_properties};
+
+    /// Parses the N-Triples subset that the URDNA2015 test vectors below use: IRIs, blank nodes and plain literals.
+    fn parse_triples(nquads: &str) -> Vec<Quad> {
+        let term = |token: &str| match token.strip_prefix('<').and_then(|token| token.strip_suffix('>')) {
+            Some(iri) => Term::Iri(iri.to_owned()),
+            None if token.starts_with("_:") => Term::Blank(token.to_owned()),
+            None => Term::Literal {
+                value: token.trim_matches('"').to_owned(),
+                datatype: XSD_STRING.to_owned(),
+                language: None,
+            },
+        };
+        nquads
+            .lines()
+            .map(|line| {
+                let [subject, predicate, object, "."] = line.split(' ').collect::<Vec<_>>()[..] else {
+                    panic!("unsupported N-Quads line: {line}");
+                };
+                let Term::Iri(predicate) = term(predicate) else {
+                    panic!("predicate is not an IRI: {line}");
+                };
+                Quad {
+                    subject: term(subject),
+                    predicate,
+                    object: term(object),
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn blank_nodes_are_labeled_as_the_urdna2015_test_suite_expects() {
+        // vectors of the W3C RDF Dataset Canonicalization test suite, the ones without named graphs
+        macro_rules! vectors {
+            ($($test:literal),*) => {
+                [$((
+                    $test,
+                    include_str!(concat!("jsonld/urdna2015/", $test, "-in.nq")),
+                    include_str!(concat!("jsonld/urdna2015/", $test, "-urdna2015.nq")),
+                )),*]
+            };
+        }
+        for (test, input, expected) in vectors!("test003", "test005", "test020", "test030", "test044", "test053", "test054", "test063") {
+            assert_eq!(canonical_nquads(parse_triples(input)).unwrap(), expected, "{test}");
+        }
+    }
+
+    #[test]
+    fn activity_is_canonicalized_to_sorted_nquads() {
+        let note = serde_json::json!({
+            "@context": ["https://www.w3.org/ns/activitystreams", { "toot": "http://joinmastodon.org/ns#", "Emoji": "toot:Emoji" }],
+            "id": "https://remote.test/notes/1",
+            "type": "Note",
+            "contentMap": { "en": "hi \"there\"" },
+            "tag": [{ "type": "Emoji", "name": ":a:" }, { "type": "Mention", "name": "@b" }],
+            "width": 640,
+            "unknown": "dropped",
+        });
+        let expected = r#"<https://remote.test/notes/1> <http://www.w3.org/1999/02/22-rdf-syntax-ns#type> <https://www.w3.org/ns/activitystreams#Note> .
+<https://remote.test/notes/1> <https://www.w3.org/ns/activitystreams#content> "hi \"there\""@en .
+<https://remote.test/notes/1> <https://www.w3.org/ns/activitystreams#tag> _:c14n0 .
+<https://remote.test/notes/1> <https://www.w3.org/ns/activitystreams#tag> _:c14n1 .
+<https://remote.test/notes/1> <https://www.w3.org/ns/activitystreams#width> "640"^^<http://www.w3.org/2001/XMLSchema#nonNegativeInteger> .
+_:c14n0 <http://www.w3.org/1999/02/22-rdf-syntax-ns#type> <https://www.w3.org/ns/activitystreams#Mention> .
+_:c14n0 <https://www.w3.org/ns/activitystreams#name> "@b" .
+_:c14n1 <http://www.w3.org/1999/02/22-rdf-syntax-ns#type> <http://joinmastodon.org/ns#Emoji> .
+_:c14n1 <https://www.w3.org/ns/activitystreams#name> ":a:" .
+"#;
+        assert_eq!(canonicalize(&note).unwrap(), expected);
+    }
+
+    #[test]
+    fn undefined_terms_are_reported_as_unsigned() {
+        let create = serde_json::json!({
+            "@context": ["https://www.w3.org/ns/activitystreams", { "toot": "http://joinmastodon.org/ns#", "Emoji": "toot:Emoji" }],
+            "id": "https://remote.test/notes/1/activity",
+            "type": "Create",
+            "actor": "https://remote.test/users/alice",
+            "object": {
+                "@context": { "_misskey_quote": "https://misskey-hub.net/ns#_misskey_quote" },
+                "id": "https://remote.test/notes/1",
+                "type": "Note",
+                "contentMap": { "en": "hi" },
+                "quoteUri": "https://blog.test/articles/first-post",
+                "_misskey_quote": "https://blog.test/articles/first-post",
+                "tag": [{ "type": "Emoji", "name": ":a:", "unknown": "dropped" }],
+            },
+        });
+        assert_eq!(unsigned_properties(&create).unwrap(), ["object.quoteUri", "object.tag.unknown"]);
+    }
+}
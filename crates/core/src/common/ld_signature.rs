@@ -0,0 +1,274 @@
+//! Linked Data Signatures (`RsaSignature2017`) that Mastodon attaches to activities it forwards.
+
+use crate::common::jsonld::{self, JsonLdError};
+use crate::common::verify::{KeyVerification, verify_with_key_id};
+use crate::traits::{Env, HTTPClient, PublicKeyCache};
+use base64::Engine;
+use rsa::pkcs1v15::Signature;
+use rsa::sha2::{Digest, Sha256};
+use serde_json::{Map, Value};
+
+const SIGNATURE_OPTIONS_CONTEXT: &str = "https://w3id.org/identity/v1";
+
+/// Builds the string that is signed for `document` with the signature `options`.
+fn signed_data(mut options: Map<String, Value>, document: Map<String, Value>) -> Result<String, JsonLdError> {
+    options.remove("type");
+    options.remove("id");
+    options.remove("signatureValue");
+    options.insert("@context".to_owned(), SIGNATURE_OPTIONS_CONTEXT.into());
+    let hash = |value: Value| jsonld::canonicalize(&value).map(|nquads| format!("{:x}", Sha256::digest(nquads.as_bytes())));
+    Ok(hash(Value::Object(options))? + &hash(Value::Object(document))?)
+}
+
+/// Verifies an `RsaSignature2017` signature on `document` and returns the actor that owns `signature.creator`.
+///
+/// Documents with properties the signature does not cover are refused, so that they are fetched from their origin instead.
+#[tracing::instrument(skip(state, document))]
+pub async fn verify_signature<E>(state: &E, document: &str) -> Option<String>
+where
+    E: Env + HTTPClient + PublicKeyCache,
+{
+    let mut document = serde_json::from_str::<Map<String, Value>>(document).ok()?;
+    let Some(Value::Object(options)) = document.remove("signature") else {
+        tracing::info!("document has no signature object");
+        return None;
+    };
+    if options.get("type").and_then(Value::as_str) != Some("RsaSignature2017") {
+        tracing::info!(?options, "unsupported signature");
+        return None;
+    }
+    let creator = options.get("creator")?.as_str()?.to_owned();
+    let signature = options.get("signatureValue")?.as_str()?;
+    let Some(signature) = base64::engine::general_purpose::STANDARD
+        .decode(signature)
+        .ok()
+        .and_then(|signature| Signature::try_from(signature.as_slice()).ok())
+    else {
+        tracing::warn!("invalid signature value");
+        return None;
+    };
+    match jsonld::unsigned_properties(&Value::Object(document.clone())) {
+        Ok(unsigned) if unsigned.is_empty() => {}
+        Ok(unsigned) => {
+            tracing::info!(?unsigned, "signature does not cover every property");
+            return None;
+        }
+        Err(e) => {
+            tracing::warn!(error = %e, "failed to expand signed document");
+            return None;
+        }
+    }
+    let data = match signed_data(options, document) {
+        Ok(data) => data,
+        Err(e) => {
+            tracing::warn!(error = %e, "failed to canonicalize signed document");
+            return None;
+        }
+    };
+
+    match verify_with_key_id(state, &creator, data.as_bytes(), &signature, state.timestamp_now()).await {
+        KeyVerification::Verified(actor) => {
+            tracing::info!(actor, "linked data signature verified");
+            Some(actor)
+        }
+        KeyVerification::KeyUnavailable | KeyVerification::Failed => None,
+    }
+}
+
+/// Attaches an `RsaSignature2017` signature by `key` to `document`, as Mastodon does on the activities it forwards.
+#[cfg(test)]
+pub(crate) fn sign_document(key: &crate::traits::RSASHA2SigningKey, creator: &str, document: &Value) -> Value {
+    use ring_compat::signature::{SignatureEncoding, Signer};
+    let options = serde_json::json!({
+        "type": "RsaSignature2017",
+        "creator": creator,
+        "created": "2025-06-01T00:00:00Z",
+    });
+    let data = signed_data(options.as_object().unwrap().clone(), document.as_object().unwrap().clone()).unwrap();
+    let signature = base64::engine::general_purpose::STANDARD.encode(key.sign(data.as_bytes()).to_bytes());
+    let mut signed = document.clone();
+    signed["signature"] = options;
+    signed["signature"]["signatureValue"] = signature.into();
+    signed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{sign_document, signed_data, verify_signature};
+    use crate::traits::{CachedPublicKey, Ed25519SigningKey, Env, HTTPClient, PublicKeyCache, RSASHA2SigningKey, UserSigningKey};
+    use axum::body::Body;
+    use axum::http::{Request, Response};
+    use bytes::Bytes;
+    use chrono::{DateTime, Utc};
+    use rsa::pkcs8::DecodePrivateKey;
+    use serde_json::{Value, json};
+    use std::collections::HashMap;
+    use std::convert::Infallible;
+    use std::fmt::Display;
+    use std::sync::Mutex;
+
+    const ACTOR: &str = "https://remote.test/users/alice";
+
+    struct TestState {
+        key: RSASHA2SigningKey,
+        key_cache: Mutex<HashMap<String, CachedPublicKey>>,
+        /// Actors other than `ACTOR` by URL.
+        actors: HashMap<String, Value>,
+    }
+
+    impl Env for TestState {
+        fn url(&self) -> impl Display + Send + '_ {
+            "https://blog.test"
+        }
+        fn timestamp_now(&self) -> DateTime<Utc> {
+            DateTime::parse_from_rfc3339("2025-06-01T00:00:00Z").unwrap().to_utc()
+        }
+        fn signing_key(&self, _username: &str) -> UserSigningKey<'_> {
+            UserSigningKey {
+                id: "main-key",
+                key: &self.key,
+            }
+        }
+        fn integrity_key(&self, _username: &str) -> Option<Ed25519SigningKey> {
+            None
+        }
+    }
+
+    impl PublicKeyCache for TestState {
+        type Error = Infallible;
+        async fn get_public_key(&self, key_id: &str) -> Option<CachedPublicKey> {
+            self.key_cache.lock().unwrap().get(key_id).cloned()
+        }
+        async fn put_public_key(&self, key_id: &str, key: CachedPublicKey) -> Result<(), Self::Error> {
+            self.key_cache.lock().unwrap().insert(key_id.to_owned(), key);
+            Ok(())
+        }
+    }
+
+    impl HTTPClient for TestState {
+        type Error = Infallible;
+        async fn request(&self, request: Request<Bytes>) -> Result<Response<Body>, Self::Error> {
+            if let Some(actor) = self.actors.get(&request.uri().to_string()) {
+                return Ok(Response::new(Body::from(actor.to_string())));
+            }
+            assert_eq!(request.uri(), ACTOR);
+            let actor = json!({
+                "id": ACTOR,
+                "type": "Person",
+                "publicKey": {
+                    "id": format!("{ACTOR}#main-key"),
+                    "owner": ACTOR,
+                    "publicKeyPem": include_str!("../../../../test_config/public-key-for-test.pem"),
+                },
+            });
+            Ok(Response::new(Body::from(actor.to_string())))
+        }
+    }
+
+    fn sign(state: &TestState, document: &Value) -> Value {
+        sign_document(&state.key, &format!("{ACTOR}#main-key"), document)
+    }
+
+    fn test_state() -> TestState {
+        TestState {
+            key: RSASHA2SigningKey::from_pkcs8_pem(include_str!("../../../../test_config/private-key-for-test.pem")).unwrap(),
+            key_cache: Mutex::new(HashMap::new()),
+            actors: HashMap::new(),
+        }
+    }
+
+    #[test]
+    fn forwarded_reply_is_verified_by_its_signature_creator() {
+        let state = test_state();
+        let activity = json!({
+            "@context": [
+                "https://www.w3.org/ns/activitystreams",
+                "https://w3id.org/security/v1",
+                { "sensitive": "as:sensitive", "toot": "http://joinmastodon.org/ns#", "Emoji": "toot:Emoji" }
+            ],
+            "id": "https://remote.test/users/alice/statuses/1/activity",
+            "type": "Create",
+            "actor": ACTOR,
+            "published": "2025-06-01T00:00:00Z",
+            "to": ["https://www.w3.org/ns/activitystreams#Public"],
+            "object": {
+                "id": "https://remote.test/users/alice/statuses/1",
+                "type": "Note",
+                "attributedTo": ACTOR,
+                "inReplyTo": "https://blog.test/articles/first-post",
+                "content": "<p>hello</p>",
+                "contentMap": { "en": "<p>hello</p>" },
+                "sensitive": false,
+                "tag": [{ "type": "Emoji", "name": ":blob:" }],
+            },
+        });
+        let signed = sign(&state, &activity);
+
+        let verified = futures::executor::block_on(verify_signature(&state, &signed.to_string()));
+        assert_eq!(verified.as_deref(), Some(ACTOR));
+
+        let tampered = signed.to_string().replace("<p>hello</p>", "<p>bye</p>");
+        assert_eq!(futures::executor::block_on(verify_signature(&state, &tampered)), None);
+    }
+
+    #[test]
+    fn quote_outside_the_signed_contexts_is_not_trusted() {
+        let state = test_state();
+        let activity = json!({
+            "@context": ["https://www.w3.org/ns/activitystreams", "https://w3id.org/security/v1"],
+            "id": "https://remote.test/users/alice/statuses/1/activity",
+            "type": "Create",
+            "actor": ACTOR,
+            "object": {
+                "id": "https://remote.test/users/alice/statuses/1",
+                "type": "Note",
+                "attributedTo": ACTOR,
+                "quoteUri": "https://blog.test/articles/first-post",
+                "content": "<p>hello</p>",
+            },
+        });
+        let signed = sign(&state, &activity);
+        assert_eq!(futures::executor::block_on(verify_signature(&state, &signed.to_string())), None);
+
+        // The signature still matches once the quote is swapped, since the undefined term was never signed.
+        let swapped = signed.to_string().replace("first-post", "second-post");
+        let mut document = serde_json::from_str::<serde_json::Map<String, Value>>(&swapped).unwrap();
+        let Some(Value::Object(options)) = document.remove("signature") else {
+            unreachable!()
+        };
+        let expected = signed_data(options.clone(), activity.as_object().unwrap().clone()).unwrap();
+        assert_eq!(signed_data(options, document).unwrap(), expected);
+    }
+
+    #[test]
+    fn activities_signed_by_other_implementations_are_verified() {
+        // activities captured from Friendica and Smithereen servers, Smithereen publishes its key with the base64 body on one line
+        let vectors = [
+            (
+                include_str!("ld_signature/friendica_create_note.json"),
+                "https://soc.schuerz.at/profile/jakob",
+                include_str!("ld_signature/friendica_key.pem"),
+            ),
+            (
+                include_str!("ld_signature/smithereen_create_note.json"),
+                "https://friends.grishka.me/users/1",
+                include_str!("ld_signature/smithereen_key.pem"),
+            ),
+        ];
+        for (activity, actor, pem) in vectors {
+            let mut state = test_state();
+            let document = json!({
+                "id": actor,
+                "type": "Person",
+                "publicKey": { "id": format!("{actor}#main-key"), "owner": actor, "publicKeyPem": pem },
+            });
+            state.actors.insert(actor.to_owned(), document);
+
+            assert_eq!(futures::executor::block_on(verify_signature(&state, activity)).as_deref(), Some(actor));
+
+            let mut tampered = serde_json::from_str::<Value>(activity).unwrap();
+            tampered["object"]["content"] = "<p>forged</p>".into();
+            assert_eq!(futures::executor::block_on(verify_signature(&state, &tampered.to_string())), None);
+        }
+    }
+}
@@ -0,0 +1,66 @@
+//! Scheduled publishing based on the `published` date of the article Note.
+
+use crate::traits::{ArticleProvider, Env};
+use bytes::Bytes;
+use chrono::{DateTime, FixedOffset, Utc};
+use serde::Deserialize;
+
+#[derive(Debug, Deserialize)]
+struct Publication {
+    published: Option<DateTime<FixedOffset>>,
+}
+
+/// Reads the article Note, if it exists.
+async fn read_article_ap<E>(state: &E, slug: &str) -> Option<Bytes>
+where
+    E: ArticleProvider,
+{
+    let body = state.get_article_ap(slug).await?;
+    match axum::body::to_bytes(body, usize::MAX).await {
+        Ok(body) => Some(body),
+        Err(e) => {
+            tracing::warn!(error = %e, "failed to read article");
+            None
+        }
+    }
+}
+
+/// Returns the `published` date of the article Note in `body`, if it has a valid one.
+fn parse_published(body: &[u8]) -> Option<DateTime<Utc>> {
+    match serde_json::from_slice::<Publication>(body) {
+        Ok(publication) => publication.published.map(|published| published.to_utc()),
+        Err(e) => {
+            tracing::warn!(error = %e, "failed to parse article");
+            None
+        }
+    }
+}
+
+/// Returns the `published` date of the article, if it exists and has a valid one.
+pub async fn published_at<E>(state: &E, slug: &str) -> Option<DateTime<Utc>>
+where
+    E: ArticleProvider,
+{
+    parse_published(&read_article_ap(state, slug).await?)
+}
+
+/// Whether the article exists but must stay hidden until its `published` date.
+pub async fn is_scheduled<E>(state: &E, slug: &str) -> bool
+where
+    E: Env + ArticleProvider,
+{
+    published_at(state, slug).await.is_some_and(|published| published > state.timestamp_now())
+}
+
+/// Returns the article Note once it is published, reading it a single time.
+pub async fn published_article_ap<E>(state: &E, slug: &str) -> Option<Bytes>
+where
+    E: Env + ArticleProvider,
+{
+    let body = read_article_ap(state, slug).await?;
+    if parse_published(&body).is_some_and(|published| published > state.timestamp_now()) {
+        tracing::info!("article is not published yet");
+        return None;
+    }
+    Some(body)
+}
@@ -1,14 +1,17 @@
-use crate::traits::HTTPClient;
-use axum::http::Request;
+use crate::traits::{CachedPublicKey, Env, HTTPClient, PublicKeyCache};
+use axum::http::header::DATE;
+use axum::http::{HeaderMap, Request};
 use base64::Engine;
 use bytes::Bytes;
+use chrono::{DateTime, TimeDelta, Utc};
 use http_body_util::{BodyExt, Limited};
 use rsa::RsaPublicKey;
 use rsa::pkcs1v15::{Signature, VerifyingKey};
 use rsa::pkcs8::DecodePublicKey;
-use rsa::sha2::{Digest, Sha256};
+use rsa::sha2::{Digest, Sha256, Sha512};
 use rsa::signature::Verifier;
 use serde::Deserialize;
+use serde::de::DeserializeOwned;
 
 #[derive(Debug)]
 pub enum VerifiedRequest<B> {
@@ -26,12 +29,85 @@ pub enum VerifiedRequest<B> {
 
 const BODY_LIMIT: usize = 1024 * 64;
 const ACTOR_DOCUMENT_LIMIT: usize = 1024 * 64;
+const PUBLIC_KEY_CACHE_TTL: TimeDelta = TimeDelta::hours(24);
+
+/// Requirements a signature has to satisfy before its key is even fetched.
+#[derive(Debug, Clone)]
+pub struct SignaturePolicy {
+    /// Header names (lowercase, `(request-target)` included) that must be listed in `headers=`.
+    pub required_headers: Vec<String>,
+    /// How old the `Date` header may be compared with `Env::timestamp_now`.
+    pub max_age: TimeDelta,
+    /// How far the `Date` header may be ahead of `Env::timestamp_now`.
+    pub max_future_skew: TimeDelta,
+}
+
+impl Default for SignaturePolicy {
+    fn default() -> Self {
+        Self {
+            required_headers: ["(request-target)", "host", "date", "digest"].map(String::from).to_vec(),
+            max_age: TimeDelta::hours(1),
+            max_future_skew: TimeDelta::minutes(5),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DigestAlgorithm {
+    Sha256,
+    Sha512,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExpectedDigest {
+    pub algorithm: DigestAlgorithm,
+    pub value: Vec<u8>,
+}
+
+/// Parses an RFC 3230 `Digest` header such as `SHA-256=..., SHA-512=...`.
+///
+/// Unsupported algorithms are skipped. Returns `None` when a supported entry is malformed.
+pub fn parse_digest(header: &str) -> Option<Vec<ExpectedDigest>> {
+    let mut digests = Vec::new();
+    for entry in header.split(',') {
+        let (algorithm, value) = entry.trim().split_once('=')?;
+        let algorithm = match algorithm.to_ascii_lowercase().as_str() {
+            "sha-256" => DigestAlgorithm::Sha256,
+            "sha-512" => DigestAlgorithm::Sha512,
+            _ => continue,
+        };
+        let value = base64::engine::general_purpose::STANDARD.decode(value.trim()).ok()?;
+        digests.push(ExpectedDigest { algorithm, value });
+    }
+    Some(digests)
+}
+
+/// Parses an RFC 9530 `Content-Digest` header such as `sha-256=:...:, sha-512=:...:`.
+///
+/// Unsupported algorithms are skipped. Returns `None` when a supported entry is malformed.
+pub fn parse_content_digest(header: &str) -> Option<Vec<ExpectedDigest>> {
+    let mut digests = Vec::new();
+    for entry in header.split(',') {
+        let (algorithm, value) = entry.trim().split_once('=')?;
+        let algorithm = match algorithm {
+            "sha-256" => DigestAlgorithm::Sha256,
+            "sha-512" => DigestAlgorithm::Sha512,
+            _ => continue,
+        };
+        let value = value.split(';').next().unwrap_or(value).trim();
+        let value = value.strip_prefix(':')?.strip_suffix(':')?;
+        let value = base64::engine::general_purpose::STANDARD.decode(value).ok()?;
+        digests.push(ExpectedDigest { algorithm, value });
+    }
+    Some(digests)
+}
 
 #[derive(Debug)]
 pub struct VerifyBody<B> {
     inner: B,
-    hasher: Sha256,
-    expected_digest: String,
+    sha256: Option<Sha256>,
+    sha512: Option<Box<Sha512>>,
+    expected_digests: Vec<ExpectedDigest>,
     done: bool,
     digest_ok: bool,
 }
@@ -41,11 +117,13 @@ where
     B: http_body::Body<Data = Bytes> + Unpin,
     B::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
 {
-    pub fn new(inner: B, digest: String) -> Self {
+    pub fn new(inner: B, expected_digests: Vec<ExpectedDigest>) -> Self {
+        let uses = |algorithm| expected_digests.iter().any(|expected| expected.algorithm == algorithm);
         Self {
             inner,
-            hasher: Sha256::new(),
-            expected_digest: digest,
+            sha256: uses(DigestAlgorithm::Sha256).then(Sha256::new),
+            sha512: uses(DigestAlgorithm::Sha512).then(|| Box::new(Sha512::new())),
+            expected_digests,
             done: false,
             digest_ok: false,
         }
@@ -53,8 +131,13 @@ where
 
     fn finalize(&mut self) {
         if !self.done {
-            let digest = base64::engine::general_purpose::STANDARD.encode(std::mem::take(&mut self.hasher).finalize());
-            self.digest_ok = format!("SHA-256={}", digest) == self.expected_digest;
+            let sha256 = self.sha256.take().map(|hasher| hasher.finalize().to_vec());
+            let sha512 = self.sha512.take().map(|hasher| hasher.finalize().to_vec());
+            self.digest_ok = !self.expected_digests.is_empty()
+                && self.expected_digests.iter().all(|expected| match expected.algorithm {
+                    DigestAlgorithm::Sha256 => sha256.as_ref() == Some(&expected.value),
+                    DigestAlgorithm::Sha512 => sha512.as_ref() == Some(&expected.value),
+                });
             self.done = true;
         }
     }
@@ -85,7 +168,12 @@ where
         match std::pin::Pin::new(&mut self.inner).poll_frame(cx) {
             std::task::Poll::Ready(Some(Ok(frame))) => {
                 if let Some(data) = frame.data_ref() {
-                    self.hasher.update(data);
+                    if let Some(hasher) = &mut self.sha256 {
+                        hasher.update(data);
+                    }
+                    if let Some(hasher) = &mut self.sha512 {
+                        hasher.update(data);
+                    }
                 }
                 std::task::Poll::Ready(Some(Ok(frame)))
             }
@@ -99,10 +187,37 @@ where
     }
 }
 
+#[derive(Debug, Default)]
+struct SignatureHeader {
+    key_id: Option<String>,
+    algorithm: Option<String>,
+    signed_headers: Option<String>,
+    signature: Option<String>,
+}
+
+fn parse_signature_header(headers: &HeaderMap) -> Option<SignatureHeader> {
+    let signature_header = headers.get("signature")?.to_str().ok()?;
+    let mut parsed = SignatureHeader::default();
+    for part in signature_header.split(',') {
+        let part = part.trim();
+        let mut kv = part.splitn(2, '=');
+        let k = kv.next().unwrap_or("");
+        let v = kv.next().unwrap_or("").trim_matches('"');
+        match k {
+            "keyId" => parsed.key_id = Some(v.to_string()),
+            "algorithm" => parsed.algorithm = Some(v.to_string()),
+            "headers" => parsed.signed_headers = Some(v.to_string()),
+            "signature" => parsed.signature = Some(v.to_string()),
+            _ => {}
+        }
+    }
+    Some(parsed)
+}
+
 #[tracing::instrument(skip(state, req))]
 pub async fn verify_request<E, B>(state: &E, req: Request<B>) -> VerifiedRequest<B>
 where
-    E: HTTPClient,
+    E: Env + HTTPClient + PublicKeyCache,
     B: http_body::Body<Data = Bytes> + Unpin,
     B::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
 {
@@ -111,32 +226,16 @@ where
     let path = parts.uri.path_and_query().map(|pq| pq.as_str()).unwrap_or(parts.uri.path());
     let headers = &parts.headers;
 
-    let signature_header = headers
-        .get("Signature")
-        .or_else(|| headers.get("signature"))
-        .and_then(|v| v.to_str().ok());
-    let Some(signature_header) = signature_header else {
+    let Some(SignatureHeader {
+        key_id,
+        algorithm,
+        signed_headers,
+        signature,
+    }) = parse_signature_header(headers)
+    else {
         tracing::warn!("missing signature header");
         return VerifiedRequest::CannotVerify(Request::from_parts(parts, Limited::new(body, BODY_LIMIT)));
     };
-
-    let mut key_id = None;
-    let mut algorithm = None;
-    let mut signed_headers = None;
-    let mut signature = None;
-    for part in signature_header.split(',') {
-        let part = part.trim();
-        let mut kv = part.splitn(2, '=');
-        let k = kv.next().unwrap_or("");
-        let v = kv.next().unwrap_or("").trim_matches('"');
-        match k {
-            "keyId" => key_id = Some(v.to_string()),
-            "algorithm" => algorithm = Some(v.to_string()),
-            "headers" => signed_headers = Some(v.to_string()),
-            "signature" => signature = Some(v.to_string()),
-            _ => {}
-        }
-    }
     let Some(key_id) = key_id else {
         tracing::warn!("missing keyId");
         return VerifiedRequest::CannotVerify(Request::from_parts(parts, Limited::new(body, BODY_LIMIT)));
@@ -159,9 +258,41 @@ where
         return VerifiedRequest::CannotVerify(Request::from_parts(parts, Limited::new(body, BODY_LIMIT)));
     }
 
+    let policy = state.signature_policy();
+    if let Some(missing) = policy.required_headers.iter().find(|required| {
+        !signed_headers.split_whitespace().any(|header_name| {
+            header_name.eq_ignore_ascii_case(required)
+                // an RFC 9530 Content-Digest covers the body as well as a Digest does
+                || (required.as_str() == "digest" && header_name.eq_ignore_ascii_case("content-digest"))
+        })
+    }) {
+        tracing::warn!(header = %missing, "required header is not signed");
+        return VerifiedRequest::VerifyFailed;
+    }
+    let Some(date) = headers.get(DATE).and_then(|v| v.to_str().ok()) else {
+        tracing::warn!("missing date header");
+        return VerifiedRequest::VerifyFailed;
+    };
+    let date = match DateTime::parse_from_rfc2822(date) {
+        Ok(date) => date.to_utc(),
+        Err(_) => {
+            tracing::warn!(date, "invalid date header");
+            return VerifiedRequest::VerifyFailed;
+        }
+    };
+    let now = state.timestamp_now();
+    if date < now - policy.max_age {
+        tracing::warn!(%date, %now, "request is too old");
+        return VerifiedRequest::VerifyFailed;
+    }
+    if date > now + policy.max_future_skew {
+        tracing::warn!(%date, %now, "request is dated in the future");
+        return VerifiedRequest::VerifyFailed;
+    }
+
     let mut sign_target = String::new();
     let mut first = true;
-    let mut digest_header = None;
+    let mut expected_digests: Option<Vec<ExpectedDigest>> = None;
     for header_name in signed_headers.split_whitespace() {
         if !first {
             sign_target.push('\n');
@@ -186,105 +317,474 @@ where
                     return VerifiedRequest::CannotVerify(Request::from_parts(parts, Limited::new(body, BODY_LIMIT)));
                 }
             };
-            if header_name.eq_ignore_ascii_case("digest") {
-                digest_header = Some(value.to_string());
+            let digests = if header_name.eq_ignore_ascii_case("digest") {
+                Some(parse_digest(value))
+            } else if header_name.eq_ignore_ascii_case("content-digest") {
+                Some(parse_content_digest(value))
+            } else {
+                None
+            };
+            if let Some(digests) = digests {
+                match digests {
+                    Some(digests) if !digests.is_empty() => expected_digests.get_or_insert_default().extend(digests),
+                    _ => {
+                        tracing::warn!(header = %header_name, value, "unsupported or malformed digest");
+                        return VerifiedRequest::VerifyFailed;
+                    }
+                }
             }
             sign_target.push_str(&format!("{}: {}", header_name.to_ascii_lowercase(), value));
         }
     }
 
-    let actor_url = key_id.split('#').next().unwrap_or(&key_id);
-    #[derive(Deserialize)]
-    struct ActorKey {
-        #[serde(rename = "publicKeyPem")]
-        pem: String,
-    }
-    #[derive(Deserialize)]
-    struct Actor {
-        #[serde(rename = "publicKey")]
-        key: Option<ActorKey>,
-    }
-    let request = match Request::get(actor_url)
-        .header(axum::http::header::ACCEPT, crate::common::headers::AP_ACCEPT)
-        .body(Bytes::new())
-    {
-        Ok(req) => req,
+    let sig_bytes = match base64::engine::general_purpose::STANDARD.decode(signature.as_bytes()) {
+        Ok(b) => b,
         Err(_) => {
-            tracing::warn!("failed to build actor request");
+            tracing::warn!("invalid signature encoding");
             return VerifiedRequest::CannotVerify(Request::from_parts(parts, Limited::new(body, BODY_LIMIT)));
         }
     };
-    let response = match state.request(request).await {
-        Ok(resp) => resp,
+    let signature = match Signature::try_from(sig_bytes.as_slice()) {
+        Ok(s) => s,
         Err(_) => {
-            tracing::warn!("failed to fetch actor");
+            tracing::warn!("invalid signature format");
             return VerifiedRequest::CannotVerify(Request::from_parts(parts, Limited::new(body, BODY_LIMIT)));
         }
     };
-    if !response.status().is_success() {
-        tracing::warn!(status = %response.status(), "actor fetch failed");
-        return VerifiedRequest::CannotVerify(Request::from_parts(parts, Limited::new(body, BODY_LIMIT)));
-    }
-    let actor_body = match BodyExt::collect(Limited::new(response.into_body(), ACTOR_DOCUMENT_LIMIT)).await {
-        Ok(b) => b,
-        Err(_) => {
-            tracing::warn!("failed to read actor response");
+
+    let actor = match verify_with_key_id(state, &key_id, sign_target.as_bytes(), &signature, now).await {
+        KeyVerification::Verified(actor) => actor,
+        KeyVerification::KeyUnavailable => {
             return VerifiedRequest::CannotVerify(Request::from_parts(parts, Limited::new(body, BODY_LIMIT)));
         }
+        KeyVerification::Failed => return VerifiedRequest::VerifyFailed,
     };
-    let actor: Actor = match serde_json::from_slice(&actor_body.to_bytes()) {
-        Ok(v) => v,
-        Err(_) => {
-            tracing::warn!("failed to parse actor");
-            return VerifiedRequest::CannotVerify(Request::from_parts(parts, Limited::new(body, BODY_LIMIT)));
+
+    tracing::info!("signature verified");
+    let limited = Limited::new(body, BODY_LIMIT);
+    if let Some(expected_digests) = expected_digests {
+        let body = VerifyBody::new(limited, expected_digests);
+        VerifiedRequest::VerifiedDigest {
+            request: Request::from_parts(parts, body),
+            actor,
         }
-    };
-    let pem = match actor.key {
-        Some(k) => k.pem,
-        None => {
-            tracing::warn!("actor has no public key");
-            return VerifiedRequest::CannotVerify(Request::from_parts(parts, Limited::new(body, BODY_LIMIT)));
+    } else {
+        VerifiedRequest::Verified {
+            request: Request::from_parts(parts, limited),
+            actor,
         }
+    }
+}
+
+pub(crate) enum KeyVerification {
+    Verified(String),
+    KeyUnavailable,
+    Failed,
+}
+
+/// Verifies `message` with the key `key_id`, the cached key is refetched when it is expired or does not match.
+pub(crate) async fn verify_with_key_id<E>(state: &E, key_id: &str, message: &[u8], signature: &Signature, now: DateTime<Utc>) -> KeyVerification
+where
+    E: HTTPClient + PublicKeyCache,
+{
+    match state.get_public_key(key_id).await {
+        Some(cached) if cached.expires_at > now => match parse_public_key(&cached.pem) {
+            Some(verifying_key) if verifying_key.verify(message, signature).is_ok() => return KeyVerification::Verified(cached.actor),
+            _ => tracing::info!("cached key does not verify the signature, refetch"),
+        },
+        _ => {}
+    }
+    let Some(key) = fetch_public_key(state, key_id, now).await else {
+        return KeyVerification::KeyUnavailable;
+    };
+    let Some(verifying_key) = parse_public_key(&key.pem) else {
+        tracing::warn!("invalid public key");
+        return KeyVerification::KeyUnavailable;
     };
+    // the key is fetched again next time, so a failed cache write does not fail the verification
+    if let Err(e) = state.put_public_key(key_id, key.clone()).await {
+        tracing::error!(error = ?e, "failed to cache public key");
+    }
+    if verifying_key.verify(message, signature).is_err() {
+        tracing::info!("signature verification failed");
+        return KeyVerification::Failed;
+    }
+    KeyVerification::Verified(key.actor)
+}
 
-    let verifying_key = match RsaPublicKey::from_public_key_pem(&pem) {
-        Ok(k) => VerifyingKey::<Sha256>::new(k),
+fn parse_public_key(pem: &str) -> Option<VerifyingKey<Sha256>> {
+    let key = match RsaPublicKey::from_public_key_pem(pem) {
+        Ok(key) => key,
+        // some implementations such as Smithereen put the whole base64 body on one line, which strict PEM parsing refuses
         Err(_) => {
-            tracing::warn!("invalid public key");
-            return VerifiedRequest::CannotVerify(Request::from_parts(parts, Limited::new(body, BODY_LIMIT)));
+            let body = pem.lines().filter(|line| !line.starts_with("-----")).collect::<String>();
+            let der = base64::engine::general_purpose::STANDARD.decode(body.trim()).ok()?;
+            RsaPublicKey::from_public_key_der(&der).ok()?
         }
     };
-    let sig_bytes = match base64::engine::general_purpose::STANDARD.decode(signature.as_bytes()) {
-        Ok(b) => b,
-        Err(_) => {
-            tracing::warn!("invalid signature encoding");
-            return VerifiedRequest::CannotVerify(Request::from_parts(parts, Limited::new(body, BODY_LIMIT)));
+    Some(VerifyingKey::<Sha256>::new(key))
+}
+
+#[derive(Debug, Deserialize)]
+struct PublicKey {
+    id: String,
+    owner: String,
+    #[serde(rename = "publicKeyPem")]
+    pem: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub(crate) enum OneOrMany<T> {
+    Many(Vec<T>),
+    One(T),
+}
+
+#[derive(Debug, Deserialize)]
+struct KeyOwner {
+    id: String,
+    #[serde(rename = "publicKey")]
+    keys: Option<OneOrMany<PublicKey>>,
+}
+
+impl KeyOwner {
+    fn into_key(self, key_id: &str) -> Option<PublicKey> {
+        let keys = match self.keys? {
+            OneOrMany::One(key) => vec![key],
+            OneOrMany::Many(keys) => keys,
+        };
+        let key = keys.into_iter().find(|key| key.id == key_id)?;
+        if key.owner != self.id {
+            tracing::warn!(owner = key.owner, actor = self.id, "key owner mismatch");
+            return None;
         }
-    };
-    let signature = match Signature::try_from(sig_bytes.as_slice()) {
-        Ok(s) => s,
-        Err(_) => {
-            tracing::warn!("invalid signature format");
-            return VerifiedRequest::CannotVerify(Request::from_parts(parts, Limited::new(body, BODY_LIMIT)));
+        Some(key)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum KeyDocument {
+    Key(PublicKey),
+    Owner(KeyOwner),
+}
+
+#[tracing::instrument(skip(state))]
+async fn fetch_public_key<E>(state: &E, key_id: &str, now: DateTime<Utc>) -> Option<CachedPublicKey>
+where
+    E: HTTPClient,
+{
+    let document_url = key_id.split('#').next().unwrap_or(key_id);
+    let owner = match fetch_document::<E, KeyDocument>(state, document_url).await? {
+        KeyDocument::Key(key) => {
+            if key.id != key_id {
+                tracing::warn!(id = key.id, "key id mismatch");
+                return None;
+            }
+            let owner = fetch_document::<E, KeyOwner>(state, &key.owner).await?;
+            if owner.id != key.owner {
+                tracing::warn!(owner = key.owner, actor = owner.id, "key owner mismatch");
+                return None;
+            }
+            owner
+        }
+        KeyDocument::Owner(owner) => {
+            // the actor has to be the document it was fetched from, or any host could publish keys for it
+            if owner.id.split('#').next() != Some(document_url) {
+                tracing::warn!(actor = owner.id, document_url, "actor id mismatch");
+                return None;
+            }
+            owner
         }
     };
-    if verifying_key.verify(sign_target.as_bytes(), &signature).is_ok() {
-        tracing::info!("signature verified");
-        let limited = Limited::new(body, BODY_LIMIT);
-        if let Some(digest) = digest_header {
-            let body = VerifyBody::new(limited, digest);
-            VerifiedRequest::VerifiedDigest {
-                request: Request::from_parts(parts, body),
-                actor: actor_url.to_owned(),
+    let actor = owner.id.clone();
+    let Some(key) = owner.into_key(key_id) else {
+        tracing::warn!(actor, "actor does not publish the key");
+        return None;
+    };
+    Some(CachedPublicKey {
+        actor,
+        pem: key.pem,
+        expires_at: now + PUBLIC_KEY_CACHE_TTL,
+    })
+}
+
+#[tracing::instrument(skip(state))]
+pub(crate) async fn fetch_document<E, T>(state: &E, url: &str) -> Option<T>
+where
+    E: HTTPClient,
+    T: DeserializeOwned,
+{
+    let Ok(request) = Request::get(url)
+        .header(axum::http::header::ACCEPT, crate::common::headers::AP_ACCEPT)
+        .body(Bytes::new())
+    else {
+        tracing::warn!("failed to build key request");
+        return None;
+    };
+    let Ok(response) = state.request(request).await else {
+        tracing::warn!("failed to fetch key");
+        return None;
+    };
+    if !response.status().is_success() {
+        tracing::warn!(status = %response.status(), "key fetch failed");
+        return None;
+    }
+    let Ok(body) = BodyExt::collect(Limited::new(response.into_body(), ACTOR_DOCUMENT_LIMIT)).await else {
+        tracing::warn!("failed to read key response");
+        return None;
+    };
+    match serde_json::from_slice(&body.to_bytes()) {
+        Ok(document) => Some(document),
+        Err(e) => {
+            tracing::warn!(error = %e, "failed to parse key document");
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DigestAlgorithm, VerifiedRequest, VerifyBody, parse_content_digest, parse_digest, verify_request};
+    use crate::common::sign;
+    use crate::traits::{CachedPublicKey, Ed25519SigningKey, Env, HTTPClient, PublicKeyCache, RSASHA2SigningKey, UserSigningKey};
+    use axum::body::Body;
+    use axum::http::{Request, Response};
+    use bytes::Bytes;
+    use chrono::{DateTime, TimeDelta, Utc};
+    use rsa::pkcs8::DecodePrivateKey;
+    use std::collections::HashMap;
+    use std::convert::Infallible;
+    use std::fmt::Display;
+    use std::sync::Mutex;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    const ACTOR: &str = "https://remote.test/users/alice";
+    const ROTATED_OUT_KEY: &str = "-----BEGIN PUBLIC KEY-----
+MIGfMA0GCSqGSIb3DQEBAQUAA4GNADCBiQKBgQC0DRUPMJQdAKuuciwUVi0gvLx9
+prCUhKcqZHCN2Kb48vyIJsgKEwLKG8D54tTYTargVxRl/JU1tkxju0DzVMiBWJ2z
+4WDZmXHbIO+gPlDShQOaAMV5zptaHwfzDAbjVDJByZqIKT7NqSpGXTNUX2yvhgkz
+JPh/xS+77XdA6tWEwQIDAQAB
+-----END PUBLIC KEY-----";
+    const PUBLIC_KEY: &str = include_str!("../../../../test_config/public-key-for-test.pem");
+
+    struct TestState {
+        key: RSASHA2SigningKey,
+        now: DateTime<Utc>,
+        key_cache: Mutex<HashMap<String, CachedPublicKey>>,
+        documents: HashMap<String, serde_json::Value>,
+        actor_fetch_count: AtomicUsize,
+    }
+
+    impl TestState {
+        fn new() -> Self {
+            Self {
+                key: RSASHA2SigningKey::from_pkcs8_pem(include_str!("../../../../test_config/private-key-for-test.pem")).unwrap(),
+                now: DateTime::parse_from_rfc3339("2025-06-01T00:00:00Z").unwrap().to_utc(),
+                key_cache: Mutex::new(HashMap::new()),
+                documents: HashMap::from([(
+                    ACTOR.to_owned(),
+                    serde_json::json!({
+                        "id": ACTOR,
+                        "type": "Person",
+                        "publicKey": {
+                            "id": format!("{ACTOR}#main-key"),
+                            "owner": ACTOR,
+                            "publicKeyPem": PUBLIC_KEY,
+                        },
+                    }),
+                )]),
+                actor_fetch_count: AtomicUsize::new(0),
             }
-        } else {
-            VerifiedRequest::Verified {
-                request: Request::from_parts(parts, limited),
-                actor: actor_url.to_owned(),
+        }
+    }
+
+    impl Env for TestState {
+        fn url(&self) -> impl Display + Send + '_ {
+            "https://blog.test"
+        }
+        fn timestamp_now(&self) -> DateTime<Utc> {
+            self.now
+        }
+        fn signing_key(&self, _username: &str) -> UserSigningKey<'_> {
+            UserSigningKey {
+                id: "main-key",
+                key: &self.key,
             }
         }
-    } else {
-        tracing::info!("signature verification failed");
-        VerifiedRequest::VerifyFailed
+        fn integrity_key(&self, _username: &str) -> Option<Ed25519SigningKey> {
+            None
+        }
+    }
+
+    impl PublicKeyCache for TestState {
+        type Error = Infallible;
+        async fn get_public_key(&self, key_id: &str) -> Option<CachedPublicKey> {
+            self.key_cache.lock().unwrap().get(key_id).cloned()
+        }
+        async fn put_public_key(&self, key_id: &str, key: CachedPublicKey) -> Result<(), Self::Error> {
+            self.key_cache.lock().unwrap().insert(key_id.to_owned(), key);
+            Ok(())
+        }
+    }
+
+    impl HTTPClient for TestState {
+        type Error = Infallible;
+        async fn request(&self, request: Request<Bytes>) -> Result<Response<Body>, Self::Error> {
+            self.actor_fetch_count.fetch_add(1, Ordering::SeqCst);
+            match self.documents.get(&request.uri().to_string()) {
+                Some(document) => Ok(Response::new(Body::from(document.to_string()))),
+                None => Ok(Response::builder().status(404).body(Body::empty()).unwrap()),
+            }
+        }
+    }
+
+    fn signed_request(state: &TestState, date: DateTime<Utc>) -> Request<Bytes> {
+        signed_request_with_key_id(state, date, &format!("{ACTOR}#main-key"))
+    }
+
+    fn signed_request_with_key_id(state: &TestState, date: DateTime<Utc>, key_id: &str) -> Request<Bytes> {
+        let request = Request::post("https://blog.test/users/default/inbox")
+            .body(Bytes::from_static(br#"{"type":"Like"}"#))
+            .unwrap();
+        sign::sign(request, key_id, &state.key, date)
+    }
+
+    #[test]
+    fn fresh_request_is_verified() {
+        let state = TestState::new();
+        let request = signed_request(&state, state.now - TimeDelta::seconds(30));
+        let result = futures::executor::block_on(verify_request(&state, request.map(http_body_util::Full::new)));
+        assert!(matches!(result, VerifiedRequest::VerifiedDigest { actor, .. } if actor == ACTOR));
+    }
+
+    #[test]
+    fn stale_or_future_dated_request_is_rejected() {
+        let state = TestState::new();
+        for date in [state.now - TimeDelta::days(1), state.now + TimeDelta::hours(1)] {
+            let request = signed_request(&state, date);
+            let result = futures::executor::block_on(verify_request(&state, request.map(http_body_util::Full::new)));
+            assert!(matches!(result, VerifiedRequest::VerifyFailed));
+        }
+    }
+
+    #[test]
+    fn request_without_signed_digest_is_rejected() {
+        let state = TestState::new();
+        let mut request = signed_request(&state, state.now);
+        let signature = request.headers()["signature"].to_str().unwrap().replace(" digest\"", "\"");
+        request.headers_mut().insert("signature", signature.parse().unwrap());
+        let result = futures::executor::block_on(verify_request(&state, request.map(http_body_util::Full::new)));
+        assert!(matches!(result, VerifiedRequest::VerifyFailed));
+    }
+
+    #[test]
+    fn cached_key_is_reused_and_refetched_when_it_fails() {
+        let state = TestState::new();
+        let verify = |state: &TestState| {
+            let request = signed_request(state, state.now);
+            futures::executor::block_on(verify_request(state, request.map(http_body_util::Full::new)))
+        };
+
+        assert!(matches!(verify(&state), VerifiedRequest::VerifiedDigest { .. }));
+        assert!(matches!(verify(&state), VerifiedRequest::VerifiedDigest { .. }));
+        assert_eq!(state.actor_fetch_count.load(Ordering::SeqCst), 1);
+
+        state.key_cache.lock().unwrap().get_mut(&format!("{ACTOR}#main-key")).unwrap().pem = ROTATED_OUT_KEY.to_owned();
+        assert!(matches!(verify(&state), VerifiedRequest::VerifiedDigest { .. }));
+        assert_eq!(state.actor_fetch_count.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn standalone_key_is_resolved_through_its_owner() {
+        const OWNER: &str = "https://gts.test/users/bob";
+        const KEY_ID: &str = "https://gts.test/users/bob/main-key";
+        let mut state = TestState::new();
+        state.documents.insert(
+            KEY_ID.to_owned(),
+            serde_json::json!({ "id": KEY_ID, "type": "Key", "owner": OWNER, "publicKeyPem": PUBLIC_KEY }),
+        );
+        state.documents.insert(
+            OWNER.to_owned(),
+            serde_json::json!({
+                "id": OWNER,
+                "type": "Person",
+                "publicKey": [
+                    { "id": format!("{OWNER}#other-key"), "owner": OWNER, "publicKeyPem": ROTATED_OUT_KEY },
+                    { "id": KEY_ID, "owner": OWNER, "publicKeyPem": PUBLIC_KEY },
+                ],
+            }),
+        );
+
+        let request = signed_request_with_key_id(&state, state.now, KEY_ID);
+        let result = futures::executor::block_on(verify_request(&state, request.map(http_body_util::Full::new)));
+        assert!(matches!(result, VerifiedRequest::VerifiedDigest { actor, .. } if actor == OWNER));
+    }
+
+    #[test]
+    fn key_not_published_by_its_owner_cannot_verify() {
+        const KEY_ID: &str = "https://evil.test/keys/1";
+        let mut state = TestState::new();
+        state.documents.insert(
+            KEY_ID.to_owned(),
+            serde_json::json!({ "id": KEY_ID, "type": "Key", "owner": ACTOR, "publicKeyPem": PUBLIC_KEY }),
+        );
+
+        let request = signed_request_with_key_id(&state, state.now, KEY_ID);
+        let result = futures::executor::block_on(verify_request(&state, request.map(http_body_util::Full::new)));
+        assert!(matches!(result, VerifiedRequest::CannotVerify(_)));
+    }
+
+    #[test]
+    fn actor_hosted_elsewhere_cannot_verify() {
+        const DOCUMENT: &str = "https://evil.test/x";
+        let mut state = TestState::new();
+        state.documents.insert(
+            DOCUMENT.to_owned(),
+            serde_json::json!({
+                "id": ACTOR,
+                "type": "Person",
+                "publicKey": { "id": format!("{DOCUMENT}#main-key"), "owner": ACTOR, "publicKeyPem": PUBLIC_KEY },
+            }),
+        );
+
+        let request = signed_request_with_key_id(&state, state.now, &format!("{DOCUMENT}#main-key"));
+        let result = futures::executor::block_on(verify_request(&state, request.map(http_body_util::Full::new)));
+        assert!(matches!(result, VerifiedRequest::CannotVerify(_)));
+    }
+
+    #[test]
+    fn digest_headers_with_several_algorithms_are_parsed() {
+        let digests = parse_digest("SHA-256=X48E9qOokqqrvdts8nOJRJN3OWDUoyWxBf7kbu9DBPE=, MD5=HUXZLQLMuI/KZ5KDcJPcOA==, sha-512=AA==").unwrap();
+        assert_eq!(
+            digests.iter().map(|digest| digest.algorithm).collect::<Vec<_>>(),
+            [DigestAlgorithm::Sha256, DigestAlgorithm::Sha512]
+        );
+
+        let digests = parse_content_digest("sha-512=:AA==:, md5=:HUXZLQLMuI/KZ5KDcJPcOA==:").unwrap();
+        assert_eq!(digests.len(), 1);
+        assert_eq!(digests[0].algorithm, DigestAlgorithm::Sha512);
+        assert_eq!(digests[0].value, [0]);
+
+        assert!(parse_content_digest("sha-256=AA==").is_none());
+        assert!(parse_digest("SHA-256").is_none());
+    }
+
+    #[test]
+    fn every_listed_digest_has_to_match_the_body() {
+        let sha256 = "sha-256=:X48E9qOokqqrvdts8nOJRJN3OWDUoyWxBf7kbu9DBPE=:";
+        let sha512 = "sha-512=:WZDPaVn/7XgHaAy8pmojAkGWoRx2UFChF41A2svX+TaPm+AbwAgBWnrIiYllu7BNNyealdVLvRwEmTHWXvJwew==:";
+        let collect = |header: &str| {
+            let body = VerifyBody::new(
+                http_body_util::Full::new(Bytes::from_static(b"{\"hello\": \"world\"}")),
+                parse_content_digest(header).unwrap(),
+            );
+            futures::executor::block_on(body.collect_to_bytes()).unwrap().1
+        };
+
+        assert!(collect(sha256));
+        assert!(collect(sha512));
+        assert!(collect(&format!("{sha256}, {sha512}")));
+        assert!(!collect(&format!("{sha256}, sha-512=:AA==:")));
     }
 }
@@ -2,4 +2,4 @@ mod common;
 pub mod process_queue;
 pub mod route;
 pub mod traits;
-pub use common::verify;
+pub use common::{integrity, jsonld, ld_signature, verify};
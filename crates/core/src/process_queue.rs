@@ -1,11 +1,14 @@
 use crate::common::headers::{AP_ACCEPT, AP_RESPONSE_MIME};
 use crate::common::macros::json_format;
-use crate::common::{headers, sign};
-use crate::traits::{ArticleNewComment, ArticleNewReaction, ArticleProvider, Env, HTTPClient, Queue, QueueData, UserProvider};
+use crate::common::{actor, follow, headers, integrity, ld_signature, publication, sign};
+use crate::traits::{
+    ArticleNewComment, ArticleNewReaction, ArticleProvider, DeliveryLog, DeliveryScheduler, DeliverySlot, Env, Following, FollowingStore, HTTPClient,
+    InstanceAvailability, OutboundActivity, ProcessedActivityStore, PublicKeyCache, Queue, QueueData, Relay, RelayKind, RelayStore, UserProvider,
+};
 use axum::http::StatusCode;
 use axum::http::header::{ACCEPT, CONTENT_TYPE};
 use bytes::Bytes;
-use chrono::{DateTime, FixedOffset};
+use chrono::{DateTime, FixedOffset, TimeDelta, Utc};
 use futures::TryStreamExt;
 use http_body_util::{BodyExt, Limited};
 use mime::Mime;
@@ -15,6 +18,17 @@ use std::future;
 use std::str::FromStr;
 use url::Url;
 
+/// How long processed activity IDs are remembered, remote servers give up retrying well before this.
+const PROCESSED_ACTIVITY_TTL: TimeDelta = TimeDelta::days(7);
+/// How long successful deliveries are remembered so that retried fan-outs skip their inboxes.
+const DELIVERED_ACTIVITY_TTL: TimeDelta = TimeDelta::days(30);
+/// Hosts are skipped by fan-outs after deliveries failed on this many days, as Mastodon does.
+const UNAVAILABLE_AFTER_FAILURE_DAYS: u32 = 7;
+/// How long an unavailable host is skipped before the next fan-out probes it again.
+const UNAVAILABLE_HOST_PROBE_INTERVAL: TimeDelta = TimeDelta::days(1);
+/// Followers on a host are removed once deliveries to it have failed for this long.
+const DEAD_HOST_FOLLOWER_REMOVAL: TimeDelta = TimeDelta::days(30);
+
 #[derive(Debug, thiserror::Error)]
 pub enum ProcessQueueError<RequestError> {
     #[error("{0}")]
@@ -30,7 +44,22 @@ pub enum ProcessQueueResult {
 #[tracing::instrument(skip(state))]
 pub async fn process_queue<E>(state: &E, data: QueueData) -> ProcessQueueResult
 where
-    E: Env + ArticleProvider + UserProvider + HTTPClient + Queue + Send + Sync + Clone + 'static,
+    E: Env
+        + ArticleProvider
+        + UserProvider
+        + HTTPClient
+        + PublicKeyCache
+        + ProcessedActivityStore
+        + InstanceAvailability
+        + DeliveryScheduler
+        + DeliveryLog
+        + RelayStore
+        + FollowingStore
+        + Queue
+        + Send
+        + Sync
+        + Clone
+        + 'static,
 {
     tracing::info!("process queue: {:?}", data);
     match data {
@@ -40,209 +69,311 @@ where
             id,
             verified_body,
             verified_actor,
+            unverified_body,
         } => {
-            let body_raw = if let Some(body) = verified_body {
-                body
-            } else {
-                let Ok(b) = get_ap_data_raw(&id, state).await else {
-                    return ProcessQueueResult::Finished;
-                };
-                String::from_utf8_lossy(&b).into_owned()
-            };
-            let body = match serde_json::from_str(&body_raw) {
-                Ok(b) => b,
-                Err(e) => {
-                    tracing::warn!("failed to deserialize body: {e} raw={}", body_raw);
-                    return ProcessQueueResult::Finished;
-                }
+            let (body_raw, verified_actor) = match verified_body {
+                Some(body_raw) => (Some(body_raw), verified_actor),
+                None => (unverified_body, None),
             };
-            tracing::info!("body: {:?}", body);
-            match body {
-                ResponseBody::Create {
-                    object:
-                        NoteObject {
-                            id,
-                            attributed_to,
-                            published,
-                            content,
-                            reply_target,
-                        },
-                } => {
-                    let reply_target = reply_target.into_string();
-                    let slug = match reply_target.strip_prefix(&format!("{}/articles/", state.url())) {
-                        Some(slug) => slug.trim_matches('/'),
-                        None => {
-                            tracing::warn!("invalid reply target: {reply_target}");
-                            return ProcessQueueResult::Finished;
-                        }
-                    };
-                    if state.get_author_id(slug).await.is_none_or(|author| author != username) {
-                        tracing::info!(slug, username, "author mismatch");
+            let verified = match body_raw {
+                Some(body_raw) => {
+                    let Some(body) = parse_body(&body_raw) else {
                         return ProcessQueueResult::Finished;
-                    }
-                    let comment = ArticleNewComment {
-                        id,
-                        author_id: attributed_to,
-                        created_at: published.into(),
-                        proceed_at: state.timestamp_now(),
-                        content,
-                        raw: body_raw,
                     };
-                    tracing::info!("comment_data: {comment:#?}");
-                    state.add_comment(slug, comment).await;
-                    return ProcessQueueResult::Finished;
-                }
-                ResponseBody::Like { id, actor, object, content } => {
-                    let slug = match object.strip_prefix(&format!("{}/articles/", state.url())) {
-                        Some(slug) => slug.trim_matches('/'),
+                    let signer = match verified_actor.filter(|verified_actor| body.is_authored_by(verified_actor)) {
+                        Some(verified_actor) => Some(verified_actor),
+                        None => match integrity::verify_proof(state, &body_raw)
+                            .await
+                            .filter(|controller| body.is_authored_by(controller))
+                        {
+                            Some(controller) => Some(controller),
+                            None => ld_signature::verify_signature(state, &body_raw)
+                                .await
+                                .filter(|creator| body.is_authored_by(creator)),
+                        },
+                    };
+                    match signer {
+                        Some(signer) => Some((body_raw, body, Some(signer))),
                         None => {
-                            tracing::warn!("invalid reaction target: {object}");
-                            return ProcessQueueResult::Finished;
+                            tracing::warn!("activity is not authenticated for its author, fetch it from its origin");
+                            None
                         }
-                    };
-                    if state.get_author_id(slug).await.is_none_or(|author| author != username) {
-                        tracing::info!(slug, username, "author mismatch");
-                        return ProcessQueueResult::Finished;
                     }
-                    let reaction = ArticleNewReaction {
-                        id,
-                        author_id: actor,
-                        proceed_at: state.timestamp_now(),
-                        reaction: content,
-                        raw: body_raw,
-                    };
-                    tracing::info!("reaction_data: {reaction:#?}");
-                    state.add_reaction(slug, reaction).await;
-                    return ProcessQueueResult::Finished;
                 }
-                ResponseBody::Follow { id, actor, object } => {
-                    let url = state.url();
-                    if object != format!("{}/users/{username}", url) {
-                        tracing::warn!(object, "invalid follow target");
+                None => None,
+            };
+            let (body_raw, body, verified_actor) = match verified {
+                Some(verified) => verified,
+                None => {
+                    let Ok(b) = get_ap_data_raw(&id, state).await else {
                         return ProcessQueueResult::Finished;
-                    }
-                    #[derive(Debug, Deserialize)]
-                    struct Person {
-                        #[allow(dead_code)]
-                        #[serde(rename = "id")]
-                        _id: String,
-                        #[serde(rename = "type")]
-                        ty: String,
-                        inbox: String,
-                        #[serde(rename = "sharedInbox")]
-                        shared_inbox: Option<String>,
-                    }
-                    let Ok(user): Result<Person, _> = get_ap_data(&actor, state).await else {
+                    };
+                    let body_raw = String::from_utf8_lossy(&b).into_owned();
+                    let Some(body) = parse_body(&body_raw) else {
                         return ProcessQueueResult::Finished;
                     };
-                    tracing::info!("body: {:?}", user);
-                    if user.ty != "Person" {
-                        tracing::warn!("invalid actor type");
+                    if !body.is_authored_by(&id) {
+                        tracing::warn!(id, "activity is not authored by its origin");
                         return ProcessQueueResult::Finished;
                     }
-                    state
-                        .add_follower(&username, &actor, &user.shared_inbox.unwrap_or_else(|| user.inbox.clone()), &id)
-                        .await;
-                    let follow_actor = serde_json::to_string(&actor).unwrap();
-                    let accept_actor = serde_json::to_string(&format!("{url}/users/{username}")).unwrap();
-                    let object = serde_json::to_string(&id).unwrap();
-                    let url = state.url();
-                    let url = Url::parse_with_params(&format!("{url}/users/{username}/accept_follow"), [("object", &object)]).unwrap();
-                    let url = serde_json::to_string(&url.to_string()).unwrap();
-                    tracing::info!("url: {}", url);
-                    let inbox = &user.inbox;
-                    tracing::info!("inbox: {}", inbox);
-                    let string = json_format! {
-                        "@context": "https://www.w3.org/ns/activitystreams",
-                        "id": url,
-                        "type": "Accept",
-                        "actor": accept_actor,
-                        "object": {
-                            "type": "Follow",
-                            "actor": follow_actor,
-                            "object": accept_actor,
-                        },
-                    };
-                    tracing::info!("string: {}", string);
-                    let Ok(request) = axum::http::Request::post(inbox)
-                        .header(ACCEPT, AP_ACCEPT)
-                        .header(CONTENT_TYPE, AP_RESPONSE_MIME)
-                        .body(Bytes::from(string))
-                    else {
-                        tracing::warn!("failed to create post request");
+                    (body_raw, body, None)
+                }
+            };
+            tracing::info!("body: {:?}", body);
+            // an ID on another origin could be used to suppress the genuine activity, so only those of the author are recorded,
+            // and per inbox since an activity addressed to several local users is processed once for each of them
+            let recorded = body.is_authored_by(&id);
+            if recorded {
+                match state.is_activity_processed(&username, &id).await {
+                    Ok(true) => {
+                        tracing::info!(id, "activity is already processed");
                         return ProcessQueueResult::Finished;
-                    };
-                    let url = state.url();
-                    let now = state.timestamp_now();
-                    let key = state.signing_key();
-                    let request = sign::sign(request, &format!("{url}/users/{username}#main-key"), key, now);
-                    tracing::info!("request: {:?}", request);
-                    let response = match state.request(request).await {
-                        Ok(response) => response,
-                        Err(e) => {
-                            tracing::warn!("failed to fetch by: {:?}", e);
-                            return ProcessQueueResult::Finished;
-                        }
-                    };
-                    if !response.status().is_success() {
-                        tracing::warn!("failed to post: {:?}", response);
-                        let response_body = Limited::new(response.into_body(), 1024 * 64)
-                            .into_data_stream()
-                            .try_fold(Vec::new(), |mut acc, bytes| {
-                                acc.extend_from_slice(&bytes);
-                                future::ready(Ok(acc))
-                            })
-                            .await;
-                        tracing::warn!("response: {:?}", response_body.map(|body| String::from_utf8_lossy(&body).into_owned()));
                     }
-                    return ProcessQueueResult::Finished;
+                    Ok(false) => {}
+                    Err(e) => {
+                        tracing::error!(error = ?e, "failed to check processed activity");
+                        return ProcessQueueResult::Retry;
+                    }
                 }
-                ResponseBody::Undo { actor: undo_actor, object } => match *object {
-                    ResponseBody::Like {
-                        id: _,
-                        actor,
-                        object,
-                        content: _,
+            }
+            // recorded only once processed, so that a retry or a job cut short by a crash processes it again
+            let result = async {
+                match body {
+                    ResponseBody::Create {
+                        actor: _,
+                        object:
+                            NoteObject {
+                                id,
+                                attributed_to,
+                                published,
+                                content,
+                                reply_target,
+                            },
                     } => {
-                        if verified_actor
-                            .as_ref()
-                            .is_none_or(|verified_actor| verified_actor != &undo_actor || undo_actor != actor)
-                        {
-                            tracing::info!(?verified_actor, undo_actor, actor, "undo actor is not authorized");
+                        let reply_target = reply_target.into_string();
+                        let slug = match reply_target.strip_prefix(&format!("{}/articles/", state.url())) {
+                            Some(slug) => slug.trim_matches('/'),
+                            None => {
+                                tracing::warn!("invalid reply target: {reply_target}");
+                                return ProcessQueueResult::Finished;
+                            }
+                        };
+                        if state.get_author_id(slug).await.is_none_or(|author| author != username) {
+                            tracing::info!(slug, username, "author mismatch");
                             return ProcessQueueResult::Finished;
                         }
-                        let Some(slug) = object.strip_prefix(&format!("{}/articles/", state.url())).map(|s| s.trim_matches('/')) else {
-                            tracing::warn!(object, "invalid reaction target");
-                            return ProcessQueueResult::Finished;
+                        let comment = ArticleNewComment {
+                            id,
+                            author_id: attributed_to,
+                            created_at: published.into(),
+                            proceed_at: state.timestamp_now(),
+                            content,
+                            raw: body_raw,
+                        };
+                        tracing::info!("comment_data: {comment:#?}");
+                        if let Err(e) = state.add_comment(slug, comment).await {
+                            tracing::error!(error = ?e, "failed to store comment");
+                            return ProcessQueueResult::Retry;
+                        }
+                        ProcessQueueResult::Finished
+                    }
+                    ResponseBody::Like { id, actor, object, content } => {
+                        let slug = match object.strip_prefix(&format!("{}/articles/", state.url())) {
+                            Some(slug) => slug.trim_matches('/'),
+                            None => {
+                                tracing::warn!("invalid reaction target: {object}");
+                                return ProcessQueueResult::Finished;
+                            }
                         };
                         if state.get_author_id(slug).await.is_none_or(|author| author != username) {
                             tracing::info!(slug, username, "author mismatch");
                             return ProcessQueueResult::Finished;
                         }
-                        state.remove_reaction_by(slug, &actor).await;
-                        return ProcessQueueResult::Finished;
+                        let reaction = ArticleNewReaction {
+                            id,
+                            author_id: actor,
+                            proceed_at: state.timestamp_now(),
+                            reaction: content,
+                            raw: body_raw,
+                        };
+                        tracing::info!("reaction_data: {reaction:#?}");
+                        if let Err(e) = state.add_reaction(slug, reaction).await {
+                            tracing::error!(error = ?e, "failed to store reaction");
+                            return ProcessQueueResult::Retry;
+                        }
+                        ProcessQueueResult::Finished
                     }
-                    ResponseBody::Follow { id: _, actor, object } => {
-                        if verified_actor
-                            .as_ref()
-                            .is_none_or(|verified_actor| verified_actor != &undo_actor || undo_actor != actor)
-                        {
-                            tracing::info!(?verified_actor, undo_actor, actor, "undo actor is not authorized");
+                    ResponseBody::Follow { id, actor, object } => {
+                        let url = state.url();
+                        if object != format!("{}/users/{username}", url) {
+                            tracing::warn!(object, "invalid follow target");
                             return ProcessQueueResult::Finished;
                         }
-                        if object != format!("{}/users/{username}", state.url()) {
-                            tracing::warn!(object, username, "invalid unfollow target");
+                        #[derive(Debug, Deserialize)]
+                        struct Person {
+                            #[allow(dead_code)]
+                            #[serde(rename = "id")]
+                            _id: String,
+                            #[serde(rename = "type")]
+                            ty: String,
+                            inbox: String,
+                            #[serde(rename = "sharedInbox")]
+                            shared_inbox: Option<String>,
+                        }
+                        let Ok(user): Result<Person, _> = get_ap_data(&actor, state).await else {
+                            return ProcessQueueResult::Finished;
+                        };
+                        tracing::info!("body: {:?}", user);
+                        if user.ty != "Person" {
+                            tracing::warn!("invalid actor type");
                             return ProcessQueueResult::Finished;
                         }
-                        state.remove_follower_by_actor(&username, &actor).await;
-                        return ProcessQueueResult::Finished;
+                        if let Err(e) = state
+                            .add_follower(&username, &actor, &user.shared_inbox.unwrap_or_else(|| user.inbox.clone()), &id)
+                            .await
+                        {
+                            tracing::error!(error = ?e, "failed to store follower");
+                            return ProcessQueueResult::Retry;
+                        }
+                        let (accept_id, body) = follow::accept_activity(state, &username, &actor, &id);
+                        // the accept is retried on its own, the follower is already stored
+                        if let Err(e) = state
+                            .enqueue(QueueData::DeliveryActivity {
+                                author: username.clone(),
+                                inbox: user.inbox,
+                                activity: OutboundActivity::Signed { id: accept_id, body },
+                                attempt: 0,
+                                first_attempt_at: None,
+                            })
+                            .await
+                        {
+                            tracing::error!(error = ?e, "failed to enqueue accept");
+                            // the follow is processed again, so the follower is stored again
+                            if let Err(e) = state.remove_follower(&username, &id).await {
+                                tracing::error!(error = ?e, "failed to remove follower");
+                            }
+                            return ProcessQueueResult::Retry;
+                        }
+                        ProcessQueueResult::Finished
                     }
-                    object => {
-                        tracing::warn!(object = ?object, "invalid undo target");
-                        return ProcessQueueResult::Finished;
+                    ResponseBody::Accept { actor, object } => {
+                        if verified_actor.as_ref().is_none_or(|verified_actor| verified_actor != &actor) {
+                            tracing::info!(?verified_actor, actor, "accept actor is not authorized");
+                            return ProcessQueueResult::Finished;
+                        }
+                        let now = state.timestamp_now();
+                        match state.accept_following(&actor, object.id(), now).await {
+                            Ok(true) => {
+                                tracing::info!(actor, "follow is accepted");
+                                return ProcessQueueResult::Finished;
+                            }
+                            Ok(false) => {}
+                            Err(e) => {
+                                tracing::error!(error = ?e, "failed to accept following");
+                                return ProcessQueueResult::Retry;
+                            }
+                        }
+                        match state.accept_relay(&actor, object.id(), now).await {
+                            Ok(true) => tracing::info!(actor, "relay accepted the follow"),
+                            Ok(false) => tracing::info!(actor, follow_id = object.id(), "accept of an unknown follow"),
+                            Err(e) => {
+                                tracing::error!(error = ?e, "failed to accept relay");
+                                return ProcessQueueResult::Retry;
+                            }
+                        }
+                        ProcessQueueResult::Finished
                     }
-                },
+                    ResponseBody::Reject { actor, object } => {
+                        if verified_actor.as_ref().is_none_or(|verified_actor| verified_actor != &actor) {
+                            tracing::info!(?verified_actor, actor, "reject actor is not authorized");
+                            return ProcessQueueResult::Finished;
+                        }
+                        match state.reject_following(&actor, object.id()).await {
+                            Ok(true) => {
+                                tracing::info!(actor, "follow is rejected");
+                                return ProcessQueueResult::Finished;
+                            }
+                            Ok(false) => {}
+                            Err(e) => {
+                                tracing::error!(error = ?e, "failed to reject following");
+                                return ProcessQueueResult::Retry;
+                            }
+                        }
+                        if state.get_relay(&actor).await.is_some_and(|relay| relay.follow_id == object.id()) {
+                            tracing::info!(actor, "relay rejected the follow");
+                            if let Err(e) = state.remove_relay(&actor).await {
+                                tracing::error!(error = ?e, "failed to remove relay");
+                                return ProcessQueueResult::Retry;
+                            }
+                            return ProcessQueueResult::Finished;
+                        }
+                        tracing::info!(actor, follow_id = object.id(), "reject of an unknown follow");
+                        ProcessQueueResult::Finished
+                    }
+                    ResponseBody::Undo { actor: undo_actor, object } => match *object {
+                        ResponseBody::Like {
+                            id: _,
+                            actor,
+                            object,
+                            content: _,
+                        } => {
+                            if verified_actor
+                                .as_ref()
+                                .is_none_or(|verified_actor| verified_actor != &undo_actor || undo_actor != actor)
+                            {
+                                tracing::info!(?verified_actor, undo_actor, actor, "undo actor is not authorized");
+                                return ProcessQueueResult::Finished;
+                            }
+                            let Some(slug) = object.strip_prefix(&format!("{}/articles/", state.url())).map(|s| s.trim_matches('/')) else {
+                                tracing::warn!(object, "invalid reaction target");
+                                return ProcessQueueResult::Finished;
+                            };
+                            if state.get_author_id(slug).await.is_none_or(|author| author != username) {
+                                tracing::info!(slug, username, "author mismatch");
+                                return ProcessQueueResult::Finished;
+                            }
+                            if let Err(e) = state.remove_reaction_by(slug, &actor).await {
+                                tracing::error!(error = ?e, "failed to remove reaction");
+                                return ProcessQueueResult::Retry;
+                            }
+                            ProcessQueueResult::Finished
+                        }
+                        ResponseBody::Follow { id: _, actor, object } => {
+                            if verified_actor
+                                .as_ref()
+                                .is_none_or(|verified_actor| verified_actor != &undo_actor || undo_actor != actor)
+                            {
+                                tracing::info!(?verified_actor, undo_actor, actor, "undo actor is not authorized");
+                                return ProcessQueueResult::Finished;
+                            }
+                            if object != format!("{}/users/{username}", state.url()) {
+                                tracing::warn!(object, username, "invalid unfollow target");
+                                return ProcessQueueResult::Finished;
+                            }
+                            if let Err(e) = state.remove_follower_by_actor(&username, &actor).await {
+                                tracing::error!(error = ?e, "failed to remove follower");
+                                return ProcessQueueResult::Retry;
+                            }
+                            ProcessQueueResult::Finished
+                        }
+                        object => {
+                            tracing::warn!(object = ?object, "invalid undo target");
+                            ProcessQueueResult::Finished
+                        }
+                    },
+                }
+            }
+            .await;
+            if recorded
+                && matches!(result, ProcessQueueResult::Finished)
+                && let Err(e) = state
+                    .mark_activity_processed(&username, &id, state.timestamp_now() + PROCESSED_ACTIVITY_TTL)
+                    .await
+            {
+                // the activity is stored already, a retry would store it twice
+                tracing::error!(error = ?e, id, "failed to record processed activity");
             }
+            return result;
         }
         QueueData::DeliveryNewArticleToAll { slug } => {
             let author = match state.get_author_id(&slug).await {
@@ -252,14 +383,22 @@ where
                     return ProcessQueueResult::Finished;
                 }
             };
-            state
-                .enqueue(QueueData::DeliveryNewArticleBatch {
-                    slug: slug.clone(),
-                    author,
-                    last_inbox: String::new(),
-                })
-                .await;
-            return ProcessQueueResult::Finished;
+            if let Some(published) = publication::published_at(state, &slug).await
+                && published > state.timestamp_now()
+            {
+                tracing::info!(%published, "rescheduling delivery of an article that is not published yet");
+                if let Err(e) = state
+                    .enqueue_with_delay(QueueData::DeliveryNewArticleToAll { slug }, published - state.timestamp_now())
+                    .await
+                {
+                    tracing::error!(error = ?e, "failed to reschedule delivery");
+                    return ProcessQueueResult::Retry;
+                }
+                return ProcessQueueResult::Finished;
+            }
+            // create and delete activities have one id per slug, so an article published again has to reach every inbox again
+            state.forget_deliveries(&format!("{}/events/articles/create/{slug}", state.url())).await;
+            return enqueue_fan_out(state, author, OutboundActivity::CreateArticle { slug }).await;
         }
         QueueData::DeliveryUpdateArticleToAll { slug } => {
             let author = match state.get_author_id(&slug).await {
@@ -269,255 +408,278 @@ where
                     return ProcessQueueResult::Finished;
                 }
             };
-            state
-                .enqueue(QueueData::DeliveryUpdateArticleBatch {
-                    slug: slug.clone(),
-                    author,
-                    last_inbox: String::new(),
-                })
-                .await;
-            return ProcessQueueResult::Finished;
+            if publication::is_scheduled(state, &slug).await {
+                tracing::info!("skipping update of an article that is not published yet, its create carries the latest version");
+                return ProcessQueueResult::Finished;
+            }
+            // update activities reuse their id, so a new fan-out has to reach inboxes that got the previous one
+            state.forget_deliveries(&format!("{}/events/articles/update/{slug}", state.url())).await;
+            return enqueue_fan_out(state, author, OutboundActivity::UpdateArticle { slug }).await;
         }
         QueueData::DeliveryDeleteArticleToAll { slug, author } => {
-            state
-                .enqueue(QueueData::DeliveryDeleteArticleBatch {
-                    slug: slug.clone(),
-                    author,
+            state.forget_deliveries(&format!("{}/events/articles/delete/{slug}", state.url())).await;
+            return enqueue_fan_out(state, author, OutboundActivity::DeleteArticle { slug }).await;
+        }
+        QueueData::DeliveryUpdateUserToAll { username } => {
+            let Some(activity) = person_update(state, &username).await else {
+                return ProcessQueueResult::Finished;
+            };
+            if let Err(e) = state
+                .enqueue(QueueData::DeliveryActivityBatch {
+                    author: username,
+                    activity,
                     last_inbox: String::new(),
+                    then: None,
                 })
-                .await;
-            return ProcessQueueResult::Finished;
-        }
-        QueueData::DeliveryNewArticleBatch { slug, author, last_inbox } => {
-            let (inboxes, next_last) = state.get_followers_inbox_batch(&author, &last_inbox).await;
-            if inboxes.is_full() {
-                state
-                    .enqueue(QueueData::DeliveryNewArticleBatch {
-                        slug: slug.clone(),
-                        author: author.clone(),
-                        last_inbox: next_last,
-                    })
-                    .await;
-            }
-            for inbox in inboxes.into_iter() {
-                state
-                    .enqueue(QueueData::DeliveryNewArticle {
-                        slug: slug.clone(),
-                        author: author.clone(),
-                        inbox,
-                    })
-                    .await;
+                .await
+            {
+                tracing::error!(error = ?e, "failed to enqueue delivery batch");
+                return ProcessQueueResult::Retry;
             }
             return ProcessQueueResult::Finished;
         }
-        QueueData::DeliveryUpdateArticleBatch { slug, author, last_inbox } => {
-            let (inboxes, next_last) = state.get_followers_inbox_batch(&author, &last_inbox).await;
-            if inboxes.is_full() {
-                state
-                    .enqueue(QueueData::DeliveryUpdateArticleBatch {
-                        slug: slug.clone(),
-                        author: author.clone(),
-                        last_inbox: next_last,
-                    })
-                    .await;
-            }
-            for inbox in inboxes.into_iter() {
-                state
-                    .enqueue(QueueData::DeliveryUpdateArticle {
-                        slug: slug.clone(),
-                        author: author.clone(),
-                        inbox,
-                    })
-                    .await;
-            }
-            return ProcessQueueResult::Finished;
-        }
-        QueueData::DeliveryDeleteArticleBatch { slug, author, last_inbox } => {
-            let (inboxes, next_last) = state.get_followers_inbox_batch(&author, &last_inbox).await;
-            if inboxes.is_full() {
-                state
-                    .enqueue(QueueData::DeliveryDeleteArticleBatch {
-                        slug: slug.clone(),
-                        author: author.clone(),
-                        last_inbox: next_last,
-                    })
-                    .await;
-            }
-            for inbox in inboxes.into_iter() {
-                state
-                    .enqueue(QueueData::DeliveryDeleteArticle {
-                        slug: slug.clone(),
-                        author: author.clone(),
-                        inbox,
-                    })
-                    .await;
+        QueueData::DeliveryDeleteUserToAll { username } => {
+            if state.exists_user(&username).await {
+                tracing::info!(username, "skipping deletion of a user that exists");
+                return ProcessQueueResult::Finished;
             }
-            return ProcessQueueResult::Finished;
-        }
-        QueueData::DeliveryNewArticle { slug, author, inbox } => {
             let url = state.url();
-            let actor = serde_json::to_string(&format!("{url}/users/{author}")).unwrap();
-            let id = serde_json::to_string(&format!("{url}/events/articles/create/{slug}")).unwrap();
-            let object = serde_json::to_string(&format!("{url}/articles/{slug}")).unwrap();
+            let actor_id = format!("{url}/users/{username}");
+            // a user deleted again after re-registering must not be skipped as already delivered
+            let activity_id = format!("{actor_id}#delete/{}", state.timestamp_now().timestamp_millis());
+            let actor = serde_json::to_string(&actor_id).unwrap();
+            let id = serde_json::to_string(&activity_id).unwrap();
             let body = json_format! {
                 "@context": "https://www.w3.org/ns/activitystreams",
                 "id": id,
-                "type": "Create",
+                "type": "Delete",
                 "actor": actor,
-                "object": object,
+                "object": actor,
+                "to": ["https://www.w3.org/ns/activitystreams#Public"],
             };
-            tracing::info!("body: {}", body);
-            let request = axum::http::Request::post(inbox)
-                .header(ACCEPT, AP_ACCEPT)
-                .header(CONTENT_TYPE, AP_RESPONSE_MIME)
-                .body(Bytes::from(body))
-                .unwrap();
-            let request = sign::sign(
-                request,
-                &format!("{url}/users/{author}#main-key"),
-                state.signing_key(),
-                state.timestamp_now(),
-            );
-            tracing::info!("request: {:?}", request);
-            match state.request(request).await {
-                Ok(response) => {
-                    tracing::info!("response: {:?}", response);
-                    if response.status().is_success() {
-                        tracing::info!("posted");
-                        return ProcessQueueResult::Finished;
-                    } else {
-                        tracing::warn!("failed to post");
-                        let (parts, body) = response.into_parts();
-                        let response_body = Limited::new(body, 1024 * 64)
-                            .into_data_stream()
-                            .try_fold(Vec::new(), |mut acc, bytes| {
-                                acc.extend_from_slice(&bytes);
-                                future::ready(Ok(acc))
-                            })
-                            .await;
-                        tracing::warn!("response: {:?}", response_body.map(|body| String::from_utf8_lossy(&body).into_owned()));
-                        return match parts.status {
-                            StatusCode::GONE => ProcessQueueResult::Finished,
-                            status if (400..500).contains(&status.as_u16()) => ProcessQueueResult::Finished,
-                            _ => ProcessQueueResult::Retry,
-                        };
-                    }
-                }
-                Err(e) => {
-                    tracing::error!("failed to post: {:?}", e);
+            let activity = OutboundActivity::Signed {
+                id: activity_id,
+                body: integrity::sign_activity(state, &username, body),
+            };
+            // remotes refetching the actor on the first deliveries already get the tombstone
+            if let Err(e) = state.mark_user_deleted(&username, state.timestamp_now()).await {
+                tracing::error!(error = ?e, "failed to mark user deleted");
+                return ProcessQueueResult::Retry;
+            }
+            // the followers are read by the fan-out, so they are removed after its last batch
+            if let Err(e) = state
+                .enqueue(QueueData::DeliveryActivityBatch {
+                    author: username.clone(),
+                    activity,
+                    last_inbox: String::new(),
+                    then: Some(Box::new(QueueData::RemoveUserFollowers { username: username.clone() })),
+                })
+                .await
+            {
+                tracing::error!(error = ?e, "failed to enqueue delivery batch");
+                return ProcessQueueResult::Retry;
+            }
+            return ProcessQueueResult::Finished;
+        }
+        QueueData::RemoveUserFollowers { username } => {
+            if let Err(e) = state.remove_followers_of(&username).await {
+                tracing::error!(error = ?e, "failed to remove followers");
+                return ProcessQueueResult::Retry;
+            }
+            return ProcessQueueResult::Finished;
+        }
+        QueueData::SubscribeRelay { username, actor, kind } => {
+            let relay: RemoteActor = match get_ap_data(&actor, state).await {
+                Ok(relay) => relay,
+                Err(FetchError::Temporary) => return ProcessQueueResult::Retry,
+                Err(FetchError::Permanent) => {
+                    tracing::error!(actor, "relay actor cannot be fetched");
                     return ProcessQueueResult::Finished;
                 }
+            };
+            let actor_id = format!("{}/users/{username}", state.url());
+            let follow_id = format!("{actor_id}#follows/{}", state.timestamp_now().timestamp_millis());
+            let body = relay_follow(&follow_id, &actor_id, &actor, kind);
+            let body = integrity::sign_activity(state, &username, body);
+            let relay = Relay {
+                actor,
+                inbox: relay.inbox,
+                kind,
+                username,
+                follow_id,
+                accepted_at: None,
+            };
+            if let Err(e) = state.add_relay(&relay).await {
+                tracing::error!(error = ?e, "failed to store relay");
+                return ProcessQueueResult::Retry;
+            }
+            if let Err(e) = state
+                .enqueue(QueueData::DeliveryActivity {
+                    author: relay.username,
+                    inbox: relay.inbox,
+                    activity: OutboundActivity::Signed { id: relay.follow_id, body },
+                    attempt: 0,
+                    first_attempt_at: None,
+                })
+                .await
+            {
+                tracing::error!(error = ?e, "failed to enqueue relay follow");
+                return ProcessQueueResult::Retry;
             }
+            return ProcessQueueResult::Finished;
         }
-        QueueData::DeliveryUpdateArticle { slug, author, inbox } => {
-            let url = state.url();
-            let actor = serde_json::to_string(&format!("{url}/users/{author}")).unwrap();
-            let id = serde_json::to_string(&format!("{url}/events/articles/update/{slug}")).unwrap();
-            let object = serde_json::to_string(&format!("{url}/articles/{slug}")).unwrap();
-            let body = json_format! {
-                "@context": "https://www.w3.org/ns/activitystreams",
-                "id": id,
-                "type": "Update",
-                "actor": actor,
-                "object": object,
+        QueueData::UnsubscribeRelay { actor } => {
+            let Some(relay) = state.get_relay(&actor).await else {
+                tracing::info!(actor, "relay is not subscribed");
+                return ProcessQueueResult::Finished;
             };
-            tracing::info!("body: {}", body);
-            let request = axum::http::Request::post(inbox)
-                .header(ACCEPT, AP_ACCEPT)
-                .header(CONTENT_TYPE, AP_RESPONSE_MIME)
-                .body(Bytes::from(body))
-                .unwrap();
-            let request = sign::sign(
-                request,
-                &format!("{url}/users/{author}#main-key"),
-                state.signing_key(),
-                state.timestamp_now(),
-            );
-            tracing::info!("request: {:?}", request);
-            match state.request(request).await {
-                Ok(response) => {
-                    tracing::info!("response: {:?}", response);
-                    if response.status().is_success() {
-                        tracing::info!("posted");
-                        return ProcessQueueResult::Finished;
-                    } else {
-                        tracing::warn!("failed to post");
-                        let (parts, body) = response.into_parts();
-                        let response_body = Limited::new(body, 1024 * 64)
-                            .into_data_stream()
-                            .try_fold(Vec::new(), |mut acc, bytes| {
-                                acc.extend_from_slice(&bytes);
-                                future::ready(Ok(acc))
-                            })
-                            .await;
-                        tracing::warn!("response: {:?}", response_body.map(|body| String::from_utf8_lossy(&body).into_owned()));
-                        return match parts.status {
-                            StatusCode::GONE => ProcessQueueResult::Finished,
-                            status if (400..500).contains(&status.as_u16()) => ProcessQueueResult::Finished,
-                            _ => ProcessQueueResult::Retry,
-                        };
-                    }
-                }
-                Err(e) => {
-                    tracing::error!("failed to post: {:?}", e);
+            let actor_id = format!("{}/users/{}", state.url(), relay.username);
+            let follow = relay_follow(&relay.follow_id, &actor_id, &relay.actor, relay.kind);
+            let (activity_id, body) = undo_activity(state, &relay.username, follow);
+            if let Err(e) = state
+                .enqueue(QueueData::DeliveryActivity {
+                    author: relay.username,
+                    inbox: relay.inbox,
+                    activity: OutboundActivity::Signed { id: activity_id, body },
+                    attempt: 0,
+                    first_attempt_at: None,
+                })
+                .await
+            {
+                tracing::error!(error = ?e, "failed to enqueue relay undo");
+                return ProcessQueueResult::Retry;
+            }
+            if let Err(e) = state.remove_relay(&actor).await {
+                tracing::error!(error = ?e, "failed to remove relay");
+                return ProcessQueueResult::Retry;
+            }
+            return ProcessQueueResult::Finished;
+        }
+        QueueData::FollowAccount { username, actor } => {
+            if !state.exists_user(&username).await {
+                tracing::warn!(username, "user not found");
+                return ProcessQueueResult::Finished;
+            }
+            let remote: RemoteActor = match get_ap_data(&actor, state).await {
+                Ok(remote) => remote,
+                Err(FetchError::Temporary) => return ProcessQueueResult::Retry,
+                Err(FetchError::Permanent) => {
+                    tracing::error!(actor, "followed actor cannot be fetched");
                     return ProcessQueueResult::Finished;
                 }
+            };
+            let actor_id = format!("{}/users/{username}", state.url());
+            let follow_id = format!("{actor_id}#follows/{}", state.timestamp_now().timestamp_millis());
+            let body = follow_activity(&follow_id, &actor_id, &actor);
+            let body = integrity::sign_activity(state, &username, body);
+            let following = Following {
+                username,
+                actor,
+                inbox: remote.inbox,
+                follow_id,
+                accepted_at: None,
+            };
+            if let Err(e) = state.add_following(&following).await {
+                tracing::error!(error = ?e, "failed to store following");
+                return ProcessQueueResult::Retry;
             }
+            if let Err(e) = state
+                .enqueue(QueueData::DeliveryActivity {
+                    author: following.username,
+                    inbox: following.inbox,
+                    activity: OutboundActivity::Signed {
+                        id: following.follow_id,
+                        body,
+                    },
+                    attempt: 0,
+                    first_attempt_at: None,
+                })
+                .await
+            {
+                tracing::error!(error = ?e, "failed to enqueue follow");
+                return ProcessQueueResult::Retry;
+            }
+            return ProcessQueueResult::Finished;
         }
-        QueueData::DeliveryDeleteArticle { slug, author, inbox } => {
-            let url = state.url();
-            let actor = serde_json::to_string(&format!("{url}/users/{author}")).unwrap();
-            let id = serde_json::to_string(&format!("{url}/events/articles/delete/{slug}")).unwrap();
-            let object = serde_json::to_string(&format!("{url}/articles/{slug}")).unwrap();
-            let body = json_format! {
-                "@context": "https://www.w3.org/ns/activitystreams",
-                "id": id,
-                "type": "Delete",
-                "actor": actor,
-                "object": object,
+        QueueData::UnfollowAccount { username, actor } => {
+            let Some(following) = state.get_following(&username, &actor).await else {
+                tracing::info!(username, actor, "account is not followed");
+                return ProcessQueueResult::Finished;
             };
-            tracing::info!("body: {}", body);
-            let request = axum::http::Request::post(inbox)
-                .header(ACCEPT, AP_ACCEPT)
-                .header(CONTENT_TYPE, AP_RESPONSE_MIME)
-                .body(Bytes::from(body))
-                .unwrap();
-            let request = sign::sign(
-                request,
-                &format!("{url}/users/{author}#main-key"),
-                state.signing_key(),
-                state.timestamp_now(),
-            );
-            tracing::info!("request: {:?}", request);
-            match state.request(request).await {
-                Ok(response) => {
-                    tracing::info!("response: {:?}", response);
-                    if response.status().is_success() {
-                        tracing::info!("posted");
-                        return ProcessQueueResult::Finished;
-                    } else {
-                        tracing::warn!("failed to post");
-                        let (parts, body) = response.into_parts();
-                        let response_body = Limited::new(body, 1024 * 64)
-                            .into_data_stream()
-                            .try_fold(Vec::new(), |mut acc, bytes| {
-                                acc.extend_from_slice(&bytes);
-                                future::ready(Ok(acc))
-                            })
-                            .await;
-                        tracing::warn!("response: {:?}", response_body.map(|body| String::from_utf8_lossy(&body).into_owned()));
-                        return match parts.status {
-                            StatusCode::GONE => ProcessQueueResult::Finished,
-                            status if (400..500).contains(&status.as_u16()) => ProcessQueueResult::Finished,
-                            _ => ProcessQueueResult::Retry,
-                        };
-                    }
-                }
-                Err(e) => {
-                    tracing::error!("failed to post: {:?}", e);
-                    return ProcessQueueResult::Finished;
-                }
+            let actor_id = format!("{}/users/{username}", state.url());
+            let follow = follow_activity(&following.follow_id, &actor_id, &following.actor);
+            let (activity_id, body) = undo_activity(state, &username, follow);
+            if let Err(e) = state
+                .enqueue(QueueData::DeliveryActivity {
+                    author: following.username,
+                    inbox: following.inbox,
+                    activity: OutboundActivity::Signed { id: activity_id, body },
+                    attempt: 0,
+                    first_attempt_at: None,
+                })
+                .await
+            {
+                tracing::error!(error = ?e, "failed to enqueue follow undo");
+                return ProcessQueueResult::Retry;
             }
+            if let Err(e) = state.remove_following(&username, &actor).await {
+                tracing::error!(error = ?e, "failed to remove following");
+                return ProcessQueueResult::Retry;
+            }
+            return ProcessQueueResult::Finished;
+        }
+        QueueData::DeliveryActivityBatch {
+            author,
+            activity,
+            last_inbox,
+            then,
+        } => {
+            return enqueue_delivery_batch(state, author, activity, &last_inbox, then).await;
+        }
+        QueueData::DeliveryActivity {
+            author,
+            inbox,
+            activity,
+            attempt,
+            first_attempt_at,
+        } => {
+            return deliver_to_inbox(state, author, inbox, activity, attempt, first_attempt_at).await;
+        }
+        QueueData::DeliveryNewArticleBatch { slug, author, last_inbox } => {
+            return enqueue_delivery_batch(state, author, OutboundActivity::CreateArticle { slug }, &last_inbox, None).await;
+        }
+        QueueData::DeliveryUpdateArticleBatch { slug, author, last_inbox } => {
+            return enqueue_delivery_batch(state, author, OutboundActivity::UpdateArticle { slug }, &last_inbox, None).await;
+        }
+        QueueData::DeliveryDeleteArticleBatch { slug, author, last_inbox } => {
+            return enqueue_delivery_batch(state, author, OutboundActivity::DeleteArticle { slug }, &last_inbox, None).await;
+        }
+        QueueData::DeliveryNewArticle {
+            slug,
+            author,
+            inbox,
+            attempt,
+            first_attempt_at,
+        } => {
+            return deliver_to_inbox(state, author, inbox, OutboundActivity::CreateArticle { slug }, attempt, first_attempt_at).await;
+        }
+        QueueData::DeliveryUpdateArticle {
+            slug,
+            author,
+            inbox,
+            attempt,
+            first_attempt_at,
+        } => {
+            return deliver_to_inbox(state, author, inbox, OutboundActivity::UpdateArticle { slug }, attempt, first_attempt_at).await;
+        }
+        QueueData::DeliveryDeleteArticle {
+            slug,
+            author,
+            inbox,
+            attempt,
+            first_attempt_at,
+        } => {
+            return deliver_to_inbox(state, author, inbox, OutboundActivity::DeleteArticle { slug }, attempt, first_attempt_at).await;
         }
     }
 
@@ -525,6 +687,7 @@ where
     #[serde(tag = "type")]
     enum ResponseBody {
         Create {
+            actor: String,
             object: NoteObject,
         },
         Like {
@@ -543,6 +706,47 @@ where
             actor: String,
             object: Box<ResponseBody>,
         },
+        Accept {
+            actor: String,
+            object: ObjectRef,
+        },
+        Reject {
+            actor: String,
+            object: ObjectRef,
+        },
+    }
+    impl ResponseBody {
+        fn is_authored_by(&self, origin_of: &str) -> bool {
+            match self {
+                ResponseBody::Create { actor, object } => same_origin(actor, origin_of) && same_origin(&object.attributed_to, origin_of),
+                ResponseBody::Like { actor, .. } | ResponseBody::Follow { actor, .. } => same_origin(actor, origin_of),
+                ResponseBody::Undo { actor, object } => same_origin(actor, origin_of) && object.is_authored_by(origin_of),
+                ResponseBody::Accept { actor, .. } | ResponseBody::Reject { actor, .. } => same_origin(actor, origin_of),
+            }
+        }
+    }
+    fn parse_body(body_raw: &str) -> Option<ResponseBody> {
+        match serde_json::from_str(body_raw) {
+            Ok(b) => Some(b),
+            Err(e) => {
+                tracing::warn!("failed to deserialize body: {e} raw={}", body_raw);
+                None
+            }
+        }
+    }
+    /// An object referenced by its id or embedded.
+    #[derive(Debug, Deserialize)]
+    #[serde(untagged)]
+    enum ObjectRef {
+        Id(String),
+        Object { id: String },
+    }
+    impl ObjectRef {
+        fn id(&self) -> &str {
+            match self {
+                ObjectRef::Id(id) | ObjectRef::Object { id } => id,
+            }
+        }
     }
     #[derive(Debug, Deserialize)]
     struct NoteObject {
@@ -574,66 +778,1871 @@ where
     }
 }
 
-#[tracing::instrument(skip(state))]
-async fn get_ap_data_raw<E>(id: &str, state: &E) -> Result<Vec<u8>, ()>
+/// Starts delivering `activity` to the followers of `author` and to the subscribed relays.
+async fn enqueue_fan_out<E>(state: &E, author: String, activity: OutboundActivity) -> ProcessQueueResult
 where
-    E: HTTPClient,
+    E: RelayStore + Queue,
 {
-    let Ok(request) = axum::http::Request::get(id).header(ACCEPT, AP_ACCEPT).body(Bytes::new()) else {
-        tracing::warn!("failed to create get request");
-        return Err(());
-    };
-    let response = match state.request(request).await {
-        Ok(response) => response,
+    let relay_inboxes = match state.get_relay_inboxes().await {
+        Ok(inboxes) => inboxes,
         Err(e) => {
-            tracing::warn!("failed to fetch by: {:?}", e);
-            return Err(());
+            tracing::error!(error = ?e, "failed to get relay inboxes");
+            return ProcessQueueResult::Retry;
         }
     };
-    if !response.status().is_success() {
-        tracing::warn!("failed to fetch: {:?}", response);
-        return Err(());
+    let mut jobs = Vec::with_capacity(relay_inboxes.len() + 1);
+    jobs.extend(relay_inboxes.into_iter().map(|inbox| QueueData::DeliveryActivity {
+        author: author.clone(),
+        inbox,
+        activity: activity.clone(),
+        attempt: 0,
+        first_attempt_at: None,
+    }));
+    jobs.push(QueueData::DeliveryActivityBatch {
+        author,
+        activity,
+        last_inbox: String::new(),
+        then: None,
+    });
+    if let Err(e) = state.enqueue_batch(jobs).await {
+        tracing::error!(error = ?e, "failed to enqueue delivery batch");
+        return ProcessQueueResult::Retry;
     }
-    if !response
-        .headers()
-        .get(CONTENT_TYPE)
-        .and_then(|ty| ty.to_str().ok())
-        .and_then(|ty| Mime::from_str(ty).ok())
-        .is_some_and(|ty| headers::is_content_type_ap(&ty))
-    {
-        tracing::warn!("invalid response from server: {:?}", response);
-        return Err(());
+    ProcessQueueResult::Finished
+}
+
+#[derive(Debug, Deserialize)]
+struct RemoteActor {
+    inbox: String,
+}
+
+fn follow_activity(follow_id: &str, actor_id: &str, object: &str) -> String {
+    let id = serde_json::to_string(follow_id).unwrap();
+    let actor = serde_json::to_string(actor_id).unwrap();
+    let object = serde_json::to_string(object).unwrap();
+    json_format! {
+        "@context": "https://www.w3.org/ns/activitystreams",
+        "id": id,
+        "type": "Follow",
+        "actor": actor,
+        "object": object,
     }
-    let body = response.into_body();
-    let body = Limited::new(body, 1024 * 64);
-    match body
-        .into_data_stream()
-        .try_fold(Vec::new(), |mut acc, bytes| {
-            acc.extend_from_slice(&bytes);
-            future::ready(Ok(acc))
-        })
-        .await
-    {
-        Ok(body) => Ok(body),
+}
+
+/// Builds the `Follow` of a relay, Mastodon style relays are followed through the public collection.
+fn relay_follow(follow_id: &str, actor_id: &str, relay_actor: &str, kind: RelayKind) -> String {
+    let object = match kind {
+        RelayKind::Mastodon => "https://www.w3.org/ns/activitystreams#Public",
+        RelayKind::LitePub => relay_actor,
+    };
+    follow_activity(follow_id, actor_id, object)
+}
+
+/// Builds a signed `Undo` of `object` by `username`, returns its id and body.
+fn undo_activity<E>(state: &E, username: &str, object: String) -> (String, String)
+where
+    E: Env,
+{
+    let actor_id = format!("{}/users/{username}", state.url());
+    let activity_id = format!("{actor_id}#undo/{}", state.timestamp_now().timestamp_millis());
+    let id = serde_json::to_string(&activity_id).unwrap();
+    let actor = serde_json::to_string(&actor_id).unwrap();
+    let body = json_format! {
+        "@context": "https://www.w3.org/ns/activitystreams",
+        "id": id,
+        "type": "Undo",
+        "actor": actor,
+        "object": object,
+    };
+    (activity_id, integrity::sign_activity(state, username, body))
+}
+
+/// Enqueues deliveries of `activity` to the followers of `author` after `last_inbox`, and the next batch while there are more.
+async fn enqueue_delivery_batch<E>(
+    state: &E,
+    author: String,
+    activity: OutboundActivity,
+    last_inbox: &str,
+    then: Option<Box<QueueData>>,
+) -> ProcessQueueResult
+where
+    E: UserProvider + Queue,
+{
+    // an empty batch would end the fan-out and start `then`, so a failed read is retried
+    let (inboxes, next_last) = match state.get_followers_inbox_batch(&author, last_inbox).await {
+        Ok(batch) => batch,
         Err(e) => {
-            tracing::warn!("failed to collect response: {:?}", e);
-            Err(())
+            tracing::error!(error = ?e, "failed to read followers");
+            return ProcessQueueResult::Retry;
         }
+    };
+    let mut jobs = Vec::with_capacity(inboxes.len() + 1);
+    if inboxes.is_full() {
+        jobs.push(QueueData::DeliveryActivityBatch {
+            author: author.clone(),
+            activity: activity.clone(),
+            last_inbox: next_last,
+            then,
+        });
+    } else if let Some(then) = then {
+        jobs.push(*then);
     }
+    jobs.extend(inboxes.into_iter().map(|inbox| QueueData::DeliveryActivity {
+        author: author.clone(),
+        inbox,
+        activity: activity.clone(),
+        attempt: 0,
+        first_attempt_at: None,
+    }));
+    if let Err(e) = state.enqueue_batch(jobs).await {
+        tracing::error!(error = ?e, "failed to enqueue deliveries");
+        return ProcessQueueResult::Retry;
+    }
+    ProcessQueueResult::Finished
 }
 
-#[tracing::instrument(skip(state))]
-async fn get_ap_data<E, R>(id: &str, state: &E) -> Result<R, ()>
+async fn deliver_to_inbox<E>(
+    state: &E,
+    author: String,
+    inbox: String,
+    activity: OutboundActivity,
+    attempt: u32,
+    first_attempt_at: Option<DateTime<Utc>>,
+) -> ProcessQueueResult
 where
-    E: HTTPClient,
-    R: DeserializeOwned,
+    E: Env + HTTPClient + UserProvider + InstanceAvailability + DeliveryScheduler + DeliveryLog + RelayStore + Queue,
+{
+    let first_attempt_at = first_attempt_at.unwrap_or_else(|| state.timestamp_now());
+    let (activity_id, body) = match &activity {
+        OutboundActivity::Signed { id, body } => (id.clone(), body.clone()),
+        OutboundActivity::CreateArticle { slug } => article_activity(state, &author, "create", "Create", slug),
+        OutboundActivity::UpdateArticle { slug } => article_activity(state, &author, "update", "Update", slug),
+        OutboundActivity::DeleteArticle { slug } => article_activity(state, &author, "delete", "Delete", slug),
+    };
+    let result = deliver_activity(state, &author, &inbox, &activity_id, body).await;
+    let retry = |attempt| QueueData::DeliveryActivity {
+        author,
+        inbox,
+        activity,
+        attempt,
+        first_attempt_at: Some(first_attempt_at),
+    };
+    retry_delivery(state, result, attempt, first_attempt_at, retry).await
+}
+
+/// Builds the signed `ty` activity of an article event and returns it with its id.
+fn article_activity<E>(state: &E, author: &str, event: &str, ty: &str, slug: &str) -> (String, String)
+where
+    E: Env,
+{
+    let url = state.url();
+    let actor = serde_json::to_string(&format!("{url}/users/{author}")).unwrap();
+    let activity_id = format!("{url}/events/articles/{event}/{slug}");
+    let id = serde_json::to_string(&activity_id).unwrap();
+    let ty = serde_json::to_string(ty).unwrap();
+    let object = serde_json::to_string(&format!("{url}/articles/{slug}")).unwrap();
+    let body = json_format! {
+        "@context": "https://www.w3.org/ns/activitystreams",
+        "id": id,
+        "type": ty,
+        "actor": actor,
+        "object": object,
+        "to": ["https://www.w3.org/ns/activitystreams#Public"],
+    };
+    (activity_id, integrity::sign_activity(state, author, body))
+}
+
+/// Builds a signed `Update` embedding the actor of `username` as it is deployed now.
+async fn person_update<E>(state: &E, username: &str) -> Option<OutboundActivity>
+where
+    E: Env + UserProvider,
+{
+    let Some(person) = state.get_user_ap(username).await else {
+        tracing::warn!(username, "user not found");
+        return None;
+    };
+    let url = state.url();
+    let actor_id = format!("{url}/users/{username}");
+    // embed the actor as it is served, with its current keys
+    let Some(person) = actor::actor_document(state, username, person).await else {
+        tracing::warn!("failed to add the keys to the actor");
+        return None;
+    };
+    let person = match axum::body::to_bytes(person, usize::MAX).await {
+        Ok(person) => person,
+        Err(e) => {
+            tracing::warn!(error = %e, "failed to read user");
+            return None;
+        }
+    };
+    let mut person = match serde_json::from_slice::<serde_json::Map<String, serde_json::Value>>(&person) {
+        Ok(person) => person,
+        Err(e) => {
+            tracing::warn!(error = %e, "failed to parse user");
+            return None;
+        }
+    };
+    // the embedded actor shares the context of the activity, which needs the security terms of its key as well
+    let context = person
+        .remove("@context")
+        .unwrap_or_else(|| serde_json::Value::from("https://www.w3.org/ns/activitystreams"));
+    let context = serde_json::to_string(&context).unwrap();
+    let object = serde_json::to_string(&person).unwrap();
+    let activity_id = format!("{actor_id}#updates/{}", state.timestamp_now().timestamp_millis());
+    let actor = serde_json::to_string(&actor_id).unwrap();
+    let id = serde_json::to_string(&activity_id).unwrap();
+    let body = json_format! {
+        "@context": context,
+        "id": id,
+        "type": "Update",
+        "actor": actor,
+        "object": object,
+    };
+    Some(OutboundActivity::Signed {
+        id: activity_id,
+        body: integrity::sign_activity(state, username, body),
+    })
+}
+
+enum DeliveryResult {
+    Delivered,
+    /// The inbox no longer exists.
+    Gone,
+    /// The inbox refused the activity, retrying would not help.
+    Rejected,
+    Failed {
+        retry_after: Option<TimeDelta>,
+    },
+    /// The host is busy or asked us to slow down, the delivery has to wait.
+    Throttled {
+        delay: TimeDelta,
+    },
+    /// The state of the host or the delivery could not be stored, the job is retried as a whole.
+    StoreFailed,
+}
+
+/// Posts `body` to `inbox` when its host has a free slot and keeps track of the availability of the host.
+#[tracing::instrument(skip(state, body))]
+async fn deliver_activity<E>(state: &E, author: &str, inbox: &str, activity_id: &str, body: String) -> DeliveryResult
+where
+    E: Env + HTTPClient + UserProvider + InstanceAvailability + DeliveryScheduler + DeliveryLog + RelayStore,
+{
+    let Ok(origin) = Url::parse(inbox).map(|inbox| inbox.origin().ascii_serialization()) else {
+        tracing::warn!("invalid inbox url");
+        return DeliveryResult::Rejected;
+    };
+    if state.is_delivered(activity_id, inbox).await {
+        tracing::info!("already delivered");
+        return DeliveryResult::Delivered;
+    }
+    let policy = state.host_delivery_policy();
+    let now = state.timestamp_now();
+    match state
+        .acquire_delivery_slot(&origin, policy.max_in_flight, now, now + policy.slot_lease)
+        .await
+    {
+        DeliverySlot::Acquired => {}
+        DeliverySlot::Busy => {
+            tracing::info!(origin, "host is busy");
+            return DeliveryResult::Throttled { delay: policy.busy_delay };
+        }
+        DeliverySlot::BackingOff(until) => {
+            tracing::info!(origin, %until, "host is backing off");
+            return DeliveryResult::Throttled { delay: until - now };
+        }
+    }
+    let result = post_activity(state, author, inbox, body).await;
+    state.release_delivery_slot(&origin).await;
+    match result {
+        DeliveryResult::Throttled { delay } => {
+            tracing::warn!(origin, %delay, "backing off host");
+            if let Err(e) = state.back_off_host(&origin, state.timestamp_now() + delay).await {
+                tracing::error!(error = ?e, "failed to back off host");
+                return DeliveryResult::StoreFailed;
+            }
+            DeliveryResult::Throttled { delay }
+        }
+        DeliveryResult::Failed { retry_after } => {
+            let now = state.timestamp_now();
+            if let Some(retry_after) = retry_after {
+                tracing::warn!(origin, %retry_after, "backing off host");
+                if let Err(e) = state.back_off_host(&origin, now + retry_after).await {
+                    tracing::error!(error = ?e, "failed to back off host");
+                    return DeliveryResult::StoreFailed;
+                }
+            }
+            let failures = state.record_delivery_failure(&origin, now).await;
+            // this delivery is still retried until its deadline, only later fan-outs skip the host
+            if failures.failure_days < UNAVAILABLE_AFTER_FAILURE_DAYS {
+                return DeliveryResult::Failed { retry_after };
+            }
+            if now - failures.first_failed_at >= DEAD_HOST_FOLLOWER_REMOVAL {
+                tracing::warn!(origin, "removing followers on a dead host");
+                if let Err(e) = state.remove_followers_by_origin(&origin).await {
+                    tracing::error!(error = ?e, "failed to remove followers");
+                    return DeliveryResult::StoreFailed;
+                }
+                if let Err(e) = state.clear_delivery_failures(&origin).await {
+                    tracing::error!(error = ?e, "failed to clear delivery failures");
+                    return DeliveryResult::StoreFailed;
+                }
+            } else {
+                tracing::warn!(origin, "marking host unavailable");
+                if let Err(e) = state.mark_unavailable(&origin, now + UNAVAILABLE_HOST_PROBE_INTERVAL).await {
+                    tracing::error!(error = ?e, "failed to mark host unavailable");
+                    return DeliveryResult::StoreFailed;
+                }
+            }
+            DeliveryResult::Failed { retry_after }
+        }
+        DeliveryResult::Gone => {
+            tracing::info!("removing followers and relays of a gone inbox");
+            if let Err(e) = state.remove_followers_by_inbox(inbox).await {
+                tracing::error!(error = ?e, "failed to remove followers");
+                return DeliveryResult::StoreFailed;
+            }
+            if let Err(e) = state.remove_relays_by_inbox(inbox).await {
+                tracing::error!(error = ?e, "failed to remove relays");
+                return DeliveryResult::StoreFailed;
+            }
+            clear_delivery_failures(state, &origin, DeliveryResult::Gone).await
+        }
+        DeliveryResult::Delivered => {
+            if let Err(e) = state
+                .record_delivery(activity_id, inbox, state.timestamp_now() + DELIVERED_ACTIVITY_TTL)
+                .await
+            {
+                tracing::error!(error = ?e, "failed to record delivery");
+                return DeliveryResult::StoreFailed;
+            }
+            clear_delivery_failures(state, &origin, DeliveryResult::Delivered).await
+        }
+        result => clear_delivery_failures(state, &origin, result).await,
+    }
+}
+
+/// Makes `origin` available again now that it answered, and returns `result`.
+async fn clear_delivery_failures<E>(state: &E, origin: &str, result: DeliveryResult) -> DeliveryResult
+where
+    E: InstanceAvailability,
+{
+    match state.clear_delivery_failures(origin).await {
+        Ok(()) => result,
+        Err(e) => {
+            tracing::error!(error = ?e, "failed to clear delivery failures");
+            DeliveryResult::StoreFailed
+        }
+    }
+}
+
+async fn post_activity<E>(state: &E, author: &str, inbox: &str, body: String) -> DeliveryResult
+where
+    E: Env + HTTPClient,
+{
+    tracing::info!("body: {}", body);
+    let Ok(request) = axum::http::Request::post(inbox)
+        .header(ACCEPT, AP_ACCEPT)
+        .header(CONTENT_TYPE, AP_RESPONSE_MIME)
+        .body(Bytes::from(body))
+    else {
+        tracing::warn!("failed to create post request");
+        return DeliveryResult::Rejected;
+    };
+    let key = state.signing_key(author);
+    let request = sign::sign(
+        request,
+        &key.key_id(format_args!("{}/users/{author}", state.url())),
+        key.key,
+        state.timestamp_now(),
+    );
+    tracing::info!("request: {:?}", request);
+    match state.request(request).await {
+        Ok(response) => {
+            tracing::info!("response: {:?}", response);
+            if response.status().is_success() {
+                tracing::info!("posted");
+                return DeliveryResult::Delivered;
+            }
+            tracing::warn!("failed to post");
+            let (parts, body) = response.into_parts();
+            let response_body = Limited::new(body, 1024 * 64)
+                .into_data_stream()
+                .try_fold(Vec::new(), |mut acc, bytes| {
+                    acc.extend_from_slice(&bytes);
+                    future::ready(Ok(acc))
+                })
+                .await;
+            tracing::warn!("response: {:?}", response_body.map(|body| String::from_utf8_lossy(&body).into_owned()));
+            let retry_after = headers::retry_after(&parts.headers, state.timestamp_now());
+            match parts.status {
+                StatusCode::GONE => DeliveryResult::Gone,
+                StatusCode::TOO_MANY_REQUESTS => DeliveryResult::Throttled {
+                    delay: retry_after.unwrap_or(state.host_delivery_policy().backoff),
+                },
+                // dead hosts often answer 503 behind a proxy, so it counts as a failure, paced by the host as well
+                StatusCode::SERVICE_UNAVAILABLE => DeliveryResult::Failed {
+                    retry_after: Some(retry_after.unwrap_or(state.host_delivery_policy().backoff)),
+                },
+                status if status.is_client_error() => DeliveryResult::Rejected,
+                _ => DeliveryResult::Failed { retry_after },
+            }
+        }
+        Err(e) => {
+            tracing::error!("failed to post: {:?}", e);
+            DeliveryResult::Failed { retry_after: None }
+        }
+    }
+}
+
+/// Re-enqueues a failed or throttled delivery, or drops it once the retry deadline has passed.
+async fn retry_delivery<E>(
+    state: &E,
+    result: DeliveryResult,
+    attempt: u32,
+    first_attempt_at: DateTime<Utc>,
+    retry: impl FnOnce(u32) -> QueueData,
+) -> ProcessQueueResult
+where
+    E: Env + Queue,
+{
+    let policy = state.delivery_retry_policy();
+    let (attempt, delay) = match result {
+        DeliveryResult::Delivered | DeliveryResult::Gone | DeliveryResult::Rejected => return ProcessQueueResult::Finished,
+        DeliveryResult::StoreFailed => return ProcessQueueResult::Retry,
+        DeliveryResult::Failed { retry_after } => (attempt + 1, policy.delay(attempt + 1).max(retry_after.unwrap_or_default())),
+        // waiting for the host does not count as a failed attempt
+        DeliveryResult::Throttled { delay } => (attempt, delay.max(TimeDelta::zero())),
+    };
+    if state.timestamp_now() + delay > first_attempt_at + policy.deadline {
+        tracing::warn!(attempt, %first_attempt_at, "giving up delivery after the retry deadline");
+        return ProcessQueueResult::Finished;
+    }
+    tracing::info!(attempt, %delay, "retrying delivery later");
+    if let Err(e) = state.enqueue_with_delay(retry(attempt), delay).await {
+        tracing::error!(error = ?e, "failed to enqueue retry");
+        return ProcessQueueResult::Retry;
+    }
+    ProcessQueueResult::Finished
+}
+
+fn same_origin(a: &str, b: &str) -> bool {
+    match (Url::parse(a), Url::parse(b)) {
+        (Ok(a), Ok(b)) => a.origin() == b.origin(),
+        _ => false,
+    }
+}
+
+/// Why a remote object could not be fetched.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FetchError {
+    /// The object is missing or invalid, fetching it again would fail the same way.
+    Permanent,
+    /// The request failed on the way or the server had a problem, it may succeed later.
+    Temporary,
+}
+
+#[tracing::instrument(skip(state))]
+async fn get_ap_data_raw<E>(id: &str, state: &E) -> Result<Vec<u8>, FetchError>
+where
+    E: HTTPClient,
+{
+    if Url::parse(id).is_err() {
+        tracing::warn!("invalid url");
+        return Err(FetchError::Permanent);
+    }
+    let Ok(request) = axum::http::Request::get(id).header(ACCEPT, AP_ACCEPT).body(Bytes::new()) else {
+        tracing::warn!("failed to create get request");
+        return Err(FetchError::Permanent);
+    };
+    let response = match state.request(request).await {
+        Ok(response) => response,
+        Err(e) => {
+            tracing::warn!("failed to fetch by: {:?}", e);
+            return Err(FetchError::Temporary);
+        }
+    };
+    let status = response.status();
+    if !status.is_success() {
+        tracing::warn!("failed to fetch: {:?}", response);
+        if status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS {
+            return Err(FetchError::Temporary);
+        }
+        return Err(FetchError::Permanent);
+    }
+    if !response
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|ty| ty.to_str().ok())
+        .and_then(|ty| Mime::from_str(ty).ok())
+        .is_some_and(|ty| headers::is_content_type_ap(&ty))
+    {
+        tracing::warn!("invalid response from server: {:?}", response);
+        return Err(FetchError::Permanent);
+    }
+    let body = response.into_body();
+    let body = Limited::new(body, 1024 * 64);
+    match body
+        .into_data_stream()
+        .try_fold(Vec::new(), |mut acc, bytes| {
+            acc.extend_from_slice(&bytes);
+            future::ready(Ok(acc))
+        })
+        .await
+    {
+        Ok(body) => Ok(body),
+        Err(e) => {
+            tracing::warn!("failed to collect response: {:?}", e);
+            Err(FetchError::Temporary)
+        }
+    }
+}
+
+#[tracing::instrument(skip(state))]
+async fn get_ap_data<E, R>(id: &str, state: &E) -> Result<R, FetchError>
+where
+    E: HTTPClient,
+    R: DeserializeOwned,
 {
     let body = get_ap_data_raw(id, state).await?;
     match serde_json::from_slice::<R>(&body) {
         Ok(body) => Ok(body),
         Err(_) => {
             tracing::warn!("failed to parse response: {}", String::from_utf8_lossy(&body));
-            Err(())
+            Err(FetchError::Permanent)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ProcessQueueResult, process_queue};
+    use crate::common::integrity::{assertion_method, attach_proof, derive_integrity_key};
+    use crate::common::ld_signature::sign_document;
+    use crate::traits::{
+        ArticleNewComment, ArticleNewReaction, ArticleProvider, CachedPublicKey, DeliveryFailures, DeliveryLog, DeliveryRetryPolicy,
+        DeliveryScheduler, DeliverySlot, Ed25519SigningKey, Env, Following, FollowingStore, HTTPClient, InstanceAvailability, OutboundActivity,
+        ProcessedActivityStore, PublicKeyCache, Queue, QueueData, RSASHA2SigningKey, Relay, RelayKind, RelayStore, UserProvider, UserSigningKey,
+    };
+    use arrayvec::ArrayVec;
+    use axum::body::Body;
+    use axum::http::header::{CONTENT_TYPE, RETRY_AFTER};
+    use axum::http::{Method, Request, Response, StatusCode};
+    use bytes::Bytes;
+    use chrono::{DateTime, TimeDelta, Utc};
+    use rsa::pkcs8::DecodePrivateKey;
+    use serde_json::{Value, json};
+    use std::collections::{HashMap, HashSet};
+    use std::convert::Infallible;
+    use std::fmt::{self, Display};
+    use std::sync::{Arc, Mutex, MutexGuard};
+
+    const ACTOR: &str = "https://remote.test/users/alice";
+    const REMOTE_INBOX: &str = "https://remote.test/inbox";
+    const RELAY: &str = "https://relay.test/actor";
+    const FORWARDER: &str = "https://forwarder.test/users/bob";
+    const ARTICLE: &str = "https://blog.test/articles/first-post";
+    const REPLY: &str = "https://remote.test/users/alice/statuses/1/activity";
+
+    fn now() -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339("2025-06-01T00:00:00Z").unwrap().to_utc()
+    }
+
+    #[derive(Default)]
+    struct Store {
+        /// Remote documents by URL, served to GET requests.
+        documents: HashMap<String, Value>,
+        fetched: Vec<String>,
+        /// Status and `Retry-After` of POST requests to inboxes.
+        inbox_response: Option<(StatusCode, Option<&'static str>)>,
+        posted: Vec<String>,
+        /// Signature headers of the posted requests.
+        signatures: Vec<String>,
+        key_cache: HashMap<String, CachedPublicKey>,
+        /// Articles by slug, with their author and Note.
+        articles: HashMap<String, (String, Value)>,
+        /// Deployed actors by username.
+        users: HashMap<String, Value>,
+        deleted_users: HashMap<String, DateTime<Utc>>,
+        /// Relays by actor.
+        relays: HashMap<String, Relay>,
+        /// Makes reading the relay inboxes fail.
+        fail_relay_reads: bool,
+        following: Vec<Following>,
+        comments: Vec<ArticleNewComment>,
+        reactions: Vec<ArticleNewReaction>,
+        /// Followers as (username, actor, inbox, event id).
+        followers: Vec<(String, String, String, String)>,
+        /// Processed activities as (username, activity id).
+        processed: HashSet<(String, String)>,
+        /// Makes every article write fail, as a storage outage would.
+        fail_writes: bool,
+        /// Failures returned for every host, the first failure today by default.
+        failures_so_far: Option<DeliveryFailures>,
+        failures: Vec<String>,
+        unavailable: Vec<(String, DateTime<Utc>)>,
+        host_busy: bool,
+        backed_off: Vec<(String, DateTime<Utc>)>,
+        /// Deliveries as (activity id, inbox).
+        deliveries: HashSet<(String, String)>,
+        enqueued: Vec<(QueueData, Option<TimeDelta>)>,
+        /// Number of requests made to the queue.
+        queue_requests: usize,
+    }
+
+    #[derive(Clone)]
+    struct TestState {
+        key: Arc<RSASHA2SigningKey>,
+        store: Arc<Mutex<Store>>,
+    }
+
+    impl TestState {
+        fn new() -> Self {
+            let integrity_key = derive_integrity_key(b"secret", "alice");
+            let store = Store {
+                documents: HashMap::from([(
+                    ACTOR.to_owned(),
+                    json!({
+                        "id": ACTOR,
+                        "type": "Person",
+                        "inbox": REMOTE_INBOX,
+                        "publicKey": {
+                            "id": format!("{ACTOR}#main-key"),
+                            "owner": ACTOR,
+                            "publicKeyPem": include_str!("../../../test_config/public-key-for-test.pem"),
+                        },
+                        "assertionMethod": [assertion_method(ACTOR, &integrity_key)],
+                    }),
+                )]),
+                articles: HashMap::from([("first-post".to_owned(), ("writer".to_owned(), json!({ "id": ARTICLE, "type": "Note" })))]),
+                ..Store::default()
+            };
+            Self {
+                key: Arc::new(RSASHA2SigningKey::from_pkcs8_pem(include_str!("../../../test_config/private-key-for-test.pem")).unwrap()),
+                store: Arc::new(Mutex::new(store)),
+            }
+        }
+
+        fn store(&self) -> MutexGuard<'_, Store> {
+            self.store.lock().unwrap()
+        }
+
+        /// Locks the store for a write, failing when `fail_writes` is set.
+        fn write(&self) -> Result<MutexGuard<'_, Store>, fmt::Error> {
+            let store = self.store();
+            if store.fail_writes { Err(fmt::Error) } else { Ok(store) }
+        }
+
+        fn run(&self, data: QueueData) -> ProcessQueueResult {
+            futures::executor::block_on(process_queue(self, data))
+        }
+
+        fn take_enqueued(&self) -> Vec<(QueueData, Option<TimeDelta>)> {
+            std::mem::take(&mut self.store().enqueued)
+        }
+    }
+
+    impl Env for TestState {
+        fn url(&self) -> impl Display + Send + '_ {
+            "https://blog.test"
+        }
+        fn timestamp_now(&self) -> DateTime<Utc> {
+            now()
+        }
+        fn signing_key(&self, _username: &str) -> UserSigningKey<'_> {
+            UserSigningKey { id: "key-2", key: &self.key }
+        }
+        fn retiring_keys(&self, _username: &str) -> Vec<UserSigningKey<'_>> {
+            vec![UserSigningKey {
+                id: "main-key",
+                key: &self.key,
+            }]
+        }
+        fn integrity_key(&self, _username: &str) -> Option<Ed25519SigningKey> {
+            None
+        }
+    }
+
+    impl HTTPClient for TestState {
+        type Error = Infallible;
+        async fn request(&self, request: Request<Bytes>) -> Result<Response<Body>, Self::Error> {
+            let uri = request.uri().to_string();
+            let mut store = self.store();
+            if request.method() == Method::POST {
+                store.posted.push(uri);
+                let signature = request.headers().get("signature").and_then(|signature| signature.to_str().ok());
+                store.signatures.push(signature.unwrap_or_default().to_owned());
+                let (status, retry_after) = store.inbox_response.unwrap_or((StatusCode::ACCEPTED, None));
+                let mut response = Response::builder().status(status);
+                if let Some(retry_after) = retry_after {
+                    response = response.header(RETRY_AFTER, retry_after);
+                }
+                return Ok(response.body(Body::empty()).unwrap());
+            }
+            store.fetched.push(uri.clone());
+            Ok(match store.documents.get(&uri) {
+                Some(document) => Response::builder()
+                    .header(CONTENT_TYPE, "application/activity+json")
+                    .body(Body::from(document.to_string()))
+                    .unwrap(),
+                None => Response::builder().status(StatusCode::NOT_FOUND).body(Body::empty()).unwrap(),
+            })
+        }
+    }
+
+    impl PublicKeyCache for TestState {
+        type Error = Infallible;
+        async fn get_public_key(&self, key_id: &str) -> Option<CachedPublicKey> {
+            self.store().key_cache.get(key_id).cloned()
+        }
+        async fn put_public_key(&self, key_id: &str, key: CachedPublicKey) -> Result<(), Self::Error> {
+            self.store().key_cache.insert(key_id.to_owned(), key);
+            Ok(())
+        }
+    }
+
+    impl ArticleProvider for TestState {
+        type Error = fmt::Error;
+        async fn exists_article(&self, slug: &str) -> bool {
+            self.store().articles.contains_key(slug)
+        }
+        async fn get_article_html(&self, _slug: &str) -> Option<Body> {
+            None
+        }
+        async fn get_article_ap(&self, slug: &str) -> Option<Body> {
+            let note = self.store().articles.get(slug)?.1.to_string();
+            Some(Body::from(note))
+        }
+        async fn get_author_id(&self, slug: &str) -> Option<String> {
+            Some(self.store().articles.get(slug)?.0.clone())
+        }
+        async fn add_comment(&self, _slug: &str, comment: ArticleNewComment) -> Result<(), Self::Error> {
+            self.write()?.comments.push(comment);
+            Ok(())
+        }
+        async fn add_reaction(&self, _slug: &str, reaction: ArticleNewReaction) -> Result<(), Self::Error> {
+            self.write()?.reactions.push(reaction);
+            Ok(())
+        }
+        async fn remove_reaction_by(&self, _slug: &str, actor: &str) -> Result<(), Self::Error> {
+            self.write()?.reactions.retain(|reaction| reaction.author_id != actor);
+            Ok(())
+        }
+        async fn comment_count(&self, _slug: &str) -> usize {
+            self.store().comments.len()
+        }
+        async fn reaction_count(&self, _slug: &str) -> usize {
+            self.store().reactions.len()
+        }
+    }
+
+    impl UserProvider for TestState {
+        type Error = Infallible;
+        async fn exists_user(&self, username: &str) -> bool {
+            self.store().users.contains_key(username)
+        }
+        async fn get_user_html(&self, _username: &str) -> Option<Body> {
+            None
+        }
+        async fn get_user_ap(&self, username: &str) -> Option<Body> {
+            let person = self.store().users.get(username)?.to_string();
+            Some(Body::from(person))
+        }
+        async fn add_follower(&self, username: &str, follower_id: &str, inbox: &str, event_id: &str) -> Result<(), Self::Error> {
+            let follower = (username.to_owned(), follower_id.to_owned(), inbox.to_owned(), event_id.to_owned());
+            self.store().followers.push(follower);
+            Ok(())
+        }
+        async fn remove_follower(&self, username: &str, event_id: &str) -> Result<(), Self::Error> {
+            self.store().followers.retain(|(user, _, _, id)| user != username || id != event_id);
+            Ok(())
+        }
+        async fn remove_follower_by_actor(&self, username: &str, actor: &str) -> Result<(), Self::Error> {
+            self.store()
+                .followers
+                .retain(|(user, follower, _, _)| user != username || follower != actor);
+            Ok(())
+        }
+        async fn remove_followers_by_inbox(&self, inbox: &str) -> Result<(), Self::Error> {
+            self.store().followers.retain(|(_, _, follower_inbox, _)| follower_inbox != inbox);
+            Ok(())
+        }
+        async fn remove_followers_by_origin(&self, origin: &str) -> Result<(), Self::Error> {
+            self.store().followers.retain(|(_, _, inbox, _)| !inbox.starts_with(origin));
+            Ok(())
+        }
+        async fn get_followers_inbox_batch(&self, username: &str, last_inbox: &str) -> Result<(ArrayVec<String, 10>, String), Self::Error> {
+            let mut inboxes = self
+                .store()
+                .followers
+                .iter()
+                .filter(|(user, _, inbox, _)| user == username && inbox.as_str() > last_inbox)
+                .map(|(_, _, inbox, _)| inbox.clone())
+                .collect::<Vec<_>>();
+            inboxes.sort();
+            inboxes.dedup();
+            let batch = inboxes.into_iter().take(10).collect::<ArrayVec<_, 10>>();
+            let last = batch.last().cloned().unwrap_or_default();
+            Ok((batch, last))
+        }
+        async fn get_follower_by_event_id(&self, username: &str, event_id: &str) -> Option<String> {
+            let store = self.store();
+            let mut followers = store.followers.iter();
+            let (_, actor, _, _) = followers.find(|(user, _, _, id)| user == username && id == event_id)?;
+            Some(actor.clone())
+        }
+        async fn remove_followers_of(&self, username: &str) -> Result<(), Self::Error> {
+            self.store().followers.retain(|(user, _, _, _)| user != username);
+            Ok(())
+        }
+        async fn user_deleted_at(&self, username: &str) -> Option<DateTime<Utc>> {
+            self.store().deleted_users.get(username).copied()
+        }
+        async fn mark_user_deleted(&self, username: &str, deleted_at: DateTime<Utc>) -> Result<(), Self::Error> {
+            self.store().deleted_users.insert(username.to_owned(), deleted_at);
+            Ok(())
+        }
+    }
+
+    impl ProcessedActivityStore for TestState {
+        type Error = Infallible;
+        async fn is_activity_processed(&self, username: &str, activity_id: &str) -> Result<bool, Self::Error> {
+            Ok(self.store().processed.contains(&(username.to_owned(), activity_id.to_owned())))
+        }
+        async fn mark_activity_processed(&self, username: &str, activity_id: &str, _expires_at: DateTime<Utc>) -> Result<(), Self::Error> {
+            self.store().processed.insert((username.to_owned(), activity_id.to_owned()));
+            Ok(())
+        }
+    }
+
+    impl InstanceAvailability for TestState {
+        type Error = Infallible;
+        async fn record_delivery_failure(&self, origin: &str, now: DateTime<Utc>) -> DeliveryFailures {
+            let mut store = self.store();
+            store.failures.push(origin.to_owned());
+            store.failures_so_far.unwrap_or(DeliveryFailures {
+                first_failed_at: now,
+                failure_days: 1,
+            })
+        }
+        async fn clear_delivery_failures(&self, _origin: &str) -> Result<(), Self::Error> {
+            Ok(())
+        }
+        async fn mark_unavailable(&self, origin: &str, until: DateTime<Utc>) -> Result<(), Self::Error> {
+            self.store().unavailable.push((origin.to_owned(), until));
+            Ok(())
+        }
+    }
+
+    impl DeliveryScheduler for TestState {
+        type Error = Infallible;
+        async fn acquire_delivery_slot(&self, _origin: &str, _max_in_flight: u32, _now: DateTime<Utc>, _lease_until: DateTime<Utc>) -> DeliverySlot {
+            if self.store().host_busy {
+                DeliverySlot::Busy
+            } else {
+                DeliverySlot::Acquired
+            }
+        }
+        async fn release_delivery_slot(&self, _origin: &str) {}
+        async fn back_off_host(&self, origin: &str, until: DateTime<Utc>) -> Result<(), Self::Error> {
+            self.store().backed_off.push((origin.to_owned(), until));
+            Ok(())
+        }
+    }
+
+    impl DeliveryLog for TestState {
+        type Error = fmt::Error;
+        async fn is_delivered(&self, activity_id: &str, inbox: &str) -> bool {
+            self.store().deliveries.contains(&(activity_id.to_owned(), inbox.to_owned()))
+        }
+        async fn record_delivery(&self, activity_id: &str, inbox: &str, _expires_at: DateTime<Utc>) -> Result<(), Self::Error> {
+            self.write()?.deliveries.insert((activity_id.to_owned(), inbox.to_owned()));
+            Ok(())
+        }
+        async fn forget_deliveries(&self, activity_id: &str) {
+            self.store().deliveries.retain(|(id, _)| id != activity_id);
+        }
+    }
+
+    impl RelayStore for TestState {
+        type Error = fmt::Error;
+        async fn add_relay(&self, relay: &Relay) -> Result<(), Self::Error> {
+            self.store().relays.insert(relay.actor.clone(), relay.clone());
+            Ok(())
+        }
+        async fn get_relay(&self, actor: &str) -> Option<Relay> {
+            self.store().relays.get(actor).cloned()
+        }
+        async fn accept_relay(&self, actor: &str, follow_id: &str, accepted_at: DateTime<Utc>) -> Result<bool, Self::Error> {
+            match self.store().relays.get_mut(actor) {
+                Some(relay) if relay.follow_id == follow_id => {
+                    relay.accepted_at = Some(accepted_at);
+                    Ok(true)
+                }
+                _ => Ok(false),
+            }
+        }
+        async fn remove_relay(&self, actor: &str) -> Result<(), Self::Error> {
+            self.store().relays.remove(actor);
+            Ok(())
+        }
+        async fn remove_relays_by_inbox(&self, inbox: &str) -> Result<(), Self::Error> {
+            self.store().relays.retain(|_, relay| relay.inbox != inbox);
+            Ok(())
+        }
+        async fn get_relay_inboxes(&self) -> Result<Vec<String>, Self::Error> {
+            let store = self.store();
+            if store.fail_relay_reads {
+                return Err(fmt::Error);
+            }
+            Ok(store
+                .relays
+                .values()
+                .filter(|relay| relay.accepted_at.is_some())
+                .map(|relay| relay.inbox.clone())
+                .collect())
+        }
+    }
+
+    impl FollowingStore for TestState {
+        type Error = Infallible;
+        async fn add_following(&self, following: &Following) -> Result<(), Self::Error> {
+            let mut store = self.store();
+            store.following.retain(|f| f.username != following.username || f.actor != following.actor);
+            store.following.push(following.clone());
+            Ok(())
+        }
+        async fn get_following(&self, username: &str, actor: &str) -> Option<Following> {
+            self.store()
+                .following
+                .iter()
+                .find(|f| f.username == username && f.actor == actor)
+                .cloned()
+        }
+        async fn accept_following(&self, actor: &str, follow_id: &str, accepted_at: DateTime<Utc>) -> Result<bool, Self::Error> {
+            match self.store().following.iter_mut().find(|f| f.actor == actor && f.follow_id == follow_id) {
+                Some(following) => {
+                    following.accepted_at = Some(accepted_at);
+                    Ok(true)
+                }
+                None => Ok(false),
+            }
+        }
+        async fn reject_following(&self, actor: &str, follow_id: &str) -> Result<bool, Self::Error> {
+            let mut store = self.store();
+            let before = store.following.len();
+            store.following.retain(|f| f.actor != actor || f.follow_id != follow_id);
+            Ok(store.following.len() != before)
+        }
+        async fn remove_following(&self, username: &str, actor: &str) -> Result<(), Self::Error> {
+            self.store().following.retain(|f| f.username != username || f.actor != actor);
+            Ok(())
         }
+        async fn get_following_actors(&self, username: &str) -> Vec<String> {
+            let store = self.store();
+            store
+                .following
+                .iter()
+                .filter(|f| f.username == username && f.accepted_at.is_some())
+                .map(|f| f.actor.clone())
+                .collect()
+        }
+    }
+
+    impl Queue for TestState {
+        type Error = Infallible;
+        async fn enqueue(&self, data: QueueData) -> Result<(), Self::Error> {
+            self.enqueue_batch(vec![data]).await
+        }
+        async fn enqueue_batch(&self, data: Vec<QueueData>) -> Result<(), Self::Error> {
+            let mut store = self.store();
+            store.queue_requests += 1;
+            store.enqueued.extend(data.into_iter().map(|data| (data, None)));
+            Ok(())
+        }
+        async fn enqueue_with_delay(&self, data: QueueData, delay: TimeDelta) -> Result<(), Self::Error> {
+            let mut store = self.store();
+            store.queue_requests += 1;
+            store.enqueued.push((data, Some(delay)));
+            Ok(())
+        }
+    }
+
+    fn reply(content: &str) -> Value {
+        json!({
+            "@context": "https://www.w3.org/ns/activitystreams",
+            "id": REPLY,
+            "type": "Create",
+            "actor": ACTOR,
+            "object": {
+                "id": "https://remote.test/users/alice/statuses/1",
+                "type": "Note",
+                "attributedTo": ACTOR,
+                "published": "2025-06-01T00:00:00Z",
+                "inReplyTo": ARTICLE,
+                "content": content,
+            },
+        })
+    }
+
+    fn inbox(username: &str, body: &str, verified_actor: Option<&str>) -> QueueData {
+        let activity = serde_json::from_str::<Value>(body).unwrap();
+        QueueData::Inbox {
+            username: username.to_owned(),
+            ty: activity["type"].as_str().unwrap().to_owned(),
+            id: activity["id"].as_str().unwrap().to_owned(),
+            verified_body: Some(body.to_owned()),
+            verified_actor: verified_actor.map(str::to_owned),
+            unverified_body: None,
+        }
+    }
+
+    fn delivery(attempt: u32, first_attempt_at: Option<DateTime<Utc>>) -> QueueData {
+        QueueData::DeliveryActivity {
+            author: "writer".to_owned(),
+            inbox: REMOTE_INBOX.to_owned(),
+            activity: OutboundActivity::CreateArticle {
+                slug: "first-post".to_owned(),
+            },
+            attempt,
+            first_attempt_at,
+        }
+    }
+
+    fn follow_from_remote(state: &TestState) {
+        let follower = (
+            "writer".to_owned(),
+            ACTOR.to_owned(),
+            REMOTE_INBOX.to_owned(),
+            "https://remote.test/follows/1".to_owned(),
+        );
+        state.store().followers.push(follower);
+    }
+
+    fn relay(follow_id: &str, accepted_at: Option<DateTime<Utc>>) -> Relay {
+        Relay {
+            actor: RELAY.to_owned(),
+            inbox: "https://relay.test/inbox".to_owned(),
+            kind: RelayKind::Mastodon,
+            username: "writer".to_owned(),
+            follow_id: follow_id.to_owned(),
+            accepted_at,
+        }
+    }
+
+    fn following(follow_id: &str) -> Following {
+        Following {
+            username: "writer".to_owned(),
+            actor: ACTOR.to_owned(),
+            inbox: REMOTE_INBOX.to_owned(),
+            follow_id: follow_id.to_owned(),
+            accepted_at: None,
+        }
+    }
+
+    fn comment_contents(state: &TestState) -> Vec<String> {
+        state.store().comments.iter().map(|comment| comment.content.clone()).collect()
+    }
+
+    #[test]
+    fn reply_signed_by_its_author_is_stored_without_fetching() {
+        let state = TestState::new();
+
+        let result = state.run(inbox("writer", &reply("<p>hi</p>").to_string(), Some(ACTOR)));
+
+        assert!(matches!(result, ProcessQueueResult::Finished));
+        assert_eq!(comment_contents(&state), ["<p>hi</p>"]);
+        assert!(state.store().fetched.is_empty());
+    }
+
+    #[test]
+    fn forwarded_reply_falls_back_to_its_integrity_proof() {
+        let state = TestState::new();
+        let key = derive_integrity_key(b"secret", "alice");
+        let body = attach_proof(&reply("<p>hi</p>").to_string(), &key, &format!("{ACTOR}#ed25519-key"), now()).unwrap();
+
+        state.run(inbox("writer", &body, Some(FORWARDER)));
+
+        assert_eq!(comment_contents(&state), ["<p>hi</p>"]);
+        assert_eq!(state.store().fetched, [ACTOR]);
+    }
+
+    #[test]
+    fn forwarded_reply_falls_back_to_its_ld_signature() {
+        let state = TestState::new();
+        let body = sign_document(&state.key, &format!("{ACTOR}#main-key"), &reply("<p>hi</p>"));
+
+        state.run(inbox("writer", &body.to_string(), Some(FORWARDER)));
+
+        assert_eq!(comment_contents(&state), ["<p>hi</p>"]);
+        assert_eq!(state.store().fetched, [ACTOR]);
+    }
+
+    #[test]
+    fn reply_from_another_signer_is_fetched_from_its_origin() {
+        let state = TestState::new();
+        state.store().documents.insert(REPLY.to_owned(), reply("<p>genuine</p>"));
+
+        state.run(inbox("writer", &reply("<p>forged</p>").to_string(), Some(FORWARDER)));
+
+        assert_eq!(comment_contents(&state), ["<p>genuine</p>"]);
+        assert_eq!(state.store().fetched, [REPLY]);
+    }
+
+    #[test]
+    fn note_attributed_to_another_origin_is_fetched_from_its_origin() {
+        let state = TestState::new();
+        state.store().documents.insert(REPLY.to_owned(), reply("<p>genuine</p>"));
+        let mut forged = reply("<p>forged</p>");
+        forged["object"]["attributedTo"] = FORWARDER.into();
+
+        state.run(inbox("writer", &forged.to_string(), Some(ACTOR)));
+
+        assert_eq!(comment_contents(&state), ["<p>genuine</p>"]);
+        assert_eq!(state.store().fetched, [REPLY]);
+    }
+
+    #[test]
+    fn activity_on_another_origin_than_its_id_is_dropped() {
+        let state = TestState::new();
+        let mut forged = reply("<p>forged</p>");
+        forged["actor"] = FORWARDER.into();
+        forged["object"]["attributedTo"] = FORWARDER.into();
+        state.store().documents.insert(REPLY.to_owned(), forged.clone());
+
+        let result = state.run(inbox("writer", &reply("<p>hi</p>").to_string(), None));
+
+        assert!(matches!(result, ProcessQueueResult::Finished));
+        assert!(comment_contents(&state).is_empty());
+        assert_eq!(state.store().fetched, [REPLY]);
+    }
+
+    #[test]
+    fn activity_is_processed_once_per_inbox() {
+        let state = TestState::new();
+        let like = json!({
+            "@context": "https://www.w3.org/ns/activitystreams",
+            "id": "https://remote.test/likes/1",
+            "type": "Like",
+            "actor": ACTOR,
+            "object": ARTICLE,
+        })
+        .to_string();
+
+        state.run(inbox("writer", &like, Some(ACTOR)));
+        state.run(inbox("writer", &like, Some(ACTOR)));
+        state.run(inbox("editor", &like, Some(ACTOR)));
+
+        assert_eq!(state.store().reactions.len(), 1);
+        let processed = &state.store().processed;
+        assert!(processed.contains(&("writer".to_owned(), "https://remote.test/likes/1".to_owned())));
+        assert!(processed.contains(&("editor".to_owned(), "https://remote.test/likes/1".to_owned())));
+    }
+
+    #[test]
+    fn failed_delivery_is_retried_with_a_growing_delay() {
+        let state = TestState::new();
+        state.store().inbox_response = Some((StatusCode::INTERNAL_SERVER_ERROR, None));
+        let policy = DeliveryRetryPolicy::default();
+
+        state.run(delivery(0, None));
+        let enqueued = state.take_enqueued();
+        let [
+            (
+                QueueData::DeliveryActivity {
+                    attempt: 1,
+                    first_attempt_at,
+                    ..
+                },
+                Some(delay),
+            ),
+        ] = enqueued.as_slice()
+        else {
+            panic!("unexpected retry: {enqueued:?}");
+        };
+        assert_eq!(*first_attempt_at, Some(now()));
+        assert_eq!(*delay, policy.delay(1));
+
+        state.run(delivery(3, Some(now() - TimeDelta::hours(1))));
+        let enqueued = state.take_enqueued();
+        let [
+            (
+                QueueData::DeliveryActivity {
+                    attempt: 4,
+                    first_attempt_at,
+                    ..
+                },
+                Some(delay),
+            ),
+        ] = enqueued.as_slice()
+        else {
+            panic!("unexpected retry: {enqueued:?}");
+        };
+        assert_eq!(*first_attempt_at, Some(now() - TimeDelta::hours(1)));
+        assert_eq!(*delay, policy.delay(4));
+        assert_eq!(state.store().posted, [REMOTE_INBOX, REMOTE_INBOX]);
+    }
+
+    #[test]
+    fn failed_delivery_is_given_up_after_the_deadline() {
+        let state = TestState::new();
+        state.store().inbox_response = Some((StatusCode::INTERNAL_SERVER_ERROR, None));
+        let first_attempt_at = now() - DeliveryRetryPolicy::default().deadline + TimeDelta::minutes(1);
+
+        let result = state.run(delivery(5, Some(first_attempt_at)));
+
+        assert!(matches!(result, ProcessQueueResult::Finished));
+        assert!(state.take_enqueued().is_empty());
+    }
+
+    #[test]
+    fn gone_inbox_loses_its_followers() {
+        let state = TestState::new();
+        follow_from_remote(&state);
+        state.store().inbox_response = Some((StatusCode::GONE, None));
+
+        state.run(delivery(0, None));
+
+        assert!(state.store().followers.is_empty());
+        assert!(state.take_enqueued().is_empty());
+    }
+
+    #[test]
+    fn gone_relay_inbox_is_unsubscribed() {
+        let state = TestState::new();
+        let relay = Relay {
+            inbox: REMOTE_INBOX.to_owned(),
+            ..relay("https://blog.test/users/writer#follows/1", Some(now()))
+        };
+        state.store().relays.insert(RELAY.to_owned(), relay);
+        state.store().inbox_response = Some((StatusCode::GONE, None));
+
+        state.run(delivery(0, None));
+
+        assert!(state.store().relays.is_empty());
+        assert!(state.take_enqueued().is_empty());
+    }
+
+    #[test]
+    fn host_failing_for_days_is_marked_unavailable_then_loses_its_followers() {
+        let state = TestState::new();
+        follow_from_remote(&state);
+        state.store().inbox_response = Some((StatusCode::INTERNAL_SERVER_ERROR, None));
+        state.store().failures_so_far = Some(DeliveryFailures {
+            first_failed_at: now() - TimeDelta::days(7),
+            failure_days: 7,
+        });
+
+        state.run(delivery(0, None));
+        assert_eq!(
+            state.store().unavailable,
+            [("https://remote.test".to_owned(), now() + TimeDelta::days(1))]
+        );
+        assert_eq!(state.store().followers.len(), 1);
+        let enqueued = state.take_enqueued();
+        let [(QueueData::DeliveryActivity { attempt: 1, .. }, Some(_))] = enqueued.as_slice() else {
+            panic!("delivery to an unavailable host is not retried: {enqueued:?}");
+        };
+
+        state.store().failures_so_far = Some(DeliveryFailures {
+            first_failed_at: now() - TimeDelta::days(30),
+            failure_days: 20,
+        });
+        state.run(delivery(0, None));
+        assert!(state.store().followers.is_empty());
+        let enqueued = state.take_enqueued();
+        let [(QueueData::DeliveryActivity { attempt: 1, .. }, Some(_))] = enqueued.as_slice() else {
+            panic!("delivery to a dead host is not retried: {enqueued:?}");
+        };
+    }
+
+    #[test]
+    fn delivery_that_cannot_be_recorded_is_retried() {
+        let state = TestState::new();
+        state.store().fail_writes = true;
+
+        let result = state.run(delivery(0, None));
+        assert!(matches!(result, ProcessQueueResult::Retry));
+        assert!(state.take_enqueued().is_empty());
+    }
+
+    #[test]
+    fn delivery_refused_by_the_inbox_is_not_retried() {
+        let state = TestState::new();
+        state.store().inbox_response = Some((StatusCode::FORBIDDEN, None));
+
+        let result = state.run(delivery(0, None));
+        assert!(matches!(result, ProcessQueueResult::Finished));
+        assert!(state.take_enqueued().is_empty());
+        assert!(state.store().failures.is_empty());
+    }
+
+    #[test]
+    fn throttled_delivery_keeps_its_attempt() {
+        let state = TestState::new();
+        state.store().inbox_response = Some((StatusCode::TOO_MANY_REQUESTS, Some("120")));
+
+        state.run(delivery(2, Some(now())));
+        let enqueued = state.take_enqueued();
+        let [(QueueData::DeliveryActivity { attempt: 2, .. }, Some(delay))] = enqueued.as_slice() else {
+            panic!("unexpected retry: {enqueued:?}");
+        };
+        assert_eq!(*delay, TimeDelta::seconds(120));
+        assert_eq!(
+            state.store().backed_off,
+            [("https://remote.test".to_owned(), now() + TimeDelta::seconds(120))]
+        );
+
+        state.store().host_busy = true;
+        state.run(delivery(2, Some(now())));
+        let enqueued = state.take_enqueued();
+        let [(QueueData::DeliveryActivity { attempt: 2, .. }, Some(delay))] = enqueued.as_slice() else {
+            panic!("unexpected retry: {enqueued:?}");
+        };
+        assert_eq!(*delay, state.host_delivery_policy().busy_delay);
+
+        let store = state.store();
+        assert_eq!(store.posted.len(), 1);
+        assert!(store.failures.is_empty());
+    }
+
+    #[test]
+    fn activity_is_delivered_once_per_inbox() {
+        let state = TestState::new();
+
+        state.run(delivery(0, None));
+        state.run(delivery(0, None));
+
+        let store = state.store();
+        assert_eq!(store.posted.len(), 1);
+        let delivery = ("https://blog.test/events/articles/create/first-post".to_owned(), REMOTE_INBOX.to_owned());
+        assert_eq!(store.deliveries, HashSet::from([delivery]));
+    }
+
+    #[test]
+    fn fan_out_batch_is_enqueued_in_one_request() {
+        let state = TestState::new();
+        for i in 0..12 {
+            let follower = (
+                "writer".to_owned(),
+                format!("https://remote{i:02}.test/users/a"),
+                format!("https://remote{i:02}.test/inbox"),
+                format!("https://remote{i:02}.test/follows/1"),
+            );
+            state.store().followers.push(follower);
+        }
+
+        state.run(QueueData::DeliveryActivityBatch {
+            author: "writer".to_owned(),
+            activity: OutboundActivity::CreateArticle {
+                slug: "first-post".to_owned(),
+            },
+            last_inbox: String::new(),
+            then: None,
+        });
+
+        let enqueued = state.take_enqueued();
+        let Some((QueueData::DeliveryActivityBatch { last_inbox, .. }, None)) = enqueued.first() else {
+            panic!("unexpected jobs: {enqueued:?}");
+        };
+        assert_eq!(last_inbox, "https://remote09.test/inbox");
+        assert_eq!(enqueued.len(), 11);
+        assert_eq!(state.store().queue_requests, 1);
+    }
+
+    #[test]
+    fn scheduled_article_is_delivered_once_published() {
+        let state = TestState::new();
+        let published = now() + TimeDelta::hours(1);
+        state.store().articles.get_mut("first-post").unwrap().1["published"] = published.to_rfc3339().into();
+
+        state.run(QueueData::DeliveryNewArticleToAll {
+            slug: "first-post".to_owned(),
+        });
+        state.run(QueueData::DeliveryUpdateArticleToAll {
+            slug: "first-post".to_owned(),
+        });
+        let enqueued = state.take_enqueued();
+        let [(QueueData::DeliveryNewArticleToAll { .. }, Some(delay))] = enqueued.as_slice() else {
+            panic!("unexpected reschedule: {enqueued:?}");
+        };
+        assert_eq!(*delay, TimeDelta::hours(1));
+
+        state.store().articles.get_mut("first-post").unwrap().1["published"] = now().to_rfc3339().into();
+        state.run(QueueData::DeliveryNewArticleToAll {
+            slug: "first-post".to_owned(),
+        });
+        let enqueued = state.take_enqueued();
+        let [
+            (
+                QueueData::DeliveryActivityBatch {
+                    activity: OutboundActivity::CreateArticle { .. },
+                    ..
+                },
+                None,
+            ),
+        ] = enqueued.as_slice()
+        else {
+            panic!("unexpected fan-out: {enqueued:?}");
+        };
+    }
+
+    #[test]
+    fn activity_that_failed_to_be_stored_is_processed_again() {
+        let state = TestState::new();
+        let like = json!({
+            "@context": "https://www.w3.org/ns/activitystreams",
+            "id": "https://remote.test/likes/1",
+            "type": "Like",
+            "actor": ACTOR,
+            "object": ARTICLE,
+        })
+        .to_string();
+        state.store().fail_writes = true;
+
+        let result = state.run(inbox("writer", &like, Some(ACTOR)));
+        assert!(matches!(result, ProcessQueueResult::Retry));
+        assert!(state.store().processed.is_empty());
+
+        state.store().fail_writes = false;
+        let result = state.run(inbox("writer", &like, Some(ACTOR)));
+        assert!(matches!(result, ProcessQueueResult::Finished));
+        assert_eq!(state.store().reactions.len(), 1);
+    }
+
+    #[test]
+    fn profile_update_embeds_the_deployed_actor() {
+        let state = TestState::new();
+        let person = json!({
+            "@context": ["https://www.w3.org/ns/activitystreams", "https://w3id.org/security/v1"],
+            "id": "https://blog.test/users/writer",
+            "type": "Person",
+            "name": "Writer",
+        });
+        state.store().users.insert("writer".to_owned(), person);
+
+        state.run(QueueData::DeliveryUpdateUserToAll {
+            username: "writer".to_owned(),
+        });
+
+        let enqueued = state.take_enqueued();
+        let [
+            (
+                QueueData::DeliveryActivityBatch {
+                    author,
+                    activity: OutboundActivity::Signed { id, body },
+                    ..
+                },
+                None,
+            ),
+        ] = enqueued.as_slice()
+        else {
+            panic!("unexpected fan-out: {enqueued:?}");
+        };
+        assert_eq!(author, "writer");
+        let update = serde_json::from_str::<Value>(body).unwrap();
+        assert_eq!(update["id"], id.as_str());
+        assert_eq!(update["type"], "Update");
+        assert_eq!(update["actor"], "https://blog.test/users/writer");
+        assert_eq!(update["@context"][1], "https://w3id.org/security/v1");
+        assert_eq!(update["object"]["name"], "Writer");
+        assert!(update["object"].get("@context").is_none());
+    }
+
+    #[test]
+    fn deleted_user_followers_are_removed_after_the_last_batch() {
+        let state = TestState::new();
+        for i in 0..12 {
+            let follower = (
+                "writer".to_owned(),
+                format!("https://remote{i:02}.test/users/a"),
+                format!("https://remote{i:02}.test/inbox"),
+                format!("https://remote{i:02}.test/follows/1"),
+            );
+            state.store().followers.push(follower);
+        }
+
+        state.run(QueueData::DeliveryDeleteUserToAll {
+            username: "writer".to_owned(),
+        });
+        assert_eq!(state.store().deleted_users.get("writer"), Some(&now()));
+        let mut enqueued = state.take_enqueued();
+        let Some((batch @ QueueData::DeliveryActivityBatch { then: Some(_), .. }, None)) = enqueued.pop() else {
+            panic!("unexpected fan-out: {enqueued:?}");
+        };
+        let QueueData::DeliveryActivityBatch {
+            activity: OutboundActivity::Signed { id, .. },
+            ..
+        } = &batch
+        else {
+            panic!("unexpected activity: {batch:?}");
+        };
+        assert_eq!(id, &format!("https://blog.test/users/writer#delete/{}", now().timestamp_millis()));
+
+        state.run(batch);
+        let mut enqueued = state.take_enqueued();
+        assert_eq!(enqueued.len(), 11);
+        let (batch @ QueueData::DeliveryActivityBatch { then: Some(_), .. }, None) = enqueued.remove(0) else {
+            panic!("unexpected batch: {enqueued:?}");
+        };
+        assert_eq!(state.store().followers.len(), 12);
+
+        state.run(batch);
+        let mut enqueued = state.take_enqueued();
+        assert_eq!(enqueued.len(), 3);
+        let (then @ QueueData::RemoveUserFollowers { .. }, None) = enqueued.remove(0) else {
+            panic!("unexpected batch: {enqueued:?}");
+        };
+
+        state.run(then);
+        assert!(state.store().followers.is_empty());
+    }
+
+    #[test]
+    fn delivery_is_signed_with_the_current_key_of_its_author() {
+        let state = TestState::new();
+
+        state.run(delivery(0, None));
+
+        let signatures = state.store().signatures.clone();
+        let [signature] = signatures.as_slice() else {
+            panic!("unexpected signatures: {signatures:?}");
+        };
+        assert!(signature.starts_with(r#"keyId="https://blog.test/users/writer#key-2","#));
+    }
+
+    #[test]
+    fn profile_update_publishes_the_retiring_keys() {
+        let state = TestState::new();
+        let person = json!({ "id": "https://blog.test/users/writer", "type": "Person" });
+        state.store().users.insert("writer".to_owned(), person);
+
+        state.run(QueueData::DeliveryUpdateUserToAll {
+            username: "writer".to_owned(),
+        });
+
+        let enqueued = state.take_enqueued();
+        let [
+            (
+                QueueData::DeliveryActivityBatch {
+                    activity: OutboundActivity::Signed { body, .. },
+                    ..
+                },
+                None,
+            ),
+        ] = enqueued.as_slice()
+        else {
+            panic!("unexpected fan-out: {enqueued:?}");
+        };
+        let update = serde_json::from_str::<Value>(body).unwrap();
+        let key_ids = update["object"]["publicKey"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|key| key["id"].clone())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            key_ids,
+            ["https://blog.test/users/writer#key-2", "https://blog.test/users/writer#main-key"]
+        );
+    }
+
+    #[test]
+    fn relay_is_followed_through_the_public_collection() {
+        let state = TestState::new();
+        let relay_actor = json!({ "id": RELAY, "type": "Application", "inbox": "https://relay.test/inbox" });
+        state.store().documents.insert(RELAY.to_owned(), relay_actor);
+
+        state.run(QueueData::SubscribeRelay {
+            username: "writer".to_owned(),
+            actor: RELAY.to_owned(),
+            kind: RelayKind::Mastodon,
+        });
+
+        let relay = state.store().relays[RELAY].clone();
+        assert_eq!(relay.inbox, "https://relay.test/inbox");
+        assert_eq!(relay.accepted_at, None);
+        let enqueued = state.take_enqueued();
+        let [
+            (
+                QueueData::DeliveryActivity {
+                    inbox,
+                    activity: OutboundActivity::Signed { id, body },
+                    ..
+                },
+                None,
+            ),
+        ] = enqueued.as_slice()
+        else {
+            panic!("unexpected follow: {enqueued:?}");
+        };
+        assert_eq!(inbox, "https://relay.test/inbox");
+        assert_eq!(id, &relay.follow_id);
+        let follow = serde_json::from_str::<Value>(body).unwrap();
+        assert_eq!(follow["type"], "Follow");
+        assert_eq!(follow["object"], "https://www.w3.org/ns/activitystreams#Public");
+    }
+
+    #[test]
+    fn accept_from_a_relay_activates_it() {
+        let state = TestState::new();
+        let follow_id = "https://blog.test/users/writer#follows/1";
+        state.store().relays.insert(RELAY.to_owned(), relay(follow_id, None));
+        let accept = json!({
+            "@context": "https://www.w3.org/ns/activitystreams",
+            "id": "https://relay.test/accepts/1",
+            "type": "Accept",
+            "actor": RELAY,
+            "object": follow_id,
+        });
+
+        state.run(inbox("writer", &accept.to_string(), Some(RELAY)));
+
+        assert_eq!(state.store().relays[RELAY].accepted_at, Some(now()));
+    }
+
+    #[test]
+    fn article_fan_out_reaches_accepted_relays() {
+        let state = TestState::new();
+        state
+            .store()
+            .relays
+            .insert(RELAY.to_owned(), relay("https://blog.test/users/writer#follows/1", Some(now())));
+
+        state.run(QueueData::DeliveryNewArticleToAll {
+            slug: "first-post".to_owned(),
+        });
+
+        let enqueued = state.take_enqueued();
+        let [
+            (QueueData::DeliveryActivity { inbox, .. }, None),
+            (QueueData::DeliveryActivityBatch { .. }, None),
+        ] = enqueued.as_slice()
+        else {
+            panic!("unexpected fan-out: {enqueued:?}");
+        };
+        assert_eq!(inbox, "https://relay.test/inbox");
+    }
+
+    #[test]
+    fn article_fan_out_is_retried_when_the_relays_cannot_be_read() {
+        let state = TestState::new();
+        state.store().fail_relay_reads = true;
+
+        let result = state.run(QueueData::DeliveryNewArticleToAll {
+            slug: "first-post".to_owned(),
+        });
+
+        assert!(matches!(result, ProcessQueueResult::Retry));
+        assert!(state.take_enqueued().is_empty());
+    }
+
+    #[test]
+    fn follow_of_an_account_is_sent_to_its_inbox() {
+        let state = TestState::new();
+        state.store().users.insert("writer".to_owned(), json!({ "type": "Person" }));
+
+        state.run(QueueData::FollowAccount {
+            username: "writer".to_owned(),
+            actor: ACTOR.to_owned(),
+        });
+
+        let following = state.store().following.clone();
+        let [
+            Following {
+                accepted_at: None,
+                follow_id,
+                ..
+            },
+        ] = following.as_slice()
+        else {
+            panic!("unexpected following: {following:?}");
+        };
+        let enqueued = state.take_enqueued();
+        let [
+            (
+                QueueData::DeliveryActivity {
+                    inbox,
+                    activity: OutboundActivity::Signed { id, body },
+                    ..
+                },
+                None,
+            ),
+        ] = enqueued.as_slice()
+        else {
+            panic!("unexpected follow: {enqueued:?}");
+        };
+        assert_eq!(inbox, REMOTE_INBOX);
+        assert_eq!(id, follow_id);
+        let follow = serde_json::from_str::<Value>(body).unwrap();
+        assert_eq!(follow["type"], "Follow");
+        assert_eq!(follow["object"], ACTOR);
+    }
+
+    #[test]
+    fn accept_from_the_followed_actor_marks_the_follow_accepted() {
+        let state = TestState::new();
+        let follow_id = "https://blog.test/users/writer#follows/1";
+        state.store().following.push(following(follow_id));
+        let accept = json!({
+            "@context": "https://www.w3.org/ns/activitystreams",
+            "id": "https://remote.test/accepts/1",
+            "type": "Accept",
+            "actor": ACTOR,
+            "object": { "id": follow_id, "type": "Follow" },
+        });
+        // an accept fetched from its origin does not prove who is delivering it
+        state.store().documents.insert("https://remote.test/accepts/1".to_owned(), accept.clone());
+        state.run(inbox("writer", &accept.to_string(), Some(FORWARDER)));
+        assert_eq!(state.store().following[0].accepted_at, None);
+
+        state.store().processed.clear();
+        state.run(inbox("writer", &accept.to_string(), Some(ACTOR)));
+        assert_eq!(state.store().following[0].accepted_at, Some(now()));
+    }
+
+    #[test]
+    fn reject_removes_only_the_rejected_follow() {
+        let state = TestState::new();
+        state.store().following.push(following("https://blog.test/users/writer#follows/1"));
+        let relay = Relay {
+            kind: RelayKind::LitePub,
+            ..relay("https://blog.test/users/writer#follows/2", None)
+        };
+        state.store().relays.insert(RELAY.to_owned(), relay);
+        let reject = |id: &str, actor: &str, follow_id: &str| {
+            json!({
+                "@context": "https://www.w3.org/ns/activitystreams",
+                "id": id,
+                "type": "Reject",
+                "actor": actor,
+                "object": follow_id,
+            })
+            .to_string()
+        };
+
+        state.run(inbox(
+            "writer",
+            &reject("https://remote.test/rejects/1", ACTOR, "https://blog.test/users/writer#follows/1"),
+            Some(ACTOR),
+        ));
+        assert!(state.store().following.is_empty());
+
+        state.run(inbox(
+            "writer",
+            &reject("https://relay.test/rejects/1", RELAY, "https://blog.test/users/writer#follows/1"),
+            Some(RELAY),
+        ));
+        assert!(state.store().relays.contains_key(RELAY));
+
+        state.run(inbox(
+            "writer",
+            &reject("https://relay.test/rejects/2", RELAY, "https://blog.test/users/writer#follows/2"),
+            Some(RELAY),
+        ));
+        assert!(state.store().relays.is_empty());
+    }
+
+    #[test]
+    fn follow_is_accepted_through_a_delivery_job() {
+        let state = TestState::new();
+        state.store().users.insert("writer".to_owned(), json!({ "type": "Person" }));
+        let follow = json!({
+            "@context": "https://www.w3.org/ns/activitystreams",
+            "id": "https://remote.test/follows/1",
+            "type": "Follow",
+            "actor": ACTOR,
+            "object": "https://blog.test/users/writer",
+        });
+
+        state.run(inbox("writer", &follow.to_string(), Some(ACTOR)));
+
+        assert_eq!(state.store().followers.len(), 1);
+        let enqueued = state.take_enqueued();
+        let [
+            (
+                QueueData::DeliveryActivity {
+                    inbox,
+                    activity: OutboundActivity::Signed { id, body },
+                    attempt: 0,
+                    ..
+                },
+                None,
+            ),
+        ] = enqueued.as_slice()
+        else {
+            panic!("unexpected accept: {enqueued:?}");
+        };
+        assert_eq!(inbox, REMOTE_INBOX);
+        let accept = serde_json::from_str::<Value>(body).unwrap();
+        assert_eq!(accept["id"], id.as_str());
+        assert_eq!(accept["type"], "Accept");
+        assert_eq!(accept["object"]["id"], "https://remote.test/follows/1");
+    }
+
+    #[test]
+    fn article_published_or_deleted_again_reaches_every_inbox_again() {
+        let state = TestState::new();
+        for event in ["create", "delete"] {
+            let delivery = (format!("https://blog.test/events/articles/{event}/first-post"), REMOTE_INBOX.to_owned());
+            state.store().deliveries.insert(delivery);
+        }
+
+        state.run(QueueData::DeliveryNewArticleToAll {
+            slug: "first-post".to_owned(),
+        });
+        state.run(QueueData::DeliveryDeleteArticleToAll {
+            slug: "first-post".to_owned(),
+            author: "writer".to_owned(),
+        });
+
+        assert!(state.store().deliveries.is_empty());
+    }
+
+    #[test]
+    fn unavailable_inbox_counts_as_a_failure_and_backs_off_the_host() {
+        let state = TestState::new();
+        state.store().inbox_response = Some((StatusCode::SERVICE_UNAVAILABLE, None));
+
+        state.run(delivery(0, None));
+
+        let enqueued = state.take_enqueued();
+        let [(QueueData::DeliveryActivity { attempt: 1, .. }, Some(delay))] = enqueued.as_slice() else {
+            panic!("unexpected retry: {enqueued:?}");
+        };
+        let backoff = state.host_delivery_policy().backoff;
+        assert_eq!(*delay, backoff);
+        let store = state.store();
+        assert_eq!(store.failures, ["https://remote.test"]);
+        assert_eq!(store.backed_off, [("https://remote.test".to_owned(), now() + backoff)]);
+    }
+
+    #[test]
+    fn relay_whose_actor_is_missing_is_given_up() {
+        let state = TestState::new();
+
+        let result = state.run(QueueData::SubscribeRelay {
+            username: "writer".to_owned(),
+            actor: RELAY.to_owned(),
+            kind: RelayKind::Mastodon,
+        });
+
+        assert!(matches!(result, ProcessQueueResult::Finished));
+        assert!(state.store().relays.is_empty());
+        assert!(state.take_enqueued().is_empty());
+    }
+
+    #[test]
+    fn follow_of_a_missing_account_is_given_up() {
+        let state = TestState::new();
+        state.store().users.insert("writer".to_owned(), json!({ "type": "Person" }));
+
+        let result = state.run(QueueData::FollowAccount {
+            username: "writer".to_owned(),
+            actor: "https://remote.test/users/nobody".to_owned(),
+        });
+
+        assert!(matches!(result, ProcessQueueResult::Finished));
+        assert!(state.store().following.is_empty());
+        assert!(state.take_enqueued().is_empty());
     }
 }
@@ -1,4 +1,5 @@
 use crate::common::headers::{AP_RESPONSE_MIME, AcceptMime, AcceptMimeSet, HeaderReader};
+use crate::common::publication;
 use crate::traits::{ArticleProvider, Env};
 use axum::Json;
 use axum::body::Body;
@@ -49,10 +50,11 @@ where
         }
         Some(AcceptMime::AP) => {
             tracing::info!("accept ap");
-            match state.get_article_ap(&slug).await {
+            // the HTML page is published with the site, only the federated Note waits for its date
+            match publication::published_article_ap(&state, &slug).await {
                 Some(body) => {
                     tracing::info!("found article");
-                    Response::builder().header(CONTENT_TYPE, AP_RESPONSE_MIME).body(body).unwrap()
+                    Response::builder().header(CONTENT_TYPE, AP_RESPONSE_MIME).body(Body::from(body)).unwrap()
                 }
                 None => {
                     tracing::info!("article is not found");
@@ -70,7 +72,7 @@ where
 #[tracing::instrument(skip(state))]
 async fn article_metadata_get<E>(header: HeaderMap, slug: String, state: E) -> Response<Body>
 where
-    E: ArticleProvider,
+    E: Env + ArticleProvider,
 {
     if !state.exists_article(&slug).await {
         return StatusCode::NOT_FOUND.into_response();
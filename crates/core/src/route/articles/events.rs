@@ -1,4 +1,5 @@
 use crate::common::headers::{AP_RESPONSE_MIME, AcceptMime, AcceptMimeSet, HeaderReader};
+use crate::common::{integrity, publication};
 use crate::json_format;
 use crate::traits::{ArticleProvider, Env};
 use axum::body::Body;
@@ -17,7 +18,7 @@ where
     let Some(AcceptMime::AP) = header.select(AcceptMimeSet::AP) else {
         return StatusCode::NOT_ACCEPTABLE.into_response();
     };
-    if !state.exists_article(slug).await {
+    if !state.exists_article(slug).await || publication::is_scheduled(&state, slug).await {
         return StatusCode::NOT_FOUND.into_response();
     }
 
@@ -32,7 +33,9 @@ where
         "type": "Create",
         "actor": actor,
         "object": object,
+        "to": ["https://www.w3.org/ns/activitystreams#Public"],
     };
+    let body = integrity::sign_activity(&state, &author, body);
     Response::builder()
         .header(CONTENT_TYPE, AP_RESPONSE_MIME)
         .body(body)
@@ -50,7 +53,7 @@ where
     let Some(AcceptMime::AP) = header.select(AcceptMimeSet::AP) else {
         return StatusCode::NOT_ACCEPTABLE.into_response();
     };
-    if !state.exists_article(slug).await {
+    if !state.exists_article(slug).await || publication::is_scheduled(&state, slug).await {
         return StatusCode::NOT_FOUND.into_response();
     }
 
@@ -65,7 +68,9 @@ where
         "type": "Update",
         "actor": actor,
         "object": object,
+        "to": ["https://www.w3.org/ns/activitystreams#Public"],
     };
+    let body = integrity::sign_activity(&state, &author, body);
     Response::builder()
         .header(CONTENT_TYPE, AP_RESPONSE_MIME)
         .body(body)
@@ -98,7 +103,9 @@ where
         "type": "Delete",
         "actor": actor,
         "object": object,
+        "to": ["https://www.w3.org/ns/activitystreams#Public"],
     };
+    let body = integrity::sign_activity(&state, &author, body);
     Response::builder()
         .header(CONTENT_TYPE, AP_RESPONSE_MIME)
         .body(body)
@@ -1,19 +1,23 @@
+use crate::common::actor;
 use crate::common::headers::{AP_RESPONSE_MIME, AcceptMime, AcceptMimeSet, HeaderReader};
-use crate::traits::UserProvider;
+use crate::common::macros::json_format;
+use crate::traits::{Env, UserProvider};
 use axum::body::Body;
 use axum::extract::{Path, State};
 use axum::http::header::CONTENT_TYPE;
 use axum::http::{HeaderMap, StatusCode};
 use axum::response::{IntoResponse, Response};
+use chrono::SecondsFormat;
 
 pub(crate) mod accept_follow;
+pub(crate) mod following;
 pub(crate) mod inbox;
 pub(crate) mod outbox;
 
 #[tracing::instrument(skip(state))]
 pub async fn user_get<E>(header: HeaderMap, Path(username): Path<String>, State(state): State<E>) -> Response<Body>
 where
-    E: UserProvider,
+    E: Env + UserProvider,
 {
     let header = HeaderReader::new(&header);
     match header.select(AcceptMimeSet::HTML | AcceptMimeSet::AP) {
@@ -24,6 +28,10 @@ where
                     tracing::info!("found user");
                     Response::builder().header(CONTENT_TYPE, mime::TEXT_HTML.as_ref()).body(body).unwrap()
                 }
+                None if state.user_deleted_at(&username).await.is_some() => {
+                    tracing::info!("user is deleted");
+                    StatusCode::GONE.into_response()
+                }
                 None => {
                     tracing::info!("user is not found");
                     StatusCode::NOT_FOUND.into_response()
@@ -35,12 +43,35 @@ where
             match state.get_user_ap(&username).await {
                 Some(body) => {
                     tracing::info!("found user");
+                    let Some(body) = actor::actor_document(&state, &username, body).await else {
+                        tracing::error!("failed to add the keys to the actor");
+                        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+                    };
                     Response::builder().header(CONTENT_TYPE, AP_RESPONSE_MIME).body(body).unwrap()
                 }
-                None => {
-                    tracing::info!("user is not found");
-                    StatusCode::NOT_FOUND.into_response()
-                }
+                None => match state.user_deleted_at(&username).await {
+                    Some(deleted_at) => {
+                        tracing::info!("user is deleted");
+                        let id = serde_json::to_string(&format!("{}/users/{username}", state.url())).unwrap();
+                        let deleted = serde_json::to_string(&deleted_at.to_rfc3339_opts(SecondsFormat::Secs, true)).unwrap();
+                        let body = json_format! {
+                            "@context": "https://www.w3.org/ns/activitystreams",
+                            "id": id,
+                            "type": "Tombstone",
+                            "formerType": "Person",
+                            "deleted": deleted,
+                        };
+                        Response::builder()
+                            .status(StatusCode::GONE)
+                            .header(CONTENT_TYPE, AP_RESPONSE_MIME)
+                            .body(Body::from(body))
+                            .unwrap()
+                    }
+                    None => {
+                        tracing::info!("user is not found");
+                        StatusCode::NOT_FOUND.into_response()
+                    }
+                },
             }
         }
         _ => {
@@ -1,5 +1,5 @@
+use crate::common::follow;
 use crate::common::headers::{AP_RESPONSE_MIME, AcceptMimeSet, HeaderReader};
-use crate::common::macros::json_format;
 use crate::traits::{Env, UserProvider};
 use axum::body::Body;
 use axum::extract::{Path, Query, State};
@@ -7,7 +7,6 @@ use axum::http::header::CONTENT_TYPE;
 use axum::http::{HeaderMap, StatusCode};
 use axum::response::{IntoResponse, Response};
 use serde::Deserialize;
-use url::Url;
 
 #[derive(Debug, Deserialize)]
 pub struct AcceptFollowQuery {
@@ -32,25 +31,16 @@ where
         tracing::info!("not accepted ap");
         return StatusCode::NOT_ACCEPTABLE.into_response();
     }
-    let url = state.url();
-    let url = Url::parse_with_params(&format!("{url}/users/{username}/accept_follow"), [("object", &query.object)]).unwrap();
-    let accept_actor = serde_json::to_string(&format!("{url}/users/{username}")).unwrap();
-    let follow_actor = serde_json::to_string(&query.object).unwrap();
-    let body = json_format! {
-        "@context": "https://www.w3.org/ns/activitystreams",
-        "id": url,
-        "type": "Accept",
-        "actor": accept_actor,
-        "object": {
-            "type": "Follow",
-            "actor": follow_actor,
-            "object": accept_actor,
-        },
+    // accepts delivered by earlier versions carry the follow id as a JSON string
+    let follow_id = serde_json::from_str::<String>(&query.object).unwrap_or(query.object);
+    let Some(follower) = state.get_follower_by_event_id(&username, &follow_id).await else {
+        tracing::info!(follow_id, "follow is not found");
+        return StatusCode::NOT_FOUND.into_response();
     };
-    tracing::info!("body: {}", body);
+    let (_, body) = follow::accept_activity(&state, &username, &follower, &follow_id);
     Response::builder()
         .header(CONTENT_TYPE, AP_RESPONSE_MIME)
-        .body(body)
+        .body(Body::from(body))
         .unwrap()
         .into_response()
 }
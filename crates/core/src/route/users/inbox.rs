@@ -1,18 +1,28 @@
-use crate::traits::{HTTPClient, Queue, QueueData, UserProvider};
+use crate::route::ClientAddr;
+use crate::traits::{Env, HTTPClient, InboxRateLimits, PublicKeyCache, Queue, QueueData, RateLimiter, UserProvider};
 use crate::verify::{VerifiedRequest, verify_request};
+use axum::Extension;
 use axum::body::Body;
 use axum::extract::{Path, State};
-use axum::http::header::CONTENT_TYPE;
+use axum::http::header::{CONTENT_TYPE, RETRY_AFTER};
 use axum::http::{HeaderMap, StatusCode};
 use axum::response::{IntoResponse, Response};
+use chrono::TimeDelta;
 use mime::Mime;
 use serde::Deserialize;
 use std::str::FromStr;
+use url::Url;
 
 #[tracing::instrument(skip(state))]
-pub async fn user_inbox_post<E>(header: HeaderMap, Path(username): Path<String>, State(state): State<E>, body: Body) -> Response<Body>
+pub async fn user_inbox_post<E>(
+    header: HeaderMap,
+    Path(username): Path<String>,
+    State(state): State<E>,
+    client: Option<Extension<ClientAddr>>,
+    body: Body,
+) -> Response<Body>
 where
-    E: UserProvider + Queue + HTTPClient,
+    E: Env + UserProvider + Queue + HTTPClient + PublicKeyCache + RateLimiter,
 {
     if !state.exists_user(&username).await {
         tracing::info!("user is not found");
@@ -27,6 +37,13 @@ where
         tracing::info!("invalid content type");
         return StatusCode::BAD_REQUEST.into_response();
     }
+    let limits = state.inbox_rate_limits();
+    // the signature is not verified yet, so only the connection itself is trusted
+    if let Some(Extension(ClientAddr(addr))) = client
+        && let Some(retry_after) = state.hit_rate_limit(&format!("inbox:client:{addr}"), limits.per_client).await
+    {
+        return too_many_requests(retry_after);
+    }
     let mut req_builder = axum::http::Request::builder().method("POST").uri(format!("/users/{username}/inbox"));
     for (name, value) in header.iter() {
         if let Ok(v) = value.to_str() {
@@ -64,6 +81,11 @@ where
         }
         VerifiedRequest::VerifyFailed => return StatusCode::BAD_REQUEST.into_response(),
     };
+    if let Some(actor) = &verified_actor
+        && let Some(retry_after) = check_signer_rate_limits(&state, actor, limits).await
+    {
+        return too_many_requests(retry_after);
+    }
     let data = match String::from_utf8(bytes.to_vec()) {
         Ok(s) => s,
         Err(_) => return StatusCode::BAD_REQUEST.into_response(),
@@ -75,6 +97,7 @@ where
             ty: inbox.ty,
             id: inbox.id.clone(),
             verified_body: verified_actor.is_some().then(|| data.clone()),
+            unverified_body: (verified_actor.is_none() && (inbox.proof.is_some() || inbox.signature.is_some())).then(|| data.clone()),
             verified_actor,
         }
     } else {
@@ -82,7 +105,10 @@ where
         return StatusCode::BAD_REQUEST.into_response();
     };
     tracing::info!("enqueue data: {queue_data:?}");
-    state.enqueue(queue_data).await;
+    if let Err(e) = state.enqueue(queue_data).await {
+        tracing::error!(error = ?e, "failed to enqueue inbox data");
+        return StatusCode::SERVICE_UNAVAILABLE.into_response();
+    }
     return StatusCode::ACCEPTED.into_response();
 
     #[derive(Debug, Deserialize)]
@@ -90,5 +116,28 @@ where
         id: String,
         #[serde(rename = "type")]
         ty: String,
+        proof: Option<serde::de::IgnoredAny>,
+        signature: Option<serde::de::IgnoredAny>,
+    }
+}
+
+/// Charges the budgets of the verified `actor` and of its host.
+async fn check_signer_rate_limits<E>(state: &E, actor: &str, limits: InboxRateLimits) -> Option<TimeDelta>
+where
+    E: RateLimiter,
+{
+    let host = Url::parse(actor).ok()?.host_str()?.to_owned();
+    if let Some(retry_after) = state.hit_rate_limit(&format!("inbox:host:{host}"), limits.per_host).await {
+        return Some(retry_after);
     }
+    state.hit_rate_limit(&format!("inbox:actor:{actor}"), limits.per_actor).await
+}
+
+fn too_many_requests(retry_after: TimeDelta) -> Response<Body> {
+    tracing::info!(retry_after = retry_after.num_seconds(), "inbox rate limit exceeded");
+    (
+        StatusCode::TOO_MANY_REQUESTS,
+        [(RETRY_AFTER, retry_after.num_seconds().max(1).to_string())],
+    )
+        .into_response()
 }
@@ -1,19 +1,53 @@
+use crate::verify::SignaturePolicy;
 use arrayvec::ArrayVec;
 use axum::body::Body;
 use axum::http::Request;
 use bytes::Bytes;
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, TimeDelta, Utc};
 use rsa::pkcs1v15::SigningKey;
 use serde::{Deserialize, Serialize};
 use std::error::Error;
 use std::fmt::Display;
 
 pub type RSASHA2SigningKey = SigningKey<rsa::sha2::Sha256>;
+pub type Ed25519SigningKey = ring_compat::signature::ed25519::SigningKey;
+
+/// RSA key signing the requests of a user, `id` is the fragment of its ID on their actor.
+#[derive(Clone, Copy)]
+pub struct UserSigningKey<'a> {
+    pub id: &'a str,
+    pub key: &'a RSASHA2SigningKey,
+}
+
+impl UserSigningKey<'_> {
+    pub fn key_id(&self, actor_id: impl Display) -> String {
+        format!("{actor_id}#{}", self.id)
+    }
+}
 
 pub trait Env {
     fn url(&self) -> impl Display + Send + '_;
     fn timestamp_now(&self) -> DateTime<Utc>;
-    fn signing_key(&self) -> &RSASHA2SigningKey;
+    /// Key signing the requests made on behalf of `username`.
+    fn signing_key(&self, username: &str) -> UserSigningKey<'_>;
+    /// Previous keys of `username` that stay published on their actor while a key rotation propagates.
+    fn retiring_keys(&self, _username: &str) -> Vec<UserSigningKey<'_>> {
+        Vec::new()
+    }
+    /// Key for Object Integrity Proofs on activities of `username`, if the backend has one configured.
+    fn integrity_key(&self, username: &str) -> Option<Ed25519SigningKey>;
+    fn signature_policy(&self) -> SignaturePolicy {
+        SignaturePolicy::default()
+    }
+    fn inbox_rate_limits(&self) -> InboxRateLimits {
+        InboxRateLimits::default()
+    }
+    fn delivery_retry_policy(&self) -> DeliveryRetryPolicy {
+        DeliveryRetryPolicy::default()
+    }
+    fn host_delivery_policy(&self) -> HostDeliveryPolicy {
+        HostDeliveryPolicy::default()
+    }
 }
 
 #[derive(Debug, Serialize)]
@@ -36,27 +70,45 @@ pub struct ArticleNewReaction {
 }
 
 pub trait ArticleProvider {
+    type Error: Error + Send;
     fn exists_article(&self, slug: &str) -> impl Future<Output = bool> + Send;
     fn get_article_html(&self, slug: &str) -> impl Future<Output = Option<Body>> + Send;
     fn get_article_ap(&self, slug: &str) -> impl Future<Output = Option<Body>> + Send;
     fn get_author_id(&self, slug: &str) -> impl Future<Output = Option<String>> + Send;
 
-    fn add_comment(&self, slug: &str, comment: ArticleNewComment) -> impl Future<Output = ()> + Send;
-    fn add_reaction(&self, slug: &str, reaction: ArticleNewReaction) -> impl Future<Output = ()> + Send;
-    fn remove_reaction_by(&self, slug: &str, actor: &str) -> impl Future<Output = ()> + Send;
+    fn add_comment(&self, slug: &str, comment: ArticleNewComment) -> impl Future<Output = Result<(), Self::Error>> + Send;
+    fn add_reaction(&self, slug: &str, reaction: ArticleNewReaction) -> impl Future<Output = Result<(), Self::Error>> + Send;
+    fn remove_reaction_by(&self, slug: &str, actor: &str) -> impl Future<Output = Result<(), Self::Error>> + Send;
     fn comment_count(&self, slug: &str) -> impl Future<Output = usize> + Send;
     fn reaction_count(&self, slug: &str) -> impl Future<Output = usize> + Send;
 }
 
 pub trait UserProvider {
+    type Error: Error + Send;
     fn exists_user(&self, username: &str) -> impl Future<Output = bool> + Send;
     fn get_user_html(&self, username: &str) -> impl Future<Output = Option<Body>> + Send;
     fn get_user_ap(&self, username: &str) -> impl Future<Output = Option<Body>> + Send;
 
-    fn add_follower(&self, username: &str, follower_id: &str, inbox: &str, event_id: &str) -> impl Future<Output = ()> + Send;
-    fn remove_follower(&self, username: &str, event_id: &str) -> impl Future<Output = ()> + Send;
-    fn remove_follower_by_actor(&self, username: &str, actor: &str) -> impl Future<Output = ()> + Send;
-    fn get_followers_inbox_batch(&self, username: &str, last_inbox: &str) -> impl Future<Output = (ArrayVec<String, 10>, String)> + Send;
+    fn add_follower(&self, username: &str, follower_id: &str, inbox: &str, event_id: &str) -> impl Future<Output = Result<(), Self::Error>> + Send;
+    /// Returns the actor that sent the follow `event_id` to `username`.
+    fn get_follower_by_event_id(&self, username: &str, event_id: &str) -> impl Future<Output = Option<String>> + Send;
+    fn remove_follower(&self, username: &str, event_id: &str) -> impl Future<Output = Result<(), Self::Error>> + Send;
+    fn remove_follower_by_actor(&self, username: &str, actor: &str) -> impl Future<Output = Result<(), Self::Error>> + Send;
+    /// Removes the followers of every user that receive activities at `inbox`.
+    fn remove_followers_by_inbox(&self, inbox: &str) -> impl Future<Output = Result<(), Self::Error>> + Send;
+    /// Removes the followers of every user whose inbox is on `origin`.
+    fn remove_followers_by_origin(&self, origin: &str) -> impl Future<Output = Result<(), Self::Error>> + Send;
+    /// Returns the next inboxes after `last_inbox`, leaving out hosts marked unavailable.
+    fn get_followers_inbox_batch(
+        &self,
+        username: &str,
+        last_inbox: &str,
+    ) -> impl Future<Output = Result<(ArrayVec<String, 10>, String), Self::Error>> + Send;
+    /// Removes every follower of `username`.
+    fn remove_followers_of(&self, username: &str) -> impl Future<Output = Result<(), Self::Error>> + Send;
+    /// Returns when `username` was deleted, their actor is served as a Tombstone from then on.
+    fn user_deleted_at(&self, username: &str) -> impl Future<Output = Option<DateTime<Utc>>> + Send;
+    fn mark_user_deleted(&self, username: &str, deleted_at: DateTime<Utc>) -> impl Future<Output = Result<(), Self::Error>> + Send;
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -69,6 +121,9 @@ pub enum QueueData {
         verified_body: Option<String>,
         #[serde(default)]
         verified_actor: Option<String>,
+        /// Body of a request without a valid HTTP signature that may still carry an integrity proof.
+        #[serde(default)]
+        unverified_body: Option<String>,
     },
     DeliveryNewArticleToAll {
         slug: String,
@@ -80,6 +135,55 @@ pub enum QueueData {
         slug: String,
         author: String,
     },
+    /// Sends an `Update` with the current actor of `username` to their followers.
+    DeliveryUpdateUserToAll {
+        username: String,
+    },
+    /// Sends a `Delete` of the actor of a removed user to their followers, then removes the followers.
+    DeliveryDeleteUserToAll {
+        username: String,
+    },
+    RemoveUserFollowers {
+        username: String,
+    },
+    /// Follows the relay of `actor` as `username`.
+    SubscribeRelay {
+        username: String,
+        actor: String,
+        kind: RelayKind,
+    },
+    UnsubscribeRelay {
+        actor: String,
+    },
+    /// Follows the remote account of `actor` as `username`.
+    FollowAccount {
+        username: String,
+        actor: String,
+    },
+    UnfollowAccount {
+        username: String,
+        actor: String,
+    },
+    /// Delivers `activity` to the followers after `last_inbox` and enqueues the batch after them.
+    DeliveryActivityBatch {
+        author: String,
+        activity: OutboundActivity,
+        last_inbox: String,
+        /// Job enqueued with the last batch, once every follower has a delivery.
+        #[serde(default)]
+        then: Option<Box<QueueData>>,
+    },
+    DeliveryActivity {
+        author: String,
+        inbox: String,
+        activity: OutboundActivity,
+        /// Number of failed attempts so far.
+        #[serde(default)]
+        attempt: u32,
+        #[serde(default)]
+        first_attempt_at: Option<DateTime<Utc>>,
+    },
+    // The article specific batches and deliveries are only kept to process jobs enqueued before the generic ones existed.
     DeliveryNewArticleBatch {
         slug: String,
         author: String,
@@ -99,21 +203,291 @@ pub enum QueueData {
         slug: String,
         author: String,
         inbox: String,
+        /// Number of failed attempts so far.
+        #[serde(default)]
+        attempt: u32,
+        #[serde(default)]
+        first_attempt_at: Option<DateTime<Utc>>,
     },
     DeliveryUpdateArticle {
         slug: String,
         author: String,
         inbox: String,
+        /// Number of failed attempts so far.
+        #[serde(default)]
+        attempt: u32,
+        #[serde(default)]
+        first_attempt_at: Option<DateTime<Utc>>,
     },
     DeliveryDeleteArticle {
         slug: String,
         author: String,
         inbox: String,
+        /// Number of failed attempts so far.
+        #[serde(default)]
+        attempt: u32,
+        #[serde(default)]
+        first_attempt_at: Option<DateTime<Utc>>,
+    },
+}
+
+/// Activity sent to the followers of an author by [`QueueData::DeliveryActivityBatch`].
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(tag = "kind")]
+pub enum OutboundActivity {
+    /// A complete activity, signed by its author when it was enqueued.
+    Signed {
+        id: String,
+        body: String,
+    },
+    /// The events of an article, built from the article when they are delivered.
+    CreateArticle {
+        slug: String,
+    },
+    UpdateArticle {
+        slug: String,
+    },
+    DeleteArticle {
+        slug: String,
     },
 }
 
 pub trait Queue {
-    fn enqueue(&self, data: QueueData) -> impl Future<Output = ()> + Send;
+    type Error: Error + Send;
+    fn enqueue(&self, data: QueueData) -> impl Future<Output = Result<(), Self::Error>> + Send;
+    /// Enqueues all of `data` with as few requests to the queue as the backend allows.
+    fn enqueue_batch(&self, data: Vec<QueueData>) -> impl Future<Output = Result<(), Self::Error>> + Send;
+    fn enqueue_with_delay(&self, data: QueueData, delay: TimeDelta) -> impl Future<Output = Result<(), Self::Error>> + Send;
+}
+
+/// Backoff for outbound deliveries that failed with a transport error, 429 or 5xx.
+#[derive(Debug, Clone, Copy)]
+pub struct DeliveryRetryPolicy {
+    pub initial_delay: TimeDelta,
+    pub max_delay: TimeDelta,
+    /// A delivery is dropped when its next attempt would be this long after the first one.
+    pub deadline: TimeDelta,
+}
+
+impl Default for DeliveryRetryPolicy {
+    fn default() -> Self {
+        Self {
+            initial_delay: TimeDelta::minutes(1),
+            max_delay: TimeDelta::hours(6),
+            deadline: TimeDelta::days(2),
+        }
+    }
+}
+
+impl DeliveryRetryPolicy {
+    /// Delay before retrying a delivery that has failed `attempt` times.
+    pub fn delay(&self, attempt: u32) -> TimeDelta {
+        self.initial_delay
+            .checked_mul(1 << attempt.saturating_sub(1).min(30))
+            .map_or(self.max_delay, |delay| delay.min(self.max_delay))
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CachedPublicKey {
+    pub actor: String,
+    pub pem: String,
+    pub expires_at: DateTime<Utc>,
+}
+
+pub trait PublicKeyCache {
+    type Error: Error + Send;
+    fn get_public_key(&self, key_id: &str) -> impl Future<Output = Option<CachedPublicKey>> + Send;
+    fn put_public_key(&self, key_id: &str, key: CachedPublicKey) -> impl Future<Output = Result<(), Self::Error>> + Send;
+}
+
+pub trait ProcessedActivityStore {
+    type Error: Error + Send;
+    /// Returns whether `activity_id` is recorded as processed for the inbox of `username`.
+    fn is_activity_processed(&self, username: &str, activity_id: &str) -> impl Future<Output = Result<bool, Self::Error>> + Send;
+    /// Records `activity_id` as processed for the inbox of `username` until `expires_at`.
+    fn mark_activity_processed(
+        &self,
+        username: &str,
+        activity_id: &str,
+        expires_at: DateTime<Utc>,
+    ) -> impl Future<Output = Result<(), Self::Error>> + Send;
+}
+
+/// Limits how hard outbound deliveries hit a single remote host.
+#[derive(Debug, Clone, Copy)]
+pub struct HostDeliveryPolicy {
+    /// Deliveries to one host that may be in flight at the same time.
+    pub max_in_flight: u32,
+    /// In-flight slots not released within this time are considered abandoned.
+    pub slot_lease: TimeDelta,
+    /// Delay for a delivery whose host has no free slot.
+    pub busy_delay: TimeDelta,
+    /// How long a host is left alone after 429 or 503 without `Retry-After`.
+    pub backoff: TimeDelta,
+}
+
+impl Default for HostDeliveryPolicy {
+    fn default() -> Self {
+        Self {
+            max_in_flight: 4,
+            slot_lease: TimeDelta::minutes(2),
+            busy_delay: TimeDelta::seconds(15),
+            backoff: TimeDelta::minutes(5),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeliverySlot {
+    Acquired,
+    /// All slots of the host are in use.
+    Busy,
+    /// The host asked us to slow down until the given time.
+    BackingOff(DateTime<Utc>),
+}
+
+/// Schedules outbound deliveries per remote host, keyed by the origin of their inboxes.
+pub trait DeliveryScheduler {
+    type Error: Error + Send;
+    /// Takes one of `max_in_flight` slots of `origin`, holding it until `lease_until` at the latest.
+    fn acquire_delivery_slot(
+        &self,
+        origin: &str,
+        max_in_flight: u32,
+        now: DateTime<Utc>,
+        lease_until: DateTime<Utc>,
+    ) -> impl Future<Output = DeliverySlot> + Send;
+    fn release_delivery_slot(&self, origin: &str) -> impl Future<Output = ()> + Send;
+    /// Holds deliveries to `origin` until `until`.
+    fn back_off_host(&self, origin: &str, until: DateTime<Utc>) -> impl Future<Output = Result<(), Self::Error>> + Send;
+}
+
+/// Remembers which inboxes an activity has been delivered to.
+pub trait DeliveryLog {
+    type Error: Error + Send;
+    fn is_delivered(&self, activity_id: &str, inbox: &str) -> impl Future<Output = bool> + Send;
+    /// Records a successful delivery of `activity_id` to `inbox`, kept until `expires_at`.
+    fn record_delivery(&self, activity_id: &str, inbox: &str, expires_at: DateTime<Utc>) -> impl Future<Output = Result<(), Self::Error>> + Send;
+    /// Forgets every delivery of `activity_id` so that it is sent again.
+    fn forget_deliveries(&self, activity_id: &str) -> impl Future<Output = ()> + Send;
+}
+
+/// Failed deliveries to a remote origin since its last successful one.
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct DeliveryFailures {
+    pub first_failed_at: DateTime<Utc>,
+    /// Number of distinct UTC days with a failed delivery.
+    pub failure_days: u32,
+}
+
+/// Tracks remote hosts by the origin of their inboxes so that fan-outs can skip unreachable ones.
+pub trait InstanceAvailability {
+    type Error: Error + Send;
+    /// Records a failed delivery to `origin` at `now` and returns its failures so far.
+    fn record_delivery_failure(&self, origin: &str, now: DateTime<Utc>) -> impl Future<Output = DeliveryFailures> + Send;
+    /// Forgets the failures of `origin` and makes it available again.
+    fn clear_delivery_failures(&self, origin: &str) -> impl Future<Output = Result<(), Self::Error>> + Send;
+    /// Leaves inboxes on `origin` out of follower batches until `until`.
+    fn mark_unavailable(&self, origin: &str, until: DateTime<Utc>) -> impl Future<Output = Result<(), Self::Error>> + Send;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RelayKind {
+    /// Relays that are followed through `as:Public`, such as pub-relay and Activity-Relay.
+    Mastodon,
+    /// Relays that are followed through their own actor, such as the Pleroma relay.
+    LitePub,
+}
+
+/// An ActivityPub relay followed by a local user, articles are delivered to it once it accepted.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Relay {
+    pub actor: String,
+    pub inbox: String,
+    pub kind: RelayKind,
+    pub username: String,
+    pub follow_id: String,
+    pub accepted_at: Option<DateTime<Utc>>,
+}
+
+pub trait RelayStore {
+    type Error: Error + Send;
+    /// Adds the relay of `relay.actor`, replacing a previous subscription to it.
+    fn add_relay(&self, relay: &Relay) -> impl Future<Output = Result<(), Self::Error>> + Send;
+    fn get_relay(&self, actor: &str) -> impl Future<Output = Option<Relay>> + Send;
+    /// Marks the relay as accepted when `follow_id` is its pending follow, returns whether it was.
+    fn accept_relay(&self, actor: &str, follow_id: &str, accepted_at: DateTime<Utc>) -> impl Future<Output = Result<bool, Self::Error>> + Send;
+    fn remove_relay(&self, actor: &str) -> impl Future<Output = Result<(), Self::Error>> + Send;
+    /// Removes the relays that deliver through `inbox`.
+    fn remove_relays_by_inbox(&self, inbox: &str) -> impl Future<Output = Result<(), Self::Error>> + Send;
+    /// Returns the inboxes of the relays that accepted their follow.
+    fn get_relay_inboxes(&self) -> impl Future<Output = Result<Vec<String>, Self::Error>> + Send;
+}
+
+/// A remote account followed by a local user, it is listed in the following collection once it accepted.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Following {
+    pub username: String,
+    pub actor: String,
+    pub inbox: String,
+    pub follow_id: String,
+    pub accepted_at: Option<DateTime<Utc>>,
+}
+
+pub trait FollowingStore {
+    type Error: Error + Send;
+    /// Adds the follow of `following.actor` by `following.username`, replacing a previous follow of it.
+    fn add_following(&self, following: &Following) -> impl Future<Output = Result<(), Self::Error>> + Send;
+    fn get_following(&self, username: &str, actor: &str) -> impl Future<Output = Option<Following>> + Send;
+    /// Marks the follow as accepted when `follow_id` is the pending follow of `actor`, returns whether it was.
+    fn accept_following(&self, actor: &str, follow_id: &str, accepted_at: DateTime<Utc>) -> impl Future<Output = Result<bool, Self::Error>> + Send;
+    /// Removes the follow when `follow_id` is the follow of `actor`, returns whether it was.
+    fn reject_following(&self, actor: &str, follow_id: &str) -> impl Future<Output = Result<bool, Self::Error>> + Send;
+    fn remove_following(&self, username: &str, actor: &str) -> impl Future<Output = Result<(), Self::Error>> + Send;
+    /// Returns the actors that accepted the follow of `username`.
+    fn get_following_actors(&self, username: &str) -> impl Future<Output = Vec<String>> + Send;
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct RateLimit {
+    pub max_requests: u32,
+    pub window: TimeDelta,
+}
+
+/// Limits applied to inbox deliveries.
+#[derive(Debug, Clone, Copy)]
+pub struct InboxRateLimits {
+    /// Per client address, checked before the signature is verified.
+    pub per_client: RateLimit,
+    /// Per host of the verified signer.
+    pub per_host: RateLimit,
+    /// Per verified signer.
+    pub per_actor: RateLimit,
+}
+
+impl Default for InboxRateLimits {
+    fn default() -> Self {
+        Self {
+            per_client: RateLimit {
+                max_requests: 600,
+                window: TimeDelta::minutes(5),
+            },
+            per_host: RateLimit {
+                max_requests: 300,
+                window: TimeDelta::minutes(5),
+            },
+            per_actor: RateLimit {
+                max_requests: 60,
+                window: TimeDelta::minutes(5),
+            },
+        }
+    }
+}
+
+pub trait RateLimiter {
+    /// Counts a request against `key`, returns how long the caller has to wait when `limit` is exceeded.
+    fn hit_rate_limit(&self, key: &str, limit: RateLimit) -> impl Future<Output = Option<TimeDelta>> + Send;
 }
 
 pub trait HTTPClient {
@@ -123,7 +497,8 @@ pub trait HTTPClient {
 
 #[cfg(test)]
 mod tests {
-    use super::QueueData;
+    use super::{DeliveryRetryPolicy, OutboundActivity, QueueData, RelayKind};
+    use chrono::TimeDelta;
 
     #[test]
     fn old_inbox_queue_data_defaults_to_an_unverified_actor() {
@@ -138,6 +513,93 @@ mod tests {
         )
         .unwrap();
 
-        assert!(matches!(data, QueueData::Inbox { verified_actor: None, .. }));
+        assert!(matches!(
+            data,
+            QueueData::Inbox {
+                verified_actor: None,
+                unverified_body: None,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn old_delivery_queue_data_starts_at_the_first_attempt() {
+        let data = serde_json::from_str::<QueueData>(
+            r#"{
+                "event_type": "DeliveryNewArticle",
+                "slug": "first-post",
+                "author": "default",
+                "inbox": "https://social.example/inbox"
+            }"#,
+        )
+        .unwrap();
+
+        assert!(matches!(
+            data,
+            QueueData::DeliveryNewArticle {
+                attempt: 0,
+                first_attempt_at: None,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn delivery_activity_queue_data_carries_its_activity() {
+        let data = serde_json::from_str::<QueueData>(
+            r#"{
+                "event_type": "DeliveryActivity",
+                "author": "default",
+                "inbox": "https://social.example/inbox",
+                "activity": {
+                    "kind": "Signed",
+                    "id": "https://blog.example/users/default#update-1",
+                    "body": "{}"
+                }
+            }"#,
+        )
+        .unwrap();
+
+        assert!(matches!(
+            data,
+            QueueData::DeliveryActivity {
+                activity: OutboundActivity::Signed { .. },
+                attempt: 0,
+                first_attempt_at: None,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn subscribe_relay_queue_data_names_the_relay_kind() {
+        let data = serde_json::from_str::<QueueData>(
+            r#"{
+                "event_type": "SubscribeRelay",
+                "username": "default",
+                "actor": "https://relay.example/actor",
+                "kind": "LitePub"
+            }"#,
+        )
+        .unwrap();
+
+        assert!(matches!(
+            data,
+            QueueData::SubscribeRelay {
+                kind: RelayKind::LitePub,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn delivery_retry_delay_doubles_up_to_the_maximum() {
+        let policy = DeliveryRetryPolicy::default();
+        assert_eq!(policy.delay(1), TimeDelta::minutes(1));
+        assert_eq!(policy.delay(2), TimeDelta::minutes(2));
+        assert_eq!(policy.delay(5), TimeDelta::minutes(16));
+        assert_eq!(policy.delay(12), TimeDelta::hours(6));
+        assert_eq!(policy.delay(u32::MAX), TimeDelta::hours(6));
     }
 }
@@ -1,26 +1,42 @@
 use arrayvec::ArrayVec;
 use axum::Json;
 use axum::body::Body;
-use axum::extract::{Path, Query};
+use axum::extract::{ConnectInfo, Path, Query};
 use axum::http::{Request, Response, Uri};
 use axum::routing::{delete, post, put};
 use bytes::Bytes;
-use chrono::{DateTime, Utc};
-use fblog_system_core::process_queue::process_queue;
-use fblog_system_core::route::router;
-use fblog_system_core::traits::{ArticleNewComment, ArticleNewReaction, ArticleProvider, Env, HTTPClient, Queue, QueueData, UserProvider};
+use chrono::{DateTime, TimeDelta, Utc};
+use fblog_system_core::integrity::derive_integrity_key;
+use fblog_system_core::process_queue::{ProcessQueueResult, process_queue};
+use fblog_system_core::route::{ClientAddr, router};
+use fblog_system_core::traits::{
+    ArticleNewComment, ArticleNewReaction, ArticleProvider, CachedPublicKey, DeliveryFailures, DeliveryLog, DeliveryScheduler, DeliverySlot,
+    Ed25519SigningKey, Env, Following, FollowingStore, HTTPClient, InstanceAvailability, ProcessedActivityStore, PublicKeyCache, Queue, QueueData,
+    RateLimit, RateLimiter, Relay, RelayStore, UserProvider, UserSigningKey,
+};
 use rsa::pkcs1v15::SigningKey;
 use rsa::pkcs8::DecodePrivateKey;
 use rsa::sha2::Sha256;
 use serde::Deserialize;
 use std::collections::HashMap;
+use std::convert::Infallible;
 use std::fmt::Display;
+use std::net::SocketAddr;
 use std::sync::{Arc, atomic};
+use std::time::Duration;
 use std::{env, future};
 use tokio::sync::RwLock as TokioRwLock;
+use tokio::sync::mpsc::error::SendError;
 use tower_http::trace::{self, TraceLayer};
 use tracing::Level;
 
+/// Number of queued jobs processed at the same time unless `QUEUE_PARALLELISM` is set.
+const DEFAULT_QUEUE_PARALLELISM: usize = 8;
+/// Runs of a job that keeps asking for a retry, as many as Cloudflare Queues makes by default.
+const MAX_QUEUE_ATTEMPTS: u32 = 4;
+/// Delay before a job that asked for a retry runs again.
+const QUEUE_RETRY_DELAY: Duration = Duration::from_secs(5);
+
 #[derive(Clone)]
 struct Follower {
     id: String,
@@ -43,10 +59,48 @@ struct ArticleState {
     reactions: Vec<ArticleNewReaction>,
 }
 
+struct HostFailures {
+    failures: DeliveryFailures,
+    last_failed_at: DateTime<Utc>,
+    unavailable_until: Option<DateTime<Utc>>,
+}
+
+struct HostSchedule {
+    in_flight: u32,
+    lease_until: DateTime<Utc>,
+    backoff_until: Option<DateTime<Utc>>,
+}
+
+#[derive(PartialEq, Eq, Hash)]
+struct DeliveryKey {
+    activity_id: String,
+    inbox: String,
+}
+
+#[derive(PartialEq, Eq, Hash)]
+struct ProcessedKey {
+    username: String,
+    activity_id: String,
+}
+
+struct RateLimitWindow {
+    start: DateTime<Utc>,
+    count: u32,
+}
+
 #[derive(Clone)]
 struct InMemoryServer {
     articles: Arc<TokioRwLock<HashMap<String, ArticleState>>>,
     users: Arc<TokioRwLock<HashMap<String, UserState>>>,
+    public_keys: Arc<TokioRwLock<HashMap<String, CachedPublicKey>>>,
+    processed_activities: Arc<TokioRwLock<HashMap<ProcessedKey, DateTime<Utc>>>>,
+    rate_limits: Arc<TokioRwLock<HashMap<String, RateLimitWindow>>>,
+    delivery_failures: Arc<TokioRwLock<HashMap<String, HostFailures>>>,
+    delivery_hosts: Arc<TokioRwLock<HashMap<String, HostSchedule>>>,
+    deliveries: Arc<TokioRwLock<HashMap<DeliveryKey, DateTime<Utc>>>>,
+    deleted_users: Arc<TokioRwLock<HashMap<String, DateTime<Utc>>>>,
+    relays: Arc<TokioRwLock<HashMap<String, Relay>>>,
+    following: Arc<TokioRwLock<Vec<Following>>>,
     queue: tokio::sync::mpsc::UnboundedSender<QueueData>,
     pending_jobs: Arc<atomic::AtomicUsize>,
     client: reqwest::Client,
@@ -69,6 +123,15 @@ impl InMemoryServer {
         Self {
             articles: Arc::new(TokioRwLock::new(HashMap::new())),
             users: Arc::new(TokioRwLock::new(HashMap::new())),
+            public_keys: Arc::new(TokioRwLock::new(HashMap::new())),
+            processed_activities: Arc::new(TokioRwLock::new(HashMap::new())),
+            rate_limits: Arc::new(TokioRwLock::new(HashMap::new())),
+            delivery_failures: Arc::new(TokioRwLock::new(HashMap::new())),
+            delivery_hosts: Arc::new(TokioRwLock::new(HashMap::new())),
+            deliveries: Arc::new(TokioRwLock::new(HashMap::new())),
+            deleted_users: Arc::new(TokioRwLock::new(HashMap::new())),
+            relays: Arc::new(TokioRwLock::new(HashMap::new())),
+            following: Arc::new(TokioRwLock::new(Vec::new())),
             queue,
             pending_jobs: Arc::new(atomic::AtomicUsize::new(0)),
             client: client_builder.build().unwrap(),
@@ -87,12 +150,21 @@ impl Env for InMemoryServer {
         Utc::now()
     }
 
-    fn signing_key(&self) -> &SigningKey<rsa::sha2::Sha256> {
-        &self.key
+    fn signing_key(&self, _username: &str) -> UserSigningKey<'_> {
+        UserSigningKey {
+            id: "main-key",
+            key: &self.key,
+        }
+    }
+
+    fn integrity_key(&self, username: &str) -> Option<Ed25519SigningKey> {
+        Some(derive_integrity_key(b"in-memory-integrity-key-for-test", username))
     }
 }
 
 impl ArticleProvider for InMemoryServer {
+    type Error = Infallible;
+
     async fn exists_article(&self, slug: &str) -> bool {
         let articles = self.articles.read().await;
         articles.contains_key(slug)
@@ -114,15 +186,17 @@ impl ArticleProvider for InMemoryServer {
         articles.get(slug).map(|state| state.author.clone())
     }
 
-    async fn add_comment(&self, slug: &str, comment: ArticleNewComment) {
+    async fn add_comment(&self, slug: &str, comment: ArticleNewComment) -> Result<(), Self::Error> {
         self.articles.write().await.get_mut(slug).unwrap().comments.push(comment);
+        Ok(())
     }
 
-    async fn add_reaction(&self, slug: &str, reaction: ArticleNewReaction) {
+    async fn add_reaction(&self, slug: &str, reaction: ArticleNewReaction) -> Result<(), Self::Error> {
         self.articles.write().await.get_mut(slug).unwrap().reactions.push(reaction);
+        Ok(())
     }
 
-    async fn remove_reaction_by(&self, slug: &str, actor: &str) {
+    async fn remove_reaction_by(&self, slug: &str, actor: &str) -> Result<(), Self::Error> {
         self.articles
             .write()
             .await
@@ -130,6 +204,7 @@ impl ArticleProvider for InMemoryServer {
             .unwrap()
             .reactions
             .retain(|ArticleNewReaction { author_id, .. }| author_id != actor);
+        Ok(())
     }
 
     async fn comment_count(&self, slug: &str) -> usize {
@@ -142,6 +217,8 @@ impl ArticleProvider for InMemoryServer {
 }
 
 impl UserProvider for InMemoryServer {
+    type Error = Infallible;
+
     async fn exists_user(&self, username: &str) -> bool {
         let users = self.users.read().await;
         users.contains_key(username)
@@ -157,7 +234,7 @@ impl UserProvider for InMemoryServer {
         users.get(username).map(|state| Body::from(state.info_ap.clone()))
     }
 
-    async fn add_follower(&self, username: &str, follower_id: &str, inbox: &str, event_id: &str) {
+    async fn add_follower(&self, username: &str, follower_id: &str, inbox: &str, event_id: &str) -> Result<(), Self::Error> {
         let mut users = self.users.write().await;
         if let Some(UserState { followers, .. }) = users.get_mut(username) {
             followers.push(Follower {
@@ -166,31 +243,71 @@ impl UserProvider for InMemoryServer {
                 event_id: event_id.to_owned(),
             });
         }
+        Ok(())
     }
 
-    async fn remove_follower(&self, username: &str, event_id: &str) {
+    async fn get_follower_by_event_id(&self, username: &str, event_id: &str) -> Option<String> {
+        let users = self.users.read().await;
+        let followers = &users.get(username)?.followers;
+        followers.iter().find(|f| f.event_id == event_id).map(|f| f.id.clone())
+    }
+
+    async fn remove_follower(&self, username: &str, event_id: &str) -> Result<(), Self::Error> {
         let mut users = self.users.write().await;
         if let Some(UserState { followers, .. }) = users.get_mut(username)
             && let Some(pos) = followers.iter().position(|f| f.event_id == event_id)
         {
             followers.remove(pos);
         }
+        Ok(())
     }
 
-    async fn remove_follower_by_actor(&self, username: &str, actor: &str) {
+    async fn remove_follower_by_actor(&self, username: &str, actor: &str) -> Result<(), Self::Error> {
         let mut users = self.users.write().await;
         if let Some(UserState { followers, .. }) = users.get_mut(username)
             && let Some(pos) = followers.iter().position(|f| f.id == actor)
         {
             followers.remove(pos);
         }
+        Ok(())
     }
 
-    async fn get_followers_inbox_batch(&self, username: &str, last_inbox: &str) -> (ArrayVec<String, 10>, String) {
+    async fn remove_followers_by_inbox(&self, inbox: &str) -> Result<(), Self::Error> {
+        let mut users = self.users.write().await;
+        for user in users.values_mut() {
+            user.followers.retain(|f| f.inbox != inbox);
+        }
+        Ok(())
+    }
+
+    async fn remove_followers_by_origin(&self, origin: &str) -> Result<(), Self::Error> {
+        let mut users = self.users.write().await;
+        for user in users.values_mut() {
+            user.followers
+                .retain(|f| f.inbox.strip_prefix(origin).is_none_or(|path| !path.starts_with('/')));
+        }
+        Ok(())
+    }
+
+    async fn get_followers_inbox_batch(&self, username: &str, last_inbox: &str) -> Result<(ArrayVec<String, 10>, String), Self::Error> {
+        let now = self.timestamp_now();
+        let unavailable = self
+            .delivery_failures
+            .read()
+            .await
+            .iter()
+            .filter(|(_, host)| host.unavailable_until.is_some_and(|until| until > now))
+            .map(|(origin, _)| format!("{origin}/"))
+            .collect::<Vec<_>>();
         let users = self.users.clone().read_owned().await;
         let mut vec = ArrayVec::<String, 10>::new();
         if let Some(user) = users.get(username) {
-            let mut unique: Vec<String> = user.followers.iter().map(|f| f.inbox.clone()).collect();
+            let mut unique: Vec<String> = user
+                .followers
+                .iter()
+                .filter(|f| !unavailable.iter().any(|origin| f.inbox.starts_with(origin)))
+                .map(|f| f.inbox.clone())
+                .collect();
             unique.sort();
             unique.dedup();
             let start = match unique.binary_search(&last_inbox.to_string()) {
@@ -204,14 +321,343 @@ impl UserProvider for InMemoryServer {
             }
         }
         let next_last = vec.last().cloned().unwrap_or_default();
-        (vec, next_last)
+        Ok((vec, next_last))
+    }
+    async fn remove_followers_of(&self, username: &str) -> Result<(), Self::Error> {
+        if let Some(UserState { followers, .. }) = self.users.write().await.get_mut(username) {
+            followers.clear();
+        }
+        Ok(())
+    }
+
+    async fn user_deleted_at(&self, username: &str) -> Option<DateTime<Utc>> {
+        self.deleted_users.read().await.get(username).copied()
+    }
+
+    async fn mark_user_deleted(&self, username: &str, deleted_at: DateTime<Utc>) -> Result<(), Self::Error> {
+        self.deleted_users.write().await.entry(username.to_owned()).or_insert(deleted_at);
+        Ok(())
+    }
+}
+
+impl PublicKeyCache for InMemoryServer {
+    type Error = Infallible;
+
+    async fn get_public_key(&self, key_id: &str) -> Option<CachedPublicKey> {
+        self.public_keys.read().await.get(key_id).cloned()
+    }
+
+    async fn put_public_key(&self, key_id: &str, key: CachedPublicKey) -> Result<(), Self::Error> {
+        self.public_keys.write().await.insert(key_id.to_owned(), key);
+        Ok(())
+    }
+}
+
+impl ProcessedActivityStore for InMemoryServer {
+    type Error = Infallible;
+
+    async fn is_activity_processed(&self, username: &str, activity_id: &str) -> Result<bool, Self::Error> {
+        let key = ProcessedKey {
+            username: username.to_owned(),
+            activity_id: activity_id.to_owned(),
+        };
+        let now = self.timestamp_now();
+        Ok(self
+            .processed_activities
+            .read()
+            .await
+            .get(&key)
+            .is_some_and(|expires_at| *expires_at > now))
+    }
+
+    async fn mark_activity_processed(&self, username: &str, activity_id: &str, expires_at: DateTime<Utc>) -> Result<(), Self::Error> {
+        let now = self.timestamp_now();
+        let mut processed_activities = self.processed_activities.write().await;
+        processed_activities.retain(|_, expires_at| *expires_at > now);
+        processed_activities.insert(
+            ProcessedKey {
+                username: username.to_owned(),
+                activity_id: activity_id.to_owned(),
+            },
+            expires_at,
+        );
+        Ok(())
+    }
+}
+
+impl InstanceAvailability for InMemoryServer {
+    type Error = Infallible;
+
+    async fn record_delivery_failure(&self, origin: &str, now: DateTime<Utc>) -> DeliveryFailures {
+        let mut delivery_failures = self.delivery_failures.write().await;
+        let host = delivery_failures.entry(origin.to_owned()).or_insert(HostFailures {
+            failures: DeliveryFailures {
+                first_failed_at: now,
+                failure_days: 0,
+            },
+            last_failed_at: now,
+            unavailable_until: None,
+        });
+        if host.failures.failure_days == 0 || host.last_failed_at.date_naive() < now.date_naive() {
+            host.failures.failure_days += 1;
+        }
+        host.last_failed_at = now;
+        host.failures
+    }
+
+    async fn clear_delivery_failures(&self, origin: &str) -> Result<(), Self::Error> {
+        self.delivery_failures.write().await.remove(origin);
+        Ok(())
+    }
+
+    async fn mark_unavailable(&self, origin: &str, until: DateTime<Utc>) -> Result<(), Self::Error> {
+        if let Some(host) = self.delivery_failures.write().await.get_mut(origin) {
+            host.unavailable_until = Some(until);
+        }
+        Ok(())
+    }
+}
+
+impl DeliveryLog for InMemoryServer {
+    type Error = Infallible;
+
+    async fn is_delivered(&self, activity_id: &str, inbox: &str) -> bool {
+        let now = self.timestamp_now();
+        self.deliveries
+            .read()
+            .await
+            .get(&DeliveryKey {
+                activity_id: activity_id.to_owned(),
+                inbox: inbox.to_owned(),
+            })
+            .is_some_and(|expires_at| *expires_at > now)
+    }
+
+    async fn record_delivery(&self, activity_id: &str, inbox: &str, expires_at: DateTime<Utc>) -> Result<(), Self::Error> {
+        let now = self.timestamp_now();
+        let mut deliveries = self.deliveries.write().await;
+        deliveries.retain(|_, expires_at| *expires_at > now);
+        deliveries.insert(
+            DeliveryKey {
+                activity_id: activity_id.to_owned(),
+                inbox: inbox.to_owned(),
+            },
+            expires_at,
+        );
+        Ok(())
+    }
+
+    async fn forget_deliveries(&self, activity_id: &str) {
+        self.deliveries.write().await.retain(|key, _| key.activity_id != activity_id);
+    }
+}
+
+impl DeliveryScheduler for InMemoryServer {
+    type Error = Infallible;
+
+    async fn acquire_delivery_slot(&self, origin: &str, max_in_flight: u32, now: DateTime<Utc>, lease_until: DateTime<Utc>) -> DeliverySlot {
+        let mut delivery_hosts = self.delivery_hosts.write().await;
+        let host = delivery_hosts.entry(origin.to_owned()).or_insert(HostSchedule {
+            in_flight: 0,
+            lease_until,
+            backoff_until: None,
+        });
+        if let Some(until) = host.backoff_until.filter(|until| *until > now) {
+            return DeliverySlot::BackingOff(until);
+        }
+        if host.lease_until <= now {
+            host.in_flight = 0;
+        }
+        if host.in_flight >= max_in_flight {
+            return DeliverySlot::Busy;
+        }
+        host.in_flight += 1;
+        host.lease_until = lease_until;
+        DeliverySlot::Acquired
+    }
+
+    async fn release_delivery_slot(&self, origin: &str) {
+        if let Some(host) = self.delivery_hosts.write().await.get_mut(origin) {
+            host.in_flight = host.in_flight.saturating_sub(1);
+        }
+    }
+
+    async fn back_off_host(&self, origin: &str, until: DateTime<Utc>) -> Result<(), Self::Error> {
+        let mut delivery_hosts = self.delivery_hosts.write().await;
+        let host = delivery_hosts.entry(origin.to_owned()).or_insert(HostSchedule {
+            in_flight: 0,
+            lease_until: until,
+            backoff_until: None,
+        });
+        host.backoff_until = host.backoff_until.max(Some(until));
+        Ok(())
+    }
+}
+
+impl RelayStore for InMemoryServer {
+    type Error = Infallible;
+
+    async fn add_relay(&self, relay: &Relay) -> Result<(), Self::Error> {
+        let relay = Relay {
+            accepted_at: None,
+            ..relay.clone()
+        };
+        self.relays.write().await.insert(relay.actor.clone(), relay);
+        Ok(())
+    }
+
+    async fn get_relay(&self, actor: &str) -> Option<Relay> {
+        self.relays.read().await.get(actor).cloned()
+    }
+
+    async fn accept_relay(&self, actor: &str, follow_id: &str, accepted_at: DateTime<Utc>) -> Result<bool, Self::Error> {
+        match self.relays.write().await.get_mut(actor) {
+            Some(relay) if relay.follow_id == follow_id => {
+                relay.accepted_at = Some(accepted_at);
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    async fn remove_relay(&self, actor: &str) -> Result<(), Self::Error> {
+        self.relays.write().await.remove(actor);
+        Ok(())
+    }
+
+    async fn remove_relays_by_inbox(&self, inbox: &str) -> Result<(), Self::Error> {
+        self.relays.write().await.retain(|_, relay| relay.inbox != inbox);
+        Ok(())
+    }
+
+    async fn get_relay_inboxes(&self) -> Result<Vec<String>, Self::Error> {
+        let mut inboxes = self
+            .relays
+            .read()
+            .await
+            .values()
+            .filter(|relay| relay.accepted_at.is_some())
+            .map(|relay| relay.inbox.clone())
+            .collect::<Vec<_>>();
+        inboxes.sort();
+        inboxes.dedup();
+        Ok(inboxes)
+    }
+}
+
+impl FollowingStore for InMemoryServer {
+    type Error = Infallible;
+
+    async fn add_following(&self, following: &Following) -> Result<(), Self::Error> {
+        let mut followings = self.following.write().await;
+        followings.retain(|f| f.username != following.username || f.actor != following.actor);
+        followings.push(Following {
+            accepted_at: None,
+            ..following.clone()
+        });
+        Ok(())
+    }
+
+    async fn get_following(&self, username: &str, actor: &str) -> Option<Following> {
+        self.following
+            .read()
+            .await
+            .iter()
+            .find(|f| f.username == username && f.actor == actor)
+            .cloned()
+    }
+
+    async fn accept_following(&self, actor: &str, follow_id: &str, accepted_at: DateTime<Utc>) -> Result<bool, Self::Error> {
+        match self
+            .following
+            .write()
+            .await
+            .iter_mut()
+            .find(|f| f.actor == actor && f.follow_id == follow_id)
+        {
+            Some(following) => {
+                following.accepted_at = Some(accepted_at);
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    async fn reject_following(&self, actor: &str, follow_id: &str) -> Result<bool, Self::Error> {
+        let mut followings = self.following.write().await;
+        let len = followings.len();
+        followings.retain(|f| f.actor != actor || f.follow_id != follow_id);
+        Ok(followings.len() != len)
+    }
+
+    async fn remove_following(&self, username: &str, actor: &str) -> Result<(), Self::Error> {
+        self.following.write().await.retain(|f| f.username != username || f.actor != actor);
+        Ok(())
+    }
+
+    async fn get_following_actors(&self, username: &str) -> Vec<String> {
+        self.following
+            .read()
+            .await
+            .iter()
+            .filter(|f| f.username == username && f.accepted_at.is_some())
+            .map(|f| f.actor.clone())
+            .collect()
+    }
+}
+
+impl RateLimiter for InMemoryServer {
+    async fn hit_rate_limit(&self, key: &str, limit: RateLimit) -> Option<TimeDelta> {
+        let now = self.timestamp_now();
+        let mut rate_limits = self.rate_limits.write().await;
+        let window = rate_limits.entry(key.to_owned()).or_insert(RateLimitWindow { start: now, count: 0 });
+        if window.start + limit.window <= now {
+            window.start = now;
+            window.count = 0;
+        }
+        window.count += 1;
+        (window.count > limit.max_requests).then(|| window.start + limit.window - now)
     }
 }
 
 impl Queue for InMemoryServer {
-    async fn enqueue(&self, data: QueueData) {
+    type Error = SendError<QueueData>;
+
+    // jobs count as pending before they are sent, so a worker never finishes one that is not counted yet
+    async fn enqueue(&self, data: QueueData) -> Result<(), Self::Error> {
         self.pending_jobs.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
-        self.queue.send(data).unwrap();
+        self.queue.send(data).inspect_err(|_| {
+            self.pending_jobs.fetch_sub(1, std::sync::atomic::Ordering::SeqCst);
+        })
+    }
+
+    async fn enqueue_batch(&self, data: Vec<QueueData>) -> Result<(), Self::Error> {
+        let mut unsent = data.len();
+        self.pending_jobs.fetch_add(unsent, std::sync::atomic::Ordering::SeqCst);
+        for data in data {
+            if let Err(e) = self.queue.send(data) {
+                self.pending_jobs.fetch_sub(unsent, std::sync::atomic::Ordering::SeqCst);
+                return Err(e);
+            }
+            unsent -= 1;
+        }
+        Ok(())
+    }
+
+    async fn enqueue_with_delay(&self, data: QueueData, delay: TimeDelta) -> Result<(), Self::Error> {
+        let queue = self.queue.clone();
+        let pending_jobs = self.pending_jobs.clone();
+        let delay = delay.to_std().unwrap_or_default();
+        // the job only counts as pending once it is sent, so waiting for an empty queue does not wait out the delay
+        tokio::spawn(async move {
+            tokio::time::sleep(delay).await;
+            pending_jobs.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            if let Err(e) = queue.send(data) {
+                pending_jobs.fetch_sub(1, std::sync::atomic::Ordering::SeqCst);
+                tracing::error!(error = ?e, "failed to send delayed job");
+            }
+        });
+        Ok(())
     }
 }
 
@@ -371,18 +817,49 @@ async fn main() {
                 }
             }
         })
+        .layer(axum::middleware::map_request(
+            async |ConnectInfo(addr): ConnectInfo<SocketAddr>, mut request: Request<Body>| {
+                request.extensions_mut().insert(ClientAddr(addr.ip()));
+                request
+            },
+        ))
         .layer(
             TraceLayer::new_for_http()
                 .make_span_with(trace::DefaultMakeSpan::new().level(Level::INFO))
                 .on_response(trace::DefaultOnResponse::new().level(Level::INFO)),
         );
 
+    let app = app.into_make_service_with_connect_info::<SocketAddr>();
     let listener = tokio::net::TcpListener::bind("0.0.0.0:8787").await.unwrap();
+    let parallelism = env::var("QUEUE_PARALLELISM")
+        .ok()
+        .and_then(|parallelism| parallelism.parse::<usize>().ok())
+        .unwrap_or(DEFAULT_QUEUE_PARALLELISM)
+        .max(1);
+    let permits = Arc::new(tokio::sync::Semaphore::new(parallelism));
     tokio::join!(axum::serve(listener, app), async move {
         loop {
             let data = receiver.recv().await.unwrap();
-            process_queue(&state, data).await;
-            state.pending_jobs.fetch_sub(1, atomic::Ordering::SeqCst);
+            let mut permit = permits.clone().acquire_owned().await.unwrap();
+            let permits = permits.clone();
+            let state = state.clone();
+            tokio::spawn(async move {
+                let mut attempt = 1;
+                while let ProcessQueueResult::Retry = process_queue(&state, data.clone()).await {
+                    if attempt >= MAX_QUEUE_ATTEMPTS {
+                        tracing::error!(attempt, ?data, "giving up job after its last retry");
+                        break;
+                    }
+                    tracing::warn!(attempt, "retrying job later");
+                    // the job stays pending while it waits, but leaves its slot to other jobs
+                    drop(permit);
+                    tokio::time::sleep(QUEUE_RETRY_DELAY).await;
+                    permit = permits.clone().acquire_owned().await.unwrap();
+                    attempt += 1;
+                }
+                state.pending_jobs.fetch_sub(1, atomic::Ordering::SeqCst);
+                drop(permit);
+            });
         }
     });
 }
@@ -41,4 +41,5 @@ cd crates/cloudflare_workers
 CLOUDFLARE_ACCOUNT_ID="$CF_ACCOUNT_ID_VALUE" CLOUDFLARE_API_TOKEN="$CF_API_TOKEN_VALUE" ./setup_resources.sh "$PROJECT_NAME" "$HOST_NAME"
 CLOUDFLARE_ACCOUNT_ID="$CF_ACCOUNT_ID_VALUE" CLOUDFLARE_API_TOKEN="$CF_API_TOKEN_VALUE" pnpm exec wrangler --cwd "$(pwd)" deploy
 CLOUDFLARE_ACCOUNT_ID="$CF_ACCOUNT_ID_VALUE" CLOUDFLARE_API_TOKEN="$CF_API_TOKEN_VALUE" pnpm exec wrangler --cwd "$(pwd)" r2 object put --remote "${PROJECT_NAME}-blog-bucket/article_snapshot_zst" -f "$WORKING_DIR/article_snapshot_new.zst"
+CLOUDFLARE_ACCOUNT_ID="$CF_ACCOUNT_ID_VALUE" CLOUDFLARE_API_TOKEN="$CF_API_TOKEN_VALUE" pnpm exec wrangler --cwd "$(pwd)" r2 object put --remote "${PROJECT_NAME}-blog-bucket/user_snapshot_zst" -f "$WORKING_DIR/user_snapshot_new.zst"
 CF_ACCOUNT_ID="$CF_ACCOUNT_ID_VALUE" CF_API_TOKEN="$CF_API_TOKEN_VALUE" ./send_to_queue.sh "${PROJECT_NAME}-job-queue" "$WORKING_DIR/events.jsonl"
@@ -1,15 +1,32 @@
 #!/usr/bin/env bash
-# Usage: snapshot_diff.sh <old> <new>
+# Usage: snapshot_diff.sh <old> <new> [articles|users]
 
 set -eu
 
 OLD="$1"
 NEW="$2"
+KIND="${3:-articles}"
 
 join -t $'\t' -a1 -a2 -e MISSING -o 0 1.2 1.3 2.3 \
      <(sort "$OLD") <(sort "$NEW") |
-awk -F'\t' '
-  $3=="MISSING"             {print "{\"event_type\":\"DeliveryNewArticleToAll\",\"slug\":\""$1"\"}"; next}
-  $4=="MISSING"             {print "{\"event_type\":\"DeliveryDeleteArticleToAll\",\"slug\":\""$1"\",\"author\":\""$2"\"}"; next}
-  $3 != $4                  {print "{\"event_type\":\"DeliveryUpdateArticleToAll\",\"slug\":\""$1"\"}"; next}
-'
+case "$KIND" in
+  articles)
+    awk -F'\t' '
+      $3=="MISSING"             {print "{\"event_type\":\"DeliveryNewArticleToAll\",\"slug\":\""$1"\"}"; next}
+      $4=="MISSING"             {print "{\"event_type\":\"DeliveryDeleteArticleToAll\",\"slug\":\""$1"\",\"author\":\""$2"\"}"; next}
+      $3 != $4                  {print "{\"event_type\":\"DeliveryUpdateArticleToAll\",\"slug\":\""$1"\"}"; next}
+    '
+    ;;
+  users)
+    # new users have no followers yet, so they are not federated
+    awk -F'\t' '
+      $3=="MISSING"             {next}
+      $4=="MISSING"             {print "{\"event_type\":\"DeliveryDeleteUserToAll\",\"username\":\""$1"\"}"; next}
+      $3 != $4                  {print "{\"event_type\":\"DeliveryUpdateUserToAll\",\"username\":\""$1"\"}"; next}
+    '
+    ;;
+  *)
+    echo "unknown snapshot kind: $KIND" >&2
+    exit 1
+    ;;
+esac